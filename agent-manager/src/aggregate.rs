@@ -0,0 +1,84 @@
+use crate::engine::Dependency;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// The identity of a dependency once individual sockets are collapsed.
+///
+/// Client-side ports are left out on purpose: a connection pool shows up as
+/// many sockets that differ only in their ephemeral port. Processes are
+/// matched by name and executable rather than pid so that worker processes of
+/// the same service collapse too.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DependencyKey {
+    pub local_ip: String,
+    pub remote_ip: String,
+    pub service_port: u16,
+    pub protocol: Option<String>,
+    pub process: Option<(String, Option<String>)>,
+}
+
+impl DependencyKey {
+    pub fn of(dependency: &Dependency) -> Self {
+        DependencyKey {
+            local_ip: dependency.local_ip.clone(),
+            remote_ip: dependency.remote_ip.clone(),
+            service_port: dependency.service_port.unwrap_or(dependency.remote_port),
+            protocol: dependency.protocol.clone(),
+            process: dependency
+                .process
+                .as_ref()
+                .map(|process| (process.name.clone(), process.exe.clone())),
+        }
+    }
+}
+
+/// Collapses a module's dependencies by [`DependencyKey`].
+///
+/// Records report the client-side port as 0, however many sockets they stand
+/// for, so that a record depends only on its key. Keeping a socket's
+/// ephemeral port would give the same dependency a different identity
+/// whenever it reconnects.
+///
+/// First-seen times are remembered between collections for as long as a
+/// dependency keeps showing up, so they tell how long it has existed rather
+/// than when the current collection ran.
+#[derive(Debug, Clone, Default)]
+pub struct Aggregator {
+    first_seen: HashMap<DependencyKey, DateTime<Utc>>,
+}
+
+impl Aggregator {
+    pub fn aggregate(&mut self, dependencies: Vec<Dependency>, now: DateTime<Utc>) -> Vec<Dependency> {
+        let mut index: HashMap<DependencyKey, usize> = HashMap::new();
+        let mut aggregated: Vec<Dependency> = Vec::new();
+
+        for dependency in dependencies {
+            let key = DependencyKey::of(&dependency);
+            match index.get(&key) {
+                Some(&i) => aggregated[i].count += dependency.count,
+                None => {
+                    index.insert(key, aggregated.len());
+                    aggregated.push(dependency);
+                }
+            }
+        }
+
+        self.first_seen.retain(|key, _| index.contains_key(key));
+        for (key, &i) in &index {
+            let first_seen = *self.first_seen.entry(key.clone()).or_insert(now);
+            aggregated[i].first_seen = Some(first_seen);
+            aggregated[i].last_seen = Some(now);
+            clear_client_port(&mut aggregated[i], key.service_port);
+        }
+
+        aggregated
+    }
+}
+
+fn clear_client_port(dependency: &mut Dependency, service_port: u16) {
+    if dependency.remote_port == service_port {
+        dependency.local_port = 0;
+    } else if dependency.local_port == service_port {
+        dependency.remote_port = 0;
+    }
+}
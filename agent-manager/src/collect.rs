@@ -0,0 +1,203 @@
+use crate::aggregate::Aggregator;
+use crate::config::Config;
+use crate::engine::{collect_module, Batch, BatchKind, Dependency, ExposedService, ProcessInfo};
+use crate::health::HealthTracker;
+use crate::spec::SpecCache;
+use crate::Error;
+use crate::Result;
+use chrono::Utc;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+/// How `dep_map run` and `dep_map collect --once` print their results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    /// The batches as JSON, as sent to the server minus the agent envelope.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<Self, Self::Err> {
+        match format {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown format '{}', expected table or json", format)),
+        }
+    }
+}
+
+/// Parses the `--args` of `dep_map run`, which must be a JSON object.
+pub fn parse_args(json: &str) -> Result<HashMap<String, Value>> {
+    serde_json::from_str(json)
+        .map_err(|e| Error::InvalidModuleInput(format!("--args must be a JSON object: {}", e)))
+}
+
+/// Runs one module once and returns the full snapshot it would send, or a
+/// health batch if the run failed.
+///
+/// A module that is not in the config runs with no args and the default
+/// timeout. `args`, when given, replaces the configured args.
+pub async fn collect_batch(config: &Config, name: &str, args: Option<HashMap<String, Value>>) -> Batch {
+    let mut module = config.modules.get(name).cloned().unwrap_or_default();
+    if args.is_some() {
+        module.args = args;
+    }
+    let result = collect_module(&config.agent, name, &module, &SpecCache::default()).await;
+    let now = Utc::now();
+    let health = HealthTracker::default().record(&result, now);
+
+    let batch = match result {
+        Ok(output) => Batch {
+            module: name.to_string(),
+            kind: BatchKind::Full,
+            dependencies: Aggregator::default().aggregate(output.dependencies, now),
+            services: output.services,
+            ..Default::default()
+        },
+        Err(_) => Batch {
+            module: name.to_string(),
+            kind: BatchKind::Health,
+            ..Default::default()
+        },
+    };
+    Batch {
+        health: Some(health),
+        ..batch
+    }
+}
+
+/// Runs every configured module once, one after another, in name order.
+pub async fn collect_once(config: &Config) -> Vec<Batch> {
+    let mut names: Vec<&String> = config.modules.keys().collect();
+    names.sort();
+    let mut batches = Vec::new();
+    for name in names {
+        batches.push(collect_batch(config, name, None).await);
+    }
+    batches
+}
+
+/// Whether any of the runs failed.
+pub fn any_failed(batches: &[Batch]) -> bool {
+    batches
+        .iter()
+        .any(|batch| batch.health.as_ref().is_some_and(|health| !health.is_ok()))
+}
+
+pub fn render(batches: &[Batch], format: OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(batches)? + "\n"),
+        OutputFormat::Table => Ok(batches.iter().map(batch_table).collect::<Vec<_>>().join("\n")),
+    }
+}
+
+/// A heading for the module followed by its dependencies and services, or
+/// by why the run failed.
+pub fn batch_table(batch: &Batch) -> String {
+    let mut out = String::new();
+    match batch.health.as_ref().filter(|health| !health.is_ok()) {
+        Some(health) => {
+            let _ = writeln!(out, "== {}: {}", batch.module, health.status.as_str());
+            if let Some(message) = &health.message {
+                let _ = writeln!(out, "{}", message);
+            }
+            if let Some(stderr) = &health.stderr {
+                let _ = writeln!(out, "stderr:\n{}", stderr);
+            }
+        }
+        None => {
+            let _ = writeln!(
+                out,
+                "== {}: {} dependencies, {} services",
+                batch.module,
+                batch.dependencies.len(),
+                batch.services.len()
+            );
+            if !batch.dependencies.is_empty() {
+                out.push_str(&dependency_table(&batch.dependencies));
+            }
+            if !batch.services.is_empty() {
+                if !batch.dependencies.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(&service_table(&batch.services));
+            }
+        }
+    }
+    out
+}
+
+pub fn dependency_table(dependencies: &[Dependency]) -> String {
+    let rows = dependencies
+        .iter()
+        .map(|dependency| {
+            vec![
+                dependency.direction.as_str().to_string(),
+                endpoint(&dependency.local_ip, dependency.local_port),
+                endpoint(&dependency.remote_ip, dependency.remote_port),
+                dependency.protocol.clone().unwrap_or_else(|| "-".to_string()),
+                process(dependency.process.as_ref()),
+                dependency.count.to_string(),
+            ]
+        })
+        .collect();
+    table(&["DIRECTION", "LOCAL", "REMOTE", "PROTOCOL", "PROCESS", "COUNT"], rows)
+}
+
+pub fn service_table(services: &[ExposedService]) -> String {
+    let rows = services
+        .iter()
+        .map(|service| {
+            vec![
+                endpoint(&service.bind_ip, service.port),
+                service.protocol.clone(),
+                process(service.process.as_ref()),
+            ]
+        })
+        .collect();
+    table(&["LISTEN", "PROTOCOL", "PROCESS"], rows)
+}
+
+fn endpoint(ip: &str, port: u16) -> String {
+    if ip.contains(':') {
+        format!("[{}]:{}", ip, port)
+    } else {
+        format!("{}:{}", ip, port)
+    }
+}
+
+fn process(process: Option<&ProcessInfo>) -> String {
+    process
+        .map(|process| format!("{}[{}]", process.name, process.pid))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Left-aligned columns separated by two spaces.
+pub(crate) fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let headers = headers.iter().map(|header| header.to_string()).collect();
+    let mut out = String::new();
+    for row in std::iter::once(headers).chain(rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
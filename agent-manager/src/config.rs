@@ -1,6 +1,16 @@
+use crate::spec::ModuleSpec;
+use crate::validate::ConfigIssue;
+use crate::Error;
+use crate::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub const ENV_CONFIG: &str = "DEP_MAP_CONFIG";
+pub const ENV_MODULE_PATH: &str = "DEP_MAP_MODULE_PATH";
+pub const ENV_LOG_LEVEL: &str = "DEP_MAP_LOG_LEVEL";
+pub const ENV_SERVER_URL: &str = "DEP_MAP_SERVER_URL";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
@@ -19,18 +29,689 @@ impl PartialEq for Config {
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct AgentConfig {
     pub module_paths: Vec<PathBuf>,
+    /// `error`, `warn`, `info`, `debug` or `trace`, optionally followed by
+    /// `env_logger` directives such as `info,agent::outbox=debug`.
     pub log_level: String,
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Interval in seconds for modules that set neither `interval` nor
+    /// `schedule`.
+    #[serde(default = "default_interval")]
+    pub default_interval: u64,
+    /// Upper bound in seconds of the random offset applied to each module's
+    /// runs, so that a fleet of agents does not report in lockstep.
+    #[serde(default)]
+    pub splay: u64,
+    /// Directory for agent state such as the outbox. Without it, batches
+    /// that fail to upload are dropped.
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    /// Seconds between full snapshots. In between, modules only report what
+    /// was added or removed. 0 sends a full snapshot every run.
+    #[serde(default = "default_full_snapshot_interval")]
+    pub full_snapshot_interval: u64,
+    /// Seconds a module may run before it and its process group are killed,
+    /// unless the module sets its own `timeout`.
+    #[serde(default = "default_module_timeout")]
+    pub module_timeout: u64,
+    /// How many modules may run at the same time.
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub status: StatusConfig,
+    /// Accept batches from downstream agents and forward them to `server`.
+    #[serde(default)]
+    pub relay: Option<RelayConfig>,
+    #[serde(default)]
+    pub integrity: IntegrityConfig,
+    /// Restrictions for every module process. A module's own `sandbox`
+    /// overrides single fields.
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+}
+
+fn default_interval() -> u64 {
+    300
+}
+
+fn default_full_snapshot_interval() -> u64 {
+    3600
+}
+
+fn default_module_timeout() -> u64 {
+    300
+}
+
+fn default_max_concurrency() -> usize {
+    4
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            module_paths: Vec::new(),
+            log_level: "info".to_string(),
+            log: LogConfig::default(),
+            default_interval: default_interval(),
+            splay: 0,
+            state_dir: None,
+            outbox: OutboxConfig::default(),
+            full_snapshot_interval: default_full_snapshot_interval(),
+            module_timeout: default_module_timeout(),
+            max_concurrency: default_max_concurrency(),
+            daemon: DaemonConfig::default(),
+            status: StatusConfig::default(),
+            relay: None,
+            integrity: IntegrityConfig::default(),
+            sandbox: SandboxConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Straight to the systemd journal, with structured fields.
+    Journald,
 }
 
+/// Where log output goes and what it looks like.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Log to this file instead of stderr.
+    pub file: Option<PathBuf>,
+    /// Size in bytes at which the log file is rotated.
+    pub max_bytes: u64,
+    /// How many rotated files to keep.
+    pub max_files: usize,
+    /// Log level per module, overriding `log_level` for messages about that
+    /// module.
+    pub modules: HashMap<String, String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            file: None,
+            max_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+            modules: HashMap::new(),
+        }
+    }
+}
+
+/// The local status and metrics listener.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StatusConfig {
+    /// `127.0.0.1:<port>`, `[::1]:<port>` or `unix:<path>`. Off when unset.
+    pub listen: Option<String>,
+}
+
+/// Relay mode: the agent accepts batches from agents that cannot reach the
+/// server, spools them under `<state_dir>/relay` and forwards them upstream.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RelayConfig {
+    /// `host:port` to accept downstream agents on.
+    pub listen: String,
+    /// PEM certificate chain to serve TLS with. Without it the relay speaks
+    /// plain HTTP.
+    #[serde(default)]
+    pub tls_cert_file: Option<PathBuf>,
+    /// PKCS#8 private key for `tls_cert_file`.
+    #[serde(default)]
+    pub tls_key_file: Option<PathBuf>,
+    /// Downstream agents must send `Authorization: Bearer <token>` with the
+    /// token in this file.
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    /// Largest batch accepted, in bytes.
+    #[serde(default = "default_relay_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Seconds a downstream agent gets for the TLS handshake and for
+    /// sending its request before the connection is dropped.
+    #[serde(default = "default_relay_read_timeout")]
+    pub read_timeout: u64,
+    /// Connections handled at once. Further agents wait to be accepted.
+    #[serde(default = "default_relay_max_connections")]
+    pub max_connections: usize,
+}
+
+fn default_relay_max_body_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_relay_read_timeout() -> u64 {
+    10
+}
+
+fn default_relay_max_connections() -> usize {
+    64
+}
+
+/// What a module file must satisfy before it runs. On unix, the file and
+/// every directory above it must be owned by a trusted user and must not be
+/// writable by everyone or by an untrusted group (sticky directories such as
+/// `/tmp` aside). The same goes for the manifest, its signature and the key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IntegrityConfig {
+    /// Allowed module hashes, one `<sha256>  <module>` line each as
+    /// `sha256sum` prints them, with the module as a name or a path relative
+    /// to its module path. Once set, modules missing from it do not run.
+    pub manifest_file: Option<PathBuf>,
+    /// Ed25519 signature of `manifest_file`, raw or base64. Defaults to the
+    /// manifest path with `.sig` appended.
+    pub signature_file: Option<PathBuf>,
+    /// PEM public key `manifest_file` must be signed with.
+    pub public_key_file: Option<PathBuf>,
+    /// Users, by name or uid, that may own modules and the directories they
+    /// are in. Defaults to root and the user the agent runs as.
+    pub trusted_owners: Vec<String>,
+    /// Groups, by name or gid, that may have write access to modules and
+    /// the directories they are in. Defaults to root's group and the group
+    /// the agent runs as.
+    pub trusted_groups: Vec<String>,
+}
+
+/// What a module process may do. Unset fields leave the module as
+/// privileged as the agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// User to run modules as, by name or uid. Only root can switch users.
+    pub user: Option<String>,
+    /// Group to run modules as, by name or gid. Defaults to the primary
+    /// group of `user`.
+    pub group: Option<String>,
+    /// Environment of the module: `NAME` passes the agent's value on,
+    /// `NAME=value` sets one. Once set, nothing else is passed but
+    /// `ARGS_FILE`.
+    pub env: Option<Vec<String>>,
+    pub working_dir: Option<PathBuf>,
+    /// CPU time after which the module is killed, in seconds.
+    pub max_cpu_seconds: Option<u64>,
+    /// Address space the module may map, in bytes.
+    pub max_memory_bytes: Option<u64>,
+    pub max_open_files: Option<u64>,
+    /// Keep the module and its children from gaining privileges through
+    /// setuid binaries or file capabilities. Linux only.
+    pub no_new_privs: Option<bool>,
+    /// Linux namespaces to run the module in. `network` leaves it only a
+    /// loopback interface of its own.
+    pub namespaces: Option<Vec<Namespace>>,
+    /// Deny syscalls that no inventory module needs, such as `ptrace`,
+    /// `mount` and module loading. Implies `no_new_privs`. Linux on x86_64
+    /// and aarch64 only.
+    pub seccomp: Option<bool>,
+}
+
+impl SandboxConfig {
+    /// These settings, with the fields `other` sets taking precedence.
+    pub fn overridden_by(&self, other: Option<&SandboxConfig>) -> SandboxConfig {
+        let Some(other) = other else {
+            return self.clone();
+        };
+        SandboxConfig {
+            user: other.user.clone().or_else(|| self.user.clone()),
+            group: other.group.clone().or_else(|| self.group.clone()),
+            env: other.env.clone().or_else(|| self.env.clone()),
+            working_dir: other.working_dir.clone().or_else(|| self.working_dir.clone()),
+            max_cpu_seconds: other.max_cpu_seconds.or(self.max_cpu_seconds),
+            max_memory_bytes: other.max_memory_bytes.or(self.max_memory_bytes),
+            max_open_files: other.max_open_files.or(self.max_open_files),
+            no_new_privs: other.no_new_privs.or(self.no_new_privs),
+            namespaces: other.namespaces.clone().or_else(|| self.namespaces.clone()),
+            seccomp: other.seccomp.or(self.seccomp),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    Network,
+    Ipc,
+    Uts,
+}
+
+/// Files used when the agent runs detached.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Holds the agent's pid and is locked for as long as it runs.
+    pub pid_file: PathBuf,
+    /// Log file for a detached agent that has no `log.file`. Anything written
+    /// to stderr goes to this path with a `.stderr` suffix.
+    pub log_file: PathBuf,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            pid_file: PathBuf::from("/var/run/dep_map.pid"),
+            log_file: PathBuf::from("/var/log/dep_map/agent.log"),
+        }
+    }
+}
+
+/// Limits and retry policy for the on-disk outbox. Sizes are in bytes and
+/// durations in seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub max_bytes: u64,
+    pub max_age: u64,
+    pub retry_initial: u64,
+    pub retry_max: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            max_bytes: 64 * 1024 * 1024,
+            max_age: 7 * 24 * 60 * 60,
+            retry_initial: 5,
+            retry_max: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ModuleConfig {
     pub description: Option<String>,
-    pub interval: u64,
+    /// Seconds between runs. Falls back to `agent.default_interval`.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// Cron expression (with a seconds field) evaluated in local time, e.g.
+    /// `"0 */5 9-17 * * Mon-Fri"`. Takes precedence over `interval`.
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// Overrides `agent.splay` for this module, in seconds.
+    #[serde(default)]
+    pub splay: Option<u64>,
+    /// Whether to run as soon as the agent starts rather than at the first
+    /// scheduled time. Defaults to true for interval modules and false for
+    /// cron modules.
+    #[serde(default)]
+    pub run_at_startup: Option<bool>,
     pub args: Option<HashMap<String, serde_json::Value>>,
+    /// What the module takes and prints. Without it, the spec file shipped
+    /// next to the module applies, if there is one.
+    #[serde(default)]
+    pub spec: Option<ModuleSpec>,
+    /// Take the module's `changed: false` at its word and skip diffing its
+    /// output until the next full snapshot.
+    #[serde(default)]
+    pub trust_changed: bool,
+    /// Overrides `agent.module_timeout` for this module, in seconds.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+    /// Hex SHA-256 the module file must have.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Overrides fields of `agent.sandbox` for this module.
+    #[serde(default)]
+    pub sandbox: Option<SandboxConfig>,
+}
+
+/// What to do when a module comes due while its previous run is still going.
+/// A module never runs twice at the same time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    /// Drop the run that came due.
+    #[default]
+    Skip,
+    /// Start one more run as soon as the current one finishes.
+    Queue,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ServerConfig {
     pub url: String,
     pub timeout: u64,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Sends uploads through this proxy. Without it, the `HTTPS_PROXY`,
+    /// `HTTP_PROXY` and `NO_PROXY` environment variables apply.
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    /// When set, the agent posts its identity here before sending batches,
+    /// and takes on the agent ID the server answers with, if any.
+    #[serde(default)]
+    pub enroll_url: Option<String>,
+}
+
+/// How the agent proves itself to the server. Secrets are read from files,
+/// which must not be readable by group or others.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Sent as `Authorization: Bearer <token>`.
+    pub token_file: Option<PathBuf>,
+    /// A per-agent API key, sent in `api_key_header`.
+    pub api_key_file: Option<PathBuf>,
+    pub api_key_header: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            token_file: None,
+            api_key_file: None,
+            api_key_header: "X-API-Key".to_string(),
+        }
+    }
+}
+
+/// PEM files for verifying the server and, with mutual TLS, for the agent's
+/// own certificate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Trust only the CAs in this bundle instead of the system roots, which
+    /// pins the server to certificates our own CA issued.
+    pub ca_file: Option<PathBuf>,
+    pub client_cert_file: Option<PathBuf>,
+    /// PKCS#8 private key for `client_cert_file`.
+    pub client_key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProxyConfig {
+    pub url: String,
+    /// Comma-separated hosts, domains and CIDR ranges reached directly.
+    #[serde(default)]
+    pub no_proxy: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+}
+
+/// Where an effective config value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Cli(String),
+    /// A list with entries from several sources, highest precedence first.
+    Layered(Vec<ConfigSource>),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "file {}", path.display()),
+            ConfigSource::Env(var) => write!(f, "env {}", var),
+            ConfigSource::Cli(flag) => write!(f, "cli --{}", flag),
+            ConfigSource::Layered(sources) => {
+                let sources: Vec<String> = sources.iter().map(ToString::to_string).collect();
+                write!(f, "{}", sources.join(" + "))
+            }
+        }
+    }
+}
+
+/// Values given on the command line. They win over the environment, which
+/// wins over the config file.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub config_path: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub server_url: Option<String>,
+    /// Searched before any other module path.
+    pub module_paths: Vec<PathBuf>,
+    pub pid_file: Option<PathBuf>,
+}
+
+/// A config together with the file it was read from and the origin of each
+/// value, keyed by dotted path such as `server.url`.
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub path: PathBuf,
+    pub path_source: ConfigSource,
+    sources: BTreeMap<String, ConfigSource>,
+    content: String,
+}
+
+impl LoadedConfig {
+    /// The origin of the value at `key`. Values inside a section that came
+    /// from a layer, such as a module's args, share that section's source.
+    pub fn source_of(&self, key: &str) -> ConfigSource {
+        let mut key = key;
+        loop {
+            if let Some(source) = self.sources.get(key) {
+                return source.clone();
+            }
+            match key.rfind('.') {
+                Some(dot) => key = &key[..dot],
+                None => return ConfigSource::Default,
+            }
+        }
+    }
+
+    /// The config file as it was read.
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// Fails with every error in the config, or returns the warnings; see
+    /// [`crate::validate::validate`].
+    pub fn validate(&self) -> Result<Vec<ConfigIssue>> {
+        let (errors, warnings): (Vec<_>, Vec<_>) = crate::validate::validate_loaded(self)
+            .into_iter()
+            .partition(|issue| issue.is_error());
+        if errors.is_empty() {
+            Ok(warnings)
+        } else {
+            Err(Error::InvalidConfig(errors))
+        }
+    }
+
+    /// Every effective leaf value with its origin, in key order.
+    pub fn describe(&self) -> Result<Vec<(String, String, ConfigSource)>> {
+        let mut leaves = Vec::new();
+        flatten_yaml("", &serde_yaml::to_value(&self.config)?, &mut leaves);
+        Ok(leaves
+            .into_iter()
+            .map(|(key, value)| {
+                let source = self.source_of(&key);
+                (key, value, source)
+            })
+            .collect())
+    }
+}
+
+/// Finds and loads the agent config, layering environment variables and
+/// command-line overrides on top of the file.
+///
+/// The file is the first of: `--config`, `$DEP_MAP_CONFIG`, `./dep_map.yaml`,
+/// `$HOME/.config/dep_map/config.yaml` and `/etc/dep_map/config.yaml`.
+#[derive(Debug, Clone)]
+pub struct ConfigLoader {
+    overrides: ConfigOverrides,
+    env: HashMap<String, String>,
+    search_paths: Option<Vec<PathBuf>>,
+}
+
+impl ConfigLoader {
+    pub fn new(overrides: ConfigOverrides) -> Self {
+        ConfigLoader {
+            overrides,
+            env: std::env::vars().collect(),
+            search_paths: None,
+        }
+    }
+
+    /// Replaces the process environment, mainly for tests.
+    pub fn with_env(mut self, env: HashMap<String, String>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Replaces the default search locations.
+    pub fn with_search_paths(mut self, search_paths: Vec<PathBuf>) -> Self {
+        self.search_paths = Some(search_paths);
+        self
+    }
+
+    pub fn search_paths(&self) -> Vec<PathBuf> {
+        if let Some(search_paths) = &self.search_paths {
+            return search_paths.clone();
+        }
+        let mut paths = vec![PathBuf::from("dep_map.yaml")];
+        if let Some(home) = self.env.get("HOME") {
+            paths.push(Path::new(home).join(".config/dep_map/config.yaml"));
+        }
+        paths.push(PathBuf::from("/etc/dep_map/config.yaml"));
+        paths
+    }
+
+    /// The config file to read and why it was picked.
+    pub fn locate(&self) -> Result<(PathBuf, ConfigSource)> {
+        if let Some(path) = &self.overrides.config_path {
+            return Ok((path.clone(), ConfigSource::Cli("config".to_string())));
+        }
+        if let Some(path) = self.env.get(ENV_CONFIG).filter(|path| !path.is_empty()) {
+            return Ok((PathBuf::from(path), ConfigSource::Env(ENV_CONFIG.to_string())));
+        }
+        let search_paths = self.search_paths();
+        for path in &search_paths {
+            if path.is_file() {
+                return Ok((path.clone(), ConfigSource::File(path.clone())));
+            }
+        }
+        Err(Error::ConfigNotFound(
+            search_paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ))
+    }
+
+    pub fn load(&self) -> Result<LoadedConfig> {
+        let (path, path_source) = self.locate()?;
+        let content = std::fs::read_to_string(&path)?;
+        self.load_str(&content, path, path_source)
+    }
+
+    /// Applies the environment and overrides to config file `content`.
+    pub fn load_str(&self, content: &str, path: PathBuf, path_source: ConfigSource) -> Result<LoadedConfig> {
+        let raw: serde_yaml::Value = serde_yaml::from_str(content)?;
+        let mut config: Config = serde_yaml::from_value(raw.clone())?;
+
+        let mut sources = BTreeMap::new();
+        let mut file_keys = Vec::new();
+        flatten_yaml("", &raw, &mut file_keys);
+        for (key, _) in file_keys {
+            sources.insert(key, ConfigSource::File(path.clone()));
+        }
+
+        let env_module_paths: Vec<PathBuf> = self
+            .env
+            .get(ENV_MODULE_PATH)
+            .map(|paths| std::env::split_paths(paths).filter(|p| !p.as_os_str().is_empty()).collect())
+            .unwrap_or_default();
+        if !env_module_paths.is_empty() {
+            let source = ConfigSource::Env(ENV_MODULE_PATH.to_string());
+            prepend_module_paths(&mut config, &mut sources, env_module_paths, source);
+        }
+        if let Some(level) = self.env.get(ENV_LOG_LEVEL).filter(|level| !level.is_empty()) {
+            config.agent.log_level = level.clone();
+            sources.insert("agent.log_level".to_string(), ConfigSource::Env(ENV_LOG_LEVEL.to_string()));
+        }
+        if let Some(url) = self.env.get(ENV_SERVER_URL).filter(|url| !url.is_empty()) {
+            config.server.url = url.clone();
+            sources.insert("server.url".to_string(), ConfigSource::Env(ENV_SERVER_URL.to_string()));
+        }
+
+        if !self.overrides.module_paths.is_empty() {
+            let source = ConfigSource::Cli("module-path".to_string());
+            prepend_module_paths(&mut config, &mut sources, self.overrides.module_paths.clone(), source);
+        }
+        if let Some(level) = &self.overrides.log_level {
+            config.agent.log_level = level.clone();
+            sources.insert("agent.log_level".to_string(), ConfigSource::Cli("log-level".to_string()));
+        }
+        if let Some(url) = &self.overrides.server_url {
+            config.server.url = url.clone();
+            sources.insert("server.url".to_string(), ConfigSource::Cli("server-url".to_string()));
+        }
+        if let Some(pid_file) = &self.overrides.pid_file {
+            config.agent.daemon.pid_file = pid_file.clone();
+            sources.insert("agent.daemon.pid_file".to_string(), ConfigSource::Cli("pid-file".to_string()));
+        }
+
+        Ok(LoadedConfig {
+            config,
+            path,
+            path_source,
+            sources,
+            content: content.to_string(),
+        })
+    }
+}
+
+/// Puts `paths` in front of the module paths. The paths already there keep
+/// counting, so the list is attributed to every source that added to it.
+fn prepend_module_paths(
+    config: &mut Config,
+    sources: &mut BTreeMap<String, ConfigSource>,
+    paths: Vec<PathBuf>,
+    source: ConfigSource,
+) {
+    let key = "agent.module_paths".to_string();
+    let below = sources.remove(&key).filter(|_| !config.agent.module_paths.is_empty());
+    let source = match below {
+        None => source,
+        Some(ConfigSource::Layered(mut below)) => {
+            below.insert(0, source);
+            ConfigSource::Layered(below)
+        }
+        Some(below) => ConfigSource::Layered(vec![source, below]),
+    };
+    config.agent.module_paths.splice(0..0, paths);
+    sources.insert(key, source);
+}
+
+/// Collects the dotted path and rendered value of every leaf in `value`.
+/// Sequences count as leaves.
+fn flatten_yaml(prefix: &str, value: &serde_yaml::Value, out: &mut Vec<(String, String)>) {
+    match value {
+        serde_yaml::Value::Mapping(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let key = match key {
+                    serde_yaml::Value::String(key) => key.clone(),
+                    other => serde_yaml::to_string(other).unwrap_or_default().trim().to_string(),
+                };
+                let path = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                flatten_yaml(&path, value, out);
+            }
+        }
+        _ => {
+            let rendered = serde_json::to_string(value).unwrap_or_default();
+            out.push((prefix.to_string(), rendered));
+        }
+    }
 }
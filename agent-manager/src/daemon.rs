@@ -0,0 +1,170 @@
+use crate::Error;
+use crate::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// What the pid file says about the agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonStatus {
+    /// An agent holds the pid file lock.
+    Running(i32),
+    /// The pid file exists but nothing holds its lock; the agent died
+    /// without cleaning up.
+    Stale(Option<i32>),
+    NotRunning,
+}
+
+impl DaemonStatus {
+    /// Exit code for `dep_map status`, following the LSB init script
+    /// conventions.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DaemonStatus::Running(_) => 0,
+            DaemonStatus::Stale(_) => 1,
+            DaemonStatus::NotRunning => 3,
+        }
+    }
+}
+
+/// The pid file of a detached agent.
+///
+/// The running agent keeps an exclusive `flock` on the file for as long as
+/// it lives, so the lock, not the pid, is what tells whether it is running.
+/// This holds up against pid reuse and against a pid file left behind by a
+/// crash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        PidFile { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn read_pid(&self) -> Result<Option<i32>> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(content.trim().parse().ok()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Whether another process holds the lock.
+    pub fn is_locked(&self) -> Result<bool> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        // The probe lock is released when `file` is closed.
+        let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0;
+        if locked {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::WouldBlock {
+                return Err(error.into());
+            }
+        }
+        Ok(locked)
+    }
+
+    pub fn status(&self) -> Result<DaemonStatus> {
+        if !self.path.exists() {
+            return Ok(DaemonStatus::NotRunning);
+        }
+        let pid = self.read_pid()?;
+        match (self.is_locked()?, pid) {
+            (true, Some(pid)) => Ok(DaemonStatus::Running(pid)),
+            // Locked but the pid is not written yet: the agent is starting.
+            (true, None) => Ok(DaemonStatus::Running(0)),
+            (false, pid) => Ok(DaemonStatus::Stale(pid)),
+        }
+    }
+
+    /// Fails if an agent is already running with this pid file.
+    pub fn ensure_not_running(&self) -> Result<()> {
+        match self.status()? {
+            DaemonStatus::Running(pid) => Err(Error::DetachError(format!(
+                "agent is already running (pid {}, pid file {})",
+                pid,
+                self.path.display()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits until the lock is taken, i.e. a freshly forked agent is up.
+    pub fn wait_until_running(&self, timeout: Duration) -> Result<DaemonStatus> {
+        wait_for(timeout, || {
+            Ok(match self.status()? {
+                DaemonStatus::Running(pid) if pid > 0 => Some(DaemonStatus::Running(pid)),
+                _ => None,
+            })
+        })
+        .map(|status| status.unwrap_or(DaemonStatus::NotRunning))
+    }
+
+    /// Sends SIGTERM to the running agent and waits for it to exit. Returns
+    /// the pid that was stopped, or `None` if no agent was running.
+    pub fn stop(&self, timeout: Duration) -> Result<Option<i32>> {
+        let pid = match self.status()? {
+            DaemonStatus::Running(pid) if pid > 0 => pid,
+            DaemonStatus::Running(_) => {
+                return Err(Error::DetachError("agent is still starting, try again".to_string()))
+            }
+            DaemonStatus::Stale(_) => {
+                self.remove()?;
+                return Ok(None);
+            }
+            DaemonStatus::NotRunning => return Ok(None),
+        };
+
+        if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let stopped = wait_for(timeout, || Ok((!self.is_locked()?).then_some(())))?;
+        if stopped.is_none() {
+            return Err(Error::DetachError(format!(
+                "agent (pid {}) did not stop within {}s",
+                pid,
+                timeout.as_secs()
+            )));
+        }
+        Ok(Some(pid))
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Opens `path` for appending, creating it and its directory as needed.
+pub fn open_append(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+fn wait_for<T>(timeout: Duration, mut check: impl FnMut() -> Result<Option<T>>) -> Result<Option<T>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(value) = check()? {
+            return Ok(Some(value));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::aggregate::DependencyKey;
+use crate::engine::{Batch, BatchKind, Dependency, ExposedService, ModuleOutput};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Remembers what was last delivered for one module so that later runs can
+/// report only the difference.
+///
+/// The baseline only moves in [`DeltaTracker::commit`], which the engine
+/// calls once a batch has been delivered or queued. A failed upload therefore
+/// gets its changes folded into the next batch instead of losing them.
+#[derive(Debug, Clone, Default)]
+pub struct DeltaTracker {
+    reported: HashMap<DependencyKey, Dependency>,
+    services: Vec<ExposedService>,
+    last_full: Option<Instant>,
+}
+
+impl DeltaTracker {
+    /// Builds the batch to send for `output`, or `None` when nothing changed
+    /// since the last delivered batch and no full snapshot is due.
+    pub fn batch(
+        &self,
+        module: &str,
+        output: &ModuleOutput,
+        full_interval: Duration,
+        trust_changed: bool,
+        now: Instant,
+    ) -> Option<Batch> {
+        let current: HashMap<DependencyKey, &Dependency> = output
+            .dependencies
+            .iter()
+            .map(|dependency| (DependencyKey::of(dependency), dependency))
+            .collect();
+        let removed: Vec<Dependency> = self
+            .reported
+            .iter()
+            .filter(|(key, _)| !current.contains_key(key))
+            .map(|(_, dependency)| dependency.clone())
+            .collect();
+
+        let full_due = self
+            .last_full
+            .is_none_or(|last_full| now.duration_since(last_full) >= full_interval);
+        if full_due {
+            return Some(Batch {
+                module: module.to_string(),
+                kind: BatchKind::Full,
+                dependencies: output.dependencies.clone(),
+                removed,
+                services: output.services.clone(),
+                health: None,
+            });
+        }
+
+        if trust_changed && !output.changed {
+            return None;
+        }
+
+        let added: Vec<Dependency> = output
+            .dependencies
+            .iter()
+            .filter(|dependency| !self.reported.contains_key(&DependencyKey::of(dependency)))
+            .cloned()
+            .collect();
+        if added.is_empty() && removed.is_empty() && output.services == self.services {
+            return None;
+        }
+
+        Some(Batch {
+            module: module.to_string(),
+            kind: BatchKind::Delta,
+            dependencies: added,
+            removed,
+            services: output.services.clone(),
+            health: None,
+        })
+    }
+
+    /// Makes `output` the baseline for the next diff.
+    ///
+    /// A delta only sends dependencies with new keys, so for keys that were
+    /// already reported the record the server got is kept: removals have to
+    /// name it exactly for the server to find it.
+    pub fn commit(&mut self, output: ModuleOutput, kind: BatchKind, now: Instant) {
+        let mut previous = std::mem::take(&mut self.reported);
+        self.reported = output
+            .dependencies
+            .into_iter()
+            .map(|dependency| {
+                let key = DependencyKey::of(&dependency);
+                let sent = previous.remove(&key).filter(|_| kind != BatchKind::Full);
+                (key, sent.unwrap_or(dependency))
+            })
+            .collect();
+        self.services = output.services;
+        if kind == BatchKind::Full {
+            self.last_full = Some(now);
+        }
+    }
+}
@@ -1,18 +1,37 @@
-use crate::config::{Config, ModuleConfig};
+use crate::aggregate::Aggregator;
+use crate::config::{AgentConfig, Config, ModuleConfig, OverlapPolicy};
+use crate::delta::DeltaTracker;
+use crate::health::{HealthStatus, HealthTracker, ModuleHealth};
+use crate::identity::{AgentIdentity, Enrollment};
+use crate::integrity::verify_module;
+use crate::logging::module_target;
+use crate::modules::resolve;
+use crate::outbox::{self, Outbox};
+use crate::sandbox::Sandbox;
+use crate::scheduler::ModuleSchedule;
+use crate::spec::{ModuleSpec, SpecCache, SpecViolation};
+use crate::status::{AgentStats, SharedStats};
+use crate::systemd::Notifier;
+use crate::transport::Transport;
 use crate::Error;
 use crate::Result;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::time::Instant;
 use tempfile::NamedTempFile;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{self, JoinError, JoinSet};
 use tokio::time;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all(serialize = "PascalCase", deserialize = "snake_case"))]
 pub struct Dependency {
     pub local_ip: String,
@@ -22,14 +41,208 @@ pub struct Dependency {
     pub remote_port: u16,
     pub module: String,
     pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<ProcessInfo>,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_port: Option<u16>,
+    /// How many sockets were collapsed into this record.
+    #[serde(default = "default_count")]
+    pub count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone)]
+fn default_count() -> u32 {
+    1
+}
+
+/// Which side of a connection the local host is on.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+    #[default]
+    Unknown,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+            Direction::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all(serialize = "PascalCase", deserialize = "snake_case"))]
+pub struct ProcessInfo {
+    pub pid: i32,
+    pub name: String,
+    pub exe: Option<String>,
+    pub cmdline: Option<String>,
+    pub user: Option<String>,
+}
+
+/// A service the host exposes. Listening sockets are reported here instead
+/// of as dependencies, since they have no remote side.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all(serialize = "PascalCase", deserialize = "snake_case"))]
+pub struct ExposedService {
+    pub module: String,
+    pub bind_ip: String,
+    pub port: u16,
+    pub protocol: String,
+    pub local_os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<ProcessInfo>,
+}
+
+/// What a module printed for one run.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleOutput {
+    pub dependencies: Vec<Dependency>,
+    pub services: Vec<ExposedService>,
+    pub changed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchKind {
+    /// Every dependency the module currently sees.
+    #[default]
+    Full,
+    /// Only what was added or removed since the previous batch.
+    Delta,
+    /// The module run failed; the batch carries no data, only its health.
+    Health,
+}
+
+/// A module's report as sent to the server.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct Batch {
+    pub module: String,
+    pub kind: BatchKind,
+    pub dependencies: Vec<Dependency>,
+    /// Dependencies that were reported before and are gone now.
+    pub removed: Vec<Dependency>,
+    pub services: Vec<ExposedService>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<ModuleHealth>,
+}
+
+/// What goes over the wire: a batch with the identity of the agent that sent
+/// it. The batch fields stay at the top level, next to `Agent`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct Envelope<'a> {
+    pub agent: &'a AgentIdentity,
+    #[serde(flatten)]
+    pub batch: &'a Batch,
+}
+
+#[derive(Debug)]
 pub struct CollectionEngine {
     config: Config,
-    module_last_run: HashMap<String, Instant>,
+    schedules: HashMap<String, ModuleSchedule>,
+    aggregators: HashMap<String, Aggregator>,
+    trackers: HashMap<String, DeltaTracker>,
+    health: HashMap<String, HealthTracker>,
+    /// Module specs, read once per config.
+    specs: SpecCache,
+    outbox: Option<Outbox>,
+    notifier: Notifier,
+    stats: SharedStats,
+    dry_run: bool,
+    /// Built on first use when the engine was created with [`Self::new`].
+    transport: Option<Transport>,
+    /// Loaded by [`Self::with_state`], or when the engine starts if it was
+    /// created with [`Self::new`].
+    identity: Option<AgentIdentity>,
+    /// Whether the server accepted our enrollment. Always true when
+    /// `server.enroll_url` is not set.
+    enrolled: bool,
+    /// Batches waiting to be sent when there is no outbox. Batches that fail
+    /// to upload are dropped.
+    pending: VecDeque<Vec<u8>>,
+}
+
+/// A finished module run as it comes back from the worker pool.
+struct ModuleRun {
+    name: String,
+    started_at: DateTime<Utc>,
+    duration: Duration,
+    result: Result<ModuleOutput>,
+}
+
+/// The modules running on the worker pool. Each task is tracked by its ID, so
+/// that a task that panicked still comes back as a failed run of its module
+/// instead of leaving the module marked as running forever.
+#[derive(Default)]
+struct ModuleTasks {
+    set: JoinSet<ModuleRun>,
+    started: HashMap<task::Id, (String, DateTime<Utc>, Instant)>,
+}
+
+impl ModuleTasks {
+    fn spawn(&mut self, name: &str, run: impl Future<Output = ModuleRun> + Send + 'static) {
+        let handle = self.set.spawn(run);
+        self.started
+            .insert(handle.id(), (name.to_string(), Utc::now(), Instant::now()));
+    }
+
+    /// The next finished run, or None when nothing is running.
+    async fn join_next(&mut self) -> Option<ModuleRun> {
+        loop {
+            match self.set.join_next_with_id().await? {
+                Ok((id, run)) => {
+                    self.started.remove(&id);
+                    return Some(run);
+                }
+                Err(e) => match self.started.remove(&e.id()) {
+                    Some((name, started_at, started)) => {
+                        return Some(ModuleRun {
+                            name,
+                            started_at,
+                            duration: started.elapsed(),
+                            result: Err(Error::TaskJoinError(e.to_string())),
+                        })
+                    }
+                    None => log::error!("Module task failed: {}", e),
+                },
+            }
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        self.set.shutdown().await;
+        self.started.clear();
+    }
 }
 
+/// The outcome of one round of uploads, run off the engine loop so that a
+/// slow server does not hold back module runs.
+struct Upload {
+    /// The ID the server assigned, when the round began by enrolling.
+    enrollment: Option<Result<Option<String>>>,
+    /// Whether the batches came from the outbox.
+    from_outbox: bool,
+    result: Result<()>,
+}
+
+type Uploads = JoinSet<Upload>;
+
+const MAX_AGENT_ID_LEN: usize = 128;
+
 impl PartialEq for CollectionEngine {
     fn eq(&self, other: &Self) -> bool {
         self.config == other.config
@@ -39,128 +252,1058 @@ impl PartialEq for CollectionEngine {
 impl CollectionEngine {
     pub fn new(config: Config) -> Self {
         CollectionEngine {
+            enrolled: config.server.enroll_url.is_none(),
+            identity: None,
+            stats: Arc::new(Mutex::new(AgentStats::new(config.clone()))),
             config,
-            module_last_run: HashMap::new(),
+            schedules: HashMap::new(),
+            aggregators: HashMap::new(),
+            trackers: HashMap::new(),
+            health: HashMap::new(),
+            specs: SpecCache::default(),
+            outbox: None,
+            notifier: Notifier::disabled(),
+            dry_run: false,
+            transport: None,
+            pending: VecDeque::new(),
         }
     }
 
-    pub async fn run(&mut self, mut shutdown_rx: mpsc::Receiver<()>) -> Result<()> {
-        println!("Starting engine...");
+    /// Builds an engine that spools batches to `<state_dir>/outbox` when the
+    /// agent config names a state directory. Credentials and certificates for
+    /// the server are loaded here, so that problems with them stop the agent
+    /// at startup.
+    pub fn with_state(config: Config) -> Result<Self> {
+        let transport = Transport::new(&config.server)?;
+        let identity = AgentIdentity::load(config.agent.state_dir.as_deref())?;
+        let outbox = match &config.agent.state_dir {
+            Some(state_dir) => Some(Outbox::open(
+                state_dir.join("outbox"),
+                config.agent.outbox.clone(),
+            )?),
+            None => None,
+        };
+        let engine = CollectionEngine {
+            outbox,
+            transport: Some(transport),
+            identity: Some(identity),
+            ..CollectionEngine::new(config)
+        };
+        engine.update_outbox_depth();
+        Ok(engine)
+    }
+
+    /// Reports readiness, status and watchdog pings to systemd through
+    /// `notifier` while the engine runs.
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Logs each batch instead of queueing or sending it. Batches already in
+    /// the outbox stay there.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Run statistics, shared with the status listener.
+    pub fn stats(&self) -> SharedStats {
+        self.stats.clone()
+    }
+
+    pub fn outbox(&self) -> Option<&Outbox> {
+        self.outbox.as_ref()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The identity sent with every batch. None until the engine starts
+    /// when it was created with [`Self::new`].
+    pub fn identity(&self) -> Option<&AgentIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Swaps in a reloaded config. Everything the new config needs is built
+    /// before anything is changed, so an invalid config leaves the engine
+    /// running on the old one.
+    ///
+    /// Added modules are scheduled, removed ones stop being scheduled, and
+    /// modules whose timing changed are rescheduled. Runs already in flight
+    /// finish and report as usual.
+    pub async fn apply_config(&mut self, config: Config) -> Result<()> {
+        let now = Instant::now();
+        let mut schedules = HashMap::new();
+        for (name, module) in &config.modules {
+            let schedule = match self.schedules.get(name) {
+                Some(previous) => {
+                    let old = self.config.modules.get(name);
+                    if old.is_some_and(|old| {
+                        !timing_changed(old, &self.config.agent, module, &config.agent)
+                    }) {
+                        Ok(previous.clone())
+                    } else {
+                        ModuleSchedule::rescheduled(module, &config.agent, previous, now)
+                    }
+                }
+                None => ModuleSchedule::for_module(module, &config.agent, now),
+            }
+            .map_err(|e| match e {
+                Error::InvalidSchedule(message) => {
+                    Error::InvalidSchedule(format!("module '{}': {}", name, message))
+                }
+                e => e,
+            })?;
+            schedules.insert(name.clone(), schedule);
+        }
+
+        // Always rebuilt, so that a reload picks up rotated credentials.
+        let transport = Transport::new(&config.server)?;
+        // Both may look up the FQDN, which blocks.
+        let identity = match self.identity.clone() {
+            Some(mut identity) if config.agent.state_dir == self.config.agent.state_dir => {
+                task::spawn_blocking(move || {
+                    identity.refresh();
+                    identity
+                })
+                .await
+                .map_err(|e| Error::TaskJoinError(e.to_string()))?
+            }
+            _ => {
+                let state_dir = config.agent.state_dir.clone();
+                task::spawn_blocking(move || AgentIdentity::load(state_dir.as_deref()))
+                    .await
+                    .map_err(|e| Error::TaskJoinError(e.to_string()))??
+            }
+        };
+        let outbox = if config.agent.state_dir == self.config.agent.state_dir
+            && config.agent.outbox == self.config.agent.outbox
+        {
+            self.outbox.take()
+        } else {
+            match &config.agent.state_dir {
+                Some(state_dir) => Some(Outbox::open(
+                    state_dir.join("outbox"),
+                    config.agent.outbox.clone(),
+                )?),
+                None => None,
+            }
+        };
+
+        // Runs in flight for a removed module keep their schedule until they
+        // complete, so that `complete` can still find them.
+        for (name, schedule) in &self.schedules {
+            if schedule.running && !schedules.contains_key(name) {
+                let mut schedule = schedule.clone();
+                schedule.queued = false;
+                schedules.insert(name.clone(), schedule);
+            }
+        }
+        let running: Vec<&String> = schedules
+            .iter()
+            .filter(|(_, schedule)| schedule.running)
+            .map(|(name, _)| name)
+            .collect();
+        self.aggregators
+            .retain(|name, _| config.modules.contains_key(name) || running.contains(&name));
+        self.trackers
+            .retain(|name, _| config.modules.contains_key(name) || running.contains(&name));
+        self.health
+            .retain(|name, _| config.modules.contains_key(name) || running.contains(&name));
+
+        self.schedules = schedules;
+        self.outbox = outbox;
+        self.transport = Some(transport);
+        if self.identity.as_ref().is_none_or(|current| current.agent_id != identity.agent_id)
+            || config.server.enroll_url != self.config.server.enroll_url
+        {
+            self.enrolled = config.server.enroll_url.is_none();
+        }
+        self.identity = Some(identity);
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.config = config.clone();
+            stats.modules.retain(|name, _| config.modules.contains_key(name));
+        }
+        self.config = config;
+        self.specs.clear();
+        self.update_outbox_depth();
+        Ok(())
+    }
+
+    pub async fn run(&mut self, shutdown_rx: mpsc::Receiver<()>) -> Result<()> {
+        let (_reload_tx, reload_rx) = mpsc::channel(1);
+        self.run_with_reload(shutdown_rx, reload_rx).await
+    }
+
+    /// Like [`CollectionEngine::run`], and also applies every config received
+    /// on `reload_rx`. A config that fails to apply is reported and ignored.
+    pub async fn run_with_reload(
+        &mut self,
+        mut shutdown_rx: mpsc::Receiver<()>,
+        mut reload_rx: mpsc::Receiver<Config>,
+    ) -> Result<()> {
+        log::info!("Starting engine");
+        let permits = Arc::new(Semaphore::new(self.config.agent.max_concurrency.max(1)));
+        let mut tasks = ModuleTasks::default();
+        let mut uploads = Uploads::new();
+        self.schedule_modules(Instant::now());
+        self.load_identity().await;
+        // Before any module runs, so that the first batches already carry an
+        // ID the server assigned.
+        self.enroll().await;
+        self.notifier.ready();
+        self.notify_status();
+
         loop {
+            self.notifier.keepalive(Instant::now());
+            if uploads.is_empty() {
+                if let Some(upload) = self.prepare_upload() {
+                    uploads.spawn(upload);
+                }
+            }
+            let wakeup = self.next_wakeup(Instant::now());
             tokio::select! {
                 _ = shutdown_rx.recv() => {
-                    println!("Shutdown signal received. Gracefully shutting down...");
+                    log::info!("Shutdown signal received, shutting down");
                     break;
                 }
-                _ = time::sleep(Duration::from_secs(1)) => {
-                    self.run_iteration().await?;
+                Some(config) = reload_rx.recv() => {
+                    self.notifier.reloading();
+                    let old_concurrency = self.config.agent.max_concurrency.max(1);
+                    match self.apply_config(config).await {
+                        Ok(()) => {
+                            resize_pool(&permits, old_concurrency, self.config.agent.max_concurrency.max(1));
+                            log::info!("Config reloaded");
+                        }
+                        Err(e) => log::error!("Keeping current config, reloaded config is invalid: {}", e),
+                    }
+                    self.notifier.ready();
+                    self.notify_status();
+                }
+                Some(run) = tasks.join_next() => {
+                    self.complete(run, &permits, &mut tasks);
+                    self.notify_status();
                 }
+                Some(joined) = uploads.join_next() => {
+                    let _ = self.finish_upload(joined);
+                }
+                _ = time::sleep_until(time::Instant::from_std(wakeup)) => {
+                    self.dispatch_due(Instant::now(), &permits, &mut tasks);
+                }
+            }
+        }
+
+        self.notifier.stopping();
+        tasks.shutdown().await;
+        // Batches without an outbox would be lost, so they get one more try,
+        // bounded by the server timeout so that a dead server cannot hold up
+        // shutdown.
+        let deadline = Duration::from_secs(self.config.server.timeout);
+        let drained = time::timeout(deadline, async {
+            while let Some(joined) = uploads.join_next().await {
+                let _ = self.finish_upload(joined);
             }
+            let _ = self.flush_outbox().await;
+        })
+        .await;
+        if drained.is_err() && !self.pending.is_empty() {
+            log::warn!("Dropping {} batch(es) that could not be sent before shutdown", self.pending.len());
         }
         Ok(())
     }
 
-    async fn run_iteration(&mut self) -> Result<()> {
-        let now = Instant::now();
+    /// One line summing up module health, e.g. `3 modules: 2 ok, 1 failing
+    /// (std.connections: timeout)`.
+    pub fn status_line(&self) -> String {
+        let mut failing: Vec<String> = Vec::new();
+        let mut ok = 0;
+        let mut waiting = 0;
+        let mut names: Vec<&String> = self.config.modules.keys().collect();
+        names.sort();
+        for name in &names {
+            match self.health.get(*name).and_then(HealthTracker::last_status) {
+                Some(HealthStatus::Ok) => ok += 1,
+                Some(status) => failing.push(format!("{}: {}", name, status.as_str())),
+                None => waiting += 1,
+            }
+        }
+
+        let mut line = format!("{} modules: {} ok", names.len(), ok);
+        if waiting > 0 {
+            line.push_str(&format!(", {} not run yet", waiting));
+        }
+        if !failing.is_empty() {
+            line.push_str(&format!(", {} failing ({})", failing.len(), failing.join(", ")));
+        }
+        line
+    }
+
+    fn notify_status(&self) {
+        if self.notifier.is_enabled() {
+            self.notifier.status(&self.status_line());
+        }
+    }
+
+    /// Creates schedules for modules that do not have one yet. A module
+    /// whose schedule cannot be built is reported and left out.
+    fn schedule_modules(&mut self, now: Instant) {
         for (name, module) in &self.config.modules {
-            let interval = Duration::from_secs(module.interval);
-            if let Some(last_run) = self.module_last_run.get(name) {
-                if now.duration_since(*last_run) < interval {
-                    continue;
+            if self.schedules.contains_key(name) {
+                continue;
+            }
+            match ModuleSchedule::for_module(module, &self.config.agent, now) {
+                Ok(schedule) => {
+                    self.schedules.insert(name.clone(), schedule);
                 }
+                Err(e) => log::error!(target: &module_target(name), "Module '{}' will not run: {}", name, e),
+            }
+        }
+    }
+
+    /// The earliest module due time, but no later than a second from now so
+    /// the outbox keeps draining.
+    fn next_wakeup(&self, now: Instant) -> Instant {
+        let tick = now + Duration::from_secs(1);
+        self.schedules
+            .values()
+            .map(|schedule| schedule.next_run)
+            .fold(tick, Instant::min)
+    }
+
+    /// Starts every module whose run is due, applying its overlap policy if
+    /// the previous run has not finished yet.
+    fn dispatch_due(
+        &mut self,
+        now: Instant,
+        permits: &Arc<Semaphore>,
+        tasks: &mut ModuleTasks,
+    ) {
+        for (name, schedule) in &mut self.schedules {
+            let Some(module) = self.config.modules.get(name) else {
+                continue;
+            };
+            if !schedule.is_due(now) {
+                continue;
             }
+            schedule.advance(now);
 
-            if let Ok(module_path) = self.find_module_path(name) {
-                if let Err(e) = self.run_module(name, &module_path, module).await {
-                    eprintln!("Error running module '{}': {}", name, e);
+            if schedule.running {
+                match module.overlap {
+                    OverlapPolicy::Skip => {
+                        log::warn!(target: &module_target(name), "Module '{}' is still running, skipping this run", name)
+                    }
+                    OverlapPolicy::Queue => schedule.queued = true,
                 }
-                self.module_last_run.insert(name.clone(), now);
+                continue;
             }
+
+            schedule.running = true;
+            schedule.last_run = Some(now);
+            spawn_module(tasks, permits, name, module, &self.config.agent, &self.specs);
         }
-        Ok(())
     }
 
-    fn find_module_path(&self, module_name: &str) -> Result<PathBuf> {
-        let sanitized_module_name = sanitize_module_name(module_name)?;
-        let module_path = sanitized_module_name.replace(".", "/");
-        for base_path in &self.config.agent.module_paths {
-            let full_path = base_path.join(&module_path);
-            if full_path.exists() {
-                return Ok(full_path);
+    /// Handles a finished module run: records its health, queues its output
+    /// for the server and starts the queued run if there is one.
+    fn complete(&mut self, run: ModuleRun, permits: &Arc<Semaphore>, tasks: &mut ModuleTasks) {
+        let now = Instant::now();
+        let name = run.name.as_str();
+        let tracker = self.health.entry(name.to_string()).or_default();
+        let recovered = tracker
+            .last_status()
+            .is_some_and(|status| status != HealthStatus::Ok);
+        let health = tracker.record(&run.result, Utc::now());
+
+        let delivered = match run.result {
+            Ok(mut output) => {
+                let aggregator = self.aggregators.entry(name.to_string()).or_default();
+                output.dependencies = aggregator.aggregate(output.dependencies, Utc::now());
+                self.record_run(name, run.started_at, run.duration, &health, Some(output.dependencies.len()));
+                self.report(name, output, health, recovered, now)
+            }
+            Err(e) => {
+                log::error!(target: &module_target(name), "Error running module '{}': {}", name, e);
+                self.record_run(name, run.started_at, run.duration, &health, None);
+                self.report_failure(name, health)
+            }
+        };
+        if let Err(e) = delivered {
+            log::error!("Error queueing data for the server: {}", e);
+        }
+
+        if !self.config.modules.contains_key(name) {
+            // The module was removed by a reload while this run was in flight.
+            self.schedules.remove(name);
+            self.aggregators.remove(name);
+            self.trackers.remove(name);
+            self.health.remove(name);
+            return;
+        }
+        let Some(schedule) = self.schedules.get_mut(name) else {
+            return;
+        };
+        schedule.running = false;
+        if schedule.queued {
+            if let Some(module) = self.config.modules.get(name) {
+                schedule.queued = false;
+                schedule.running = true;
+                schedule.last_run = Some(now);
+                spawn_module(tasks, permits, name, module, &self.config.agent, &self.specs);
             }
         }
-        Err(Error::ModuleNotFound(module_name.to_string()))
     }
 
-    async fn run_module(&self, name: &str, path: &Path, module: &ModuleConfig) -> Result<()> {
-        let mut command = tokio::process::Command::new(path);
+    /// Sends what changed in a module's output since its last delivered
+    /// batch, or a full snapshot when one is due.
+    fn report(
+        &mut self,
+        name: &str,
+        output: ModuleOutput,
+        health: ModuleHealth,
+        recovered: bool,
+        now: Instant,
+    ) -> Result<()> {
+        let full_interval = Duration::from_secs(self.config.agent.full_snapshot_interval);
+        let trust_changed = self
+            .config
+            .modules
+            .get(name)
+            .is_some_and(|module| module.trust_changed);
+        let tracker = self.trackers.entry(name.to_string()).or_default();
 
-        let temp_file = if let Some(args) = &module.args {
-            let mut file = NamedTempFile::new()?;
-            let args_json = serde_json::to_string(args)?;
-            println!("Writing args to file: {}", args_json);
-            file.write_all(args_json.as_bytes())?;
-            Some(file)
-        } else {
-            None
+        let batch = match tracker.batch(name, &output, full_interval, trust_changed, now) {
+            Some(batch) => batch,
+            // Let the server know the module is healthy again even if its
+            // data did not change.
+            None if recovered => Batch {
+                module: name.to_string(),
+                kind: BatchKind::Delta,
+                services: output.services.clone(),
+                ..Default::default()
+            },
+            None => return Ok(()),
+        };
+        let batch = Batch {
+            health: Some(health),
+            ..batch
+        };
+        self.deliver(&batch)?;
+
+        let tracker = self.trackers.entry(name.to_string()).or_default();
+        tracker.commit(output, batch.kind, now);
+        Ok(())
+    }
+
+    /// Reports a failed run. The delta baseline is left alone, since a
+    /// failure says nothing about whether dependencies went away.
+    fn report_failure(&mut self, name: &str, health: ModuleHealth) -> Result<()> {
+        let batch = Batch {
+            module: name.to_string(),
+            kind: BatchKind::Health,
+            health: Some(health),
+            ..Default::default()
         };
+        self.deliver(&batch)
+    }
 
-        if let Some(file) = &temp_file {
-            command.env("ARGS_FILE", file.path());
+    fn record_run(
+        &self,
+        name: &str,
+        started_at: DateTime<Utc>,
+        duration: Duration,
+        health: &ModuleHealth,
+        dependency_count: Option<usize>,
+    ) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.record_run(name, started_at, duration, health, dependency_count);
         }
+    }
 
-        let output = command.output().await?;
+    fn update_outbox_depth(&self) {
+        let depth = self.outbox.as_ref().and_then(|outbox| outbox.len().ok());
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.outbox_depth = depth;
+        }
+    }
 
-        // Clean up the temporary file
-        if let Some(file) = temp_file {
-            file.close()?;
+    /// Introduces the agent to the server at `server.enroll_url`, if set
+    /// and not done yet. A failed enrollment is retried before the next
+    /// upload; batches are sent either way.
+    pub async fn enroll(&mut self) {
+        if self.enrolled || self.dry_run {
+            return;
         }
+        if let Err(e) = self.try_enroll().await {
+            log::warn!("Enrollment failed, will retry: {}", e);
+        }
+    }
 
-        if !output.status.success() {
-            return Err(Error::ModuleExecution(format!(
-                "Module '{}' failed with status: {}",
-                name, output.status
-            )));
+    async fn try_enroll(&mut self) -> Result<()> {
+        let Some(url) = self.config.server.enroll_url.clone() else {
+            return Ok(());
+        };
+        let body = serde_json::to_vec(self.load_identity().await)?;
+        let agent_id = request_enrollment(self.transport()?, &url, body).await?;
+        self.accept_enrollment(agent_id)
+    }
+
+    /// Takes the ID the server answered an enrollment with, if any. An ID
+    /// that could not be a real one is refused before it reaches the disk.
+    fn accept_enrollment(&mut self, agent_id: Option<String>) -> Result<()> {
+        let identity = self.identity.as_mut().ok_or_else(not_loaded)?;
+        if let Some(agent_id) = agent_id.filter(|id| *id != identity.agent_id) {
+            check_agent_id(&agent_id)?;
+            log::info!("Server assigned agent ID {} (was {})", agent_id, identity.agent_id);
+            identity.adopt_id(&agent_id, self.config.agent.state_dir.as_deref())?;
         }
+        self.enrolled = true;
+        log::info!("Enrolled with the server as {}", identity.agent_id);
+        Ok(())
+    }
 
-        let result: Value = serde_json::from_slice(&output.stdout)?;
-        let data: Vec<Dependency> = match result.get("dependencies") {
-            Some(Value::Array(arr)) => serde_json::from_value(Value::Array(arr.to_vec()))
-                .unwrap_or_else(|e| {
-                    eprintln!("Error deserializing dependencies: {:?}", e);
-                    Vec::new()
-                }),
-            _ => {
-                eprintln!("'dependencies' field is missing or not an array");
+    /// Loads the identity of an engine created with [`Self::new`], off the
+    /// async runtime since it may write the agent ID and look up the FQDN.
+    /// Falls back to an ID that is not saved when the state directory
+    /// cannot be used.
+    async fn load_identity(&mut self) -> &AgentIdentity {
+        if self.identity.is_none() {
+            let state_dir = self.config.agent.state_dir.clone();
+            let identity = task::spawn_blocking(move || {
+                AgentIdentity::load(state_dir.as_deref()).unwrap_or_else(|e| {
+                    log::warn!("{}, using an agent ID that is not saved", e);
+                    AgentIdentity::load(None).expect("nothing to read or write without a state directory")
+                })
+            })
+            .await
+            .expect("loading the identity does not panic");
+            self.identity = Some(identity);
+        }
+        self.identity.as_ref().expect("loaded above")
+    }
+
+    /// Queues a batch in the outbox, or for the next upload when there is
+    /// none. Nothing is sent from here.
+    fn deliver(&mut self, batch: &Batch) -> Result<()> {
+        let identity = self.identity.as_mut().ok_or_else(not_loaded)?;
+        identity.refresh_addresses();
+        let envelope = Envelope {
+            agent: identity,
+            batch,
+        };
+        if self.dry_run {
+            log::info!(
+                target: &module_target(&batch.module),
+                "Dry run, not sending batch for '{}': {}",
+                batch.module,
+                serde_json::to_string(&envelope)?
+            );
+            return Ok(());
+        }
+        let body = serde_json::to_vec(&envelope)?;
+        match &mut self.outbox {
+            Some(outbox) => {
+                outbox.push(&body)?;
+                self.update_outbox_depth();
+            }
+            None => self.pending.push_back(body),
+        }
+        Ok(())
+    }
+
+    /// Sends queued batches oldest first. Outbox batches stop at the first
+    /// failure so that order is preserved, and failures back off
+    /// exponentially.
+    pub async fn flush_outbox(&mut self) -> Result<()> {
+        match self.prepare_upload() {
+            Some(upload) => {
+                let upload = upload.await;
+                self.finish_upload(Ok(upload))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn transport(&mut self) -> Result<&Transport> {
+        if self.transport.is_none() {
+            self.transport = Some(Transport::new(&self.config.server)?);
+        }
+        Ok(self.transport.as_ref().expect("built above"))
+    }
+
+    /// Takes what is waiting to be sent: the outbox once its backoff has
+    /// passed, or the batches queued in memory. Enrollment is retried first
+    /// when it has not succeeded yet.
+    fn prepare_upload(&mut self) -> Option<impl Future<Output = Upload> + Send + 'static> {
+        if self.dry_run {
+            return None;
+        }
+        let entries = match &self.outbox {
+            Some(outbox) if outbox.ready() => outbox.entries().unwrap_or_else(|e| {
+                log::warn!("Cannot read the outbox: {}", e);
                 Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+        if entries.is_empty() && self.pending.is_empty() {
+            return None;
+        }
+        let enrollment = (!self.enrolled)
+            .then(|| self.config.server.enroll_url.clone())
+            .flatten()
+            .zip(self.identity.as_ref())
+            .map(|(url, identity)| (url, serde_json::to_vec(identity)));
+        let bodies: Vec<Vec<u8>> = self.pending.drain(..).collect();
+        let from_outbox = !entries.is_empty();
+        let stats = self.stats.clone();
+        let transport = match self.transport() {
+            Ok(transport) => transport.clone(),
+            Err(e) => {
+                let message = e.to_string();
+                return Some(futures::future::Either::Left(async move {
+                    Upload {
+                        enrollment: None,
+                        from_outbox,
+                        result: Err(Error::Transport(message)),
+                    }
+                }));
             }
         };
 
-        // Send result to server
-        self.send_to_server(&data).await?;
+        Some(futures::future::Either::Right(async move {
+            let enrollment = match enrollment {
+                Some((url, body)) => Some(match body {
+                    Ok(body) => request_enrollment(&transport, &url, body).await,
+                    Err(e) => Err(e.into()),
+                }),
+                None => None,
+            };
+            let result = if from_outbox {
+                send_entries(&transport, &stats, entries).await
+            } else {
+                send_bodies(&transport, &stats, bodies).await
+            };
+            Upload {
+                enrollment,
+                from_outbox,
+                result,
+            }
+        }))
+    }
 
-        Ok(())
+    /// Records how a round of uploads went and returns its result.
+    fn finish_upload(&mut self, joined: std::result::Result<Upload, JoinError>) -> Result<()> {
+        let upload = match joined {
+            Ok(upload) => upload,
+            Err(e) => {
+                log::error!("Upload task failed: {}", e);
+                return Err(Error::TaskJoinError(e.to_string()));
+            }
+        };
+        if let Some(enrollment) = upload.enrollment {
+            if let Err(e) = enrollment.and_then(|agent_id| self.accept_enrollment(agent_id)) {
+                log::warn!("Enrollment failed, will retry: {}", e);
+            }
+        }
+        if upload.from_outbox {
+            if let Some(outbox) = &mut self.outbox {
+                match &upload.result {
+                    Ok(()) => outbox.record_success(),
+                    Err(_) => outbox.record_failure(),
+                }
+            }
+            self.update_outbox_depth();
+        }
+        if let Err(e) = &upload.result {
+            if upload.from_outbox {
+                log::warn!("Outbox delivery failed, will retry: {}", e);
+            } else {
+                log::error!("Error sending data to server: {}", e);
+            }
+        }
+        upload.result
+    }
+}
+
+fn not_loaded() -> Error {
+    Error::Identity("the agent identity is not loaded yet".to_string())
+}
+
+/// Agent IDs are UUIDs, but servers may assign their own. Anything that does
+/// not fit on one line in the ID file, or is implausibly long, is refused.
+fn check_agent_id(agent_id: &str) -> Result<()> {
+    if agent_id.is_empty() || agent_id.len() > MAX_AGENT_ID_LEN {
+        return Err(Error::Identity(format!(
+            "the server assigned an agent ID of {} bytes, expected 1 to {}",
+            agent_id.len(),
+            MAX_AGENT_ID_LEN
+        )));
     }
+    if agent_id.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(Error::Identity(format!(
+            "the server assigned an agent ID with whitespace or control characters: {:?}",
+            agent_id
+        )));
+    }
+    Ok(())
+}
 
-    async fn send_to_server(&self, data: &Vec<Dependency>) -> Result<()> {
-        let client = reqwest::Client::new();
+/// Asks the server at `url` to enroll the agent described by `body`, and
+/// returns the ID it assigned, if any.
+async fn request_enrollment(transport: &Transport, url: &str, body: Vec<u8>) -> Result<Option<String>> {
+    let response = transport.post(url, body).await?;
+    // An empty answer or one without an ID leaves ours in place.
+    let enrollment: Enrollment = if response.iter().all(u8::is_ascii_whitespace) {
+        Enrollment::default()
+    } else {
+        serde_json::from_slice(&response)?
+    };
+    Ok(enrollment.agent_id)
+}
 
-        let response = client
-            .post(&self.config.server.url)
-            .timeout(Duration::from_secs(self.config.server.timeout))
-            .json(data)
-            .send()
-            .await?;
+/// Sends outbox entries oldest first, removing each once the server took it.
+/// Entries the server refuses are set aside; any other failure stops the
+/// round so that it is retried.
+async fn send_entries(transport: &Transport, stats: &SharedStats, entries: Vec<PathBuf>) -> Result<()> {
+    for entry in entries {
+        let body = match std::fs::read(&entry) {
+            Ok(body) => body,
+            // Dropped by the outbox limits since the round started.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let sent = transport.send(body).await;
+        record_upload(stats, &sent);
+        match sent {
+            Err(e) if e.is_rejection() => {
+                let moved = outbox::reject(&entry)?;
+                log::warn!("Server refused a batch ({}), moved it to {}", e, moved.display());
+                continue;
+            }
+            sent => sent?,
+        }
+        match std::fs::remove_file(&entry) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
 
-        if !response.status().is_success() {
-            return Err(Error::ModuleExecution(format!(
-                "Server responded with status: {}",
-                response.status()
+/// Sends batches that were never written to disk. A failed batch is dropped
+/// and the rest are still sent; the last error is returned.
+async fn send_bodies(transport: &Transport, stats: &SharedStats, bodies: Vec<Vec<u8>>) -> Result<()> {
+    let mut result = Ok(());
+    for body in bodies {
+        let sent = transport.send(body).await;
+        record_upload(stats, &sent);
+        if let Err(e) = sent {
+            result = Err(e);
+        }
+    }
+    result
+}
+
+fn record_upload(stats: &SharedStats, result: &Result<()>) {
+    if let Ok(mut stats) = stats.lock() {
+        stats.record_upload(result.is_ok());
+    }
+}
+
+/// Whether a module's run times change between two configs.
+fn timing_changed(
+    old: &ModuleConfig,
+    old_agent: &AgentConfig,
+    new: &ModuleConfig,
+    new_agent: &AgentConfig,
+) -> bool {
+    old.interval.unwrap_or(old_agent.default_interval) != new.interval.unwrap_or(new_agent.default_interval)
+        || old.schedule != new.schedule
+        || old.splay.unwrap_or(old_agent.splay) != new.splay.unwrap_or(new_agent.splay)
+}
+
+/// Grows or shrinks the worker pool. Shrinking only takes effect as running
+/// modules hand back their permits.
+fn resize_pool(permits: &Arc<Semaphore>, old: usize, new: usize) {
+    if new > old {
+        permits.add_permits(new - old);
+    } else if new < old {
+        let mut remaining = old - new;
+        remaining -= permits.forget_permits(remaining);
+        if remaining > 0 {
+            let permits = permits.clone();
+            tokio::spawn(async move {
+                if let Ok(taken) = permits.acquire_many_owned(remaining as u32).await {
+                    taken.forget();
+                }
+            });
+        }
+    }
+}
+
+/// Runs one module on the worker pool. The permit is taken before the module
+/// starts, so waiting for a free slot does not count against its timeout.
+fn spawn_module(
+    tasks: &mut ModuleTasks,
+    permits: &Arc<Semaphore>,
+    name: &str,
+    module: &ModuleConfig,
+    agent: &AgentConfig,
+    specs: &SpecCache,
+) {
+    let permits = permits.clone();
+    let module = module.clone();
+    let agent = agent.clone();
+    let specs = specs.clone();
+    let module_name = name.to_string();
+
+    tasks.spawn(name, async move {
+        let _permit = permits.acquire_owned().await.expect("worker pool is never closed");
+        let started_at = Utc::now();
+        let started = Instant::now();
+        let result = collect_module(&agent, &module_name, &module, &specs).await;
+        ModuleRun {
+            name: module_name,
+            started_at,
+            duration: started.elapsed(),
+            result,
+        }
+    });
+}
+
+/// Finds a module on `module_paths` and runs it once, exactly as the engine
+/// does on schedule. Used by `dep_map run` and `dep_map collect --once`.
+pub async fn collect_module(
+    agent: &AgentConfig,
+    name: &str,
+    module: &ModuleConfig,
+    specs: &SpecCache,
+) -> Result<ModuleOutput> {
+    let path = find_module_path(&agent.module_paths, name)?;
+    verify_module(&agent.integrity, name, &path, Some(module))?;
+    let spec = specs.get(name, &path, module, &agent.integrity).await?;
+    let sandbox = Sandbox::for_module(agent, Some(module))?;
+    run_module(name, &path, module, spec, &sandbox, agent.module_timeout).await
+}
+
+pub(crate) fn find_module_path(module_paths: &[PathBuf], module_name: &str) -> Result<PathBuf> {
+    let sanitized_module_name = sanitize_module_name(module_name)?;
+    let module_path = sanitized_module_name.replace(".", "/");
+    resolve(module_paths, Path::new(&module_path)).ok_or_else(|| Error::ModuleNotFound(module_name.to_string()))
+}
+
+async fn run_module(
+    name: &str,
+    path: &Path,
+    module: &ModuleConfig,
+    spec: Option<ModuleSpec>,
+    sandbox: &Sandbox,
+    default_timeout: u64,
+) -> Result<ModuleOutput> {
+    if let Some(spec) = &spec {
+        let violations = spec.check_args(module.args.as_ref());
+        if !violations.is_empty() {
+            return Err(Error::InvalidModuleInput(format!(
+                "Module '{}' args: {}",
+                name,
+                join(&violations)
             )));
         }
+    }
 
-        Ok(())
+    let mut command = sandbox_command(path, sandbox)?;
+
+    let temp_file = if let Some(args) = &module.args {
+        let mut file = NamedTempFile::new()?;
+        let args_json = serde_json::to_string(args)?;
+        log::debug!(target: &module_target(name), "Writing args to file: {}", args_json);
+        file.write_all(args_json.as_bytes())?;
+        // The file is private to the agent's user; a module running as
+        // another user needs to own it to read it.
+        #[cfg(unix)]
+        if let Some((uid, gid)) = sandbox.owner() {
+            std::os::unix::fs::fchown(file.as_file(), uid, gid)?;
+        }
+        Some(file)
+    } else {
+        None
+    };
+
+    if let Some(file) = &temp_file {
+        command.env("ARGS_FILE", file.path());
+    }
+
+    let timeout = module.timeout.unwrap_or(default_timeout);
+    let output = run_with_timeout(name, command, Duration::from_secs(timeout)).await?;
+
+    // Clean up the temporary file
+    if let Some(file) = temp_file {
+        file.close()?;
     }
+
+    parse_module_output(name, output, spec.as_ref())
+}
+
+/// A command that spawns the module at `path` inside `sandbox`.
+pub(crate) fn sandbox_command(path: &Path, sandbox: &Sandbox) -> Result<tokio::process::Command> {
+    // A relative path would be resolved against the sandbox's working dir.
+    let path = match sandbox.working_dir() {
+        Some(_) => std::fs::canonicalize(path)?,
+        None => path.to_path_buf(),
+    };
+    let mut command = tokio::process::Command::new(path);
+    sandbox.apply(&mut command);
+    Ok(command)
+}
+
+/// Runs a module in its own process group and kills the whole group if it
+/// outlives `timeout`, so that children it forked do not linger either.
+pub(crate) async fn run_with_timeout(
+    name: &str,
+    mut command: tokio::process::Command,
+    timeout: Duration,
+) -> Result<std::process::Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let child = command.spawn()?;
+    let mut guard = ProcessGroupGuard(child.id());
+
+    match time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => {
+            guard.disarm();
+            Ok(output?)
+        }
+        Err(_) => Err(Error::ModuleTimeout {
+            module: name.to_string(),
+            seconds: timeout.as_secs(),
+        }),
+    }
+}
+
+/// Kills a module's whole process group when dropped, which covers both a
+/// timeout and the engine abandoning the run on shutdown. Disarmed once the
+/// module exits on its own.
+struct ProcessGroupGuard(Option<u32>);
+
+impl ProcessGroupGuard {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            // The child leads its own group, so its pid is the group id.
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Turns a finished module process into its output, or into the error that
+/// best describes why it has none.
+fn parse_module_output(
+    name: &str,
+    output: std::process::Output,
+    spec: Option<&ModuleSpec>,
+) -> Result<ModuleOutput> {
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    let target = module_target(name);
+    for line in stderr.lines().filter(|line| !line.trim().is_empty()) {
+        log::debug!(target: &target, "stderr: {}", line);
+    }
+    let result: Option<Value> = serde_json::from_slice(&output.stdout).ok();
+
+    // `fail_json` prints a failure report and exits non-zero, so look for the
+    // report before falling back to the exit status.
+    if let Some(result) = &result {
+        if result.get("failed").and_then(Value::as_bool) == Some(true) {
+            let message = result
+                .get("error")
+                .and_then(Value::as_str)
+                .unwrap_or("module reported failure without an error message")
+                .to_string();
+            return Err(Error::ModuleFailed {
+                module: name.to_string(),
+                message,
+                code: output.status.code(),
+                stderr,
+            });
+        }
+    }
+
+    if !output.status.success() {
+        return Err(Error::ModuleExit {
+            module: name.to_string(),
+            status: output.status.to_string(),
+            code: output.status.code(),
+            stderr,
+        });
+    }
+
+    let Some(result) = result else {
+        return Err(Error::InvalidModuleOutput(format!(
+            "Module '{}' did not print a JSON object",
+            name
+        )));
+    };
+    if let Some(spec) = spec {
+        let violations = spec.check_output(&result);
+        if !violations.is_empty() {
+            return Err(Error::InvalidModuleOutput(format!(
+                "Module '{}' output does not match its spec: {}",
+                name,
+                join(&violations)
+            )));
+        }
+    }
+    let dependencies: Vec<Dependency> = match result.get("dependencies") {
+        Some(Value::Array(arr)) => serde_json::from_value(Value::Array(arr.to_vec()))
+            .map_err(|e| {
+                Error::InvalidModuleOutput(format!("Module '{}' dependencies: {}", name, e))
+            })?,
+        _ => {
+            return Err(Error::InvalidModuleOutput(format!(
+                "Module '{}': 'dependencies' field is missing or not an array",
+                name
+            )))
+        }
+    };
+    let services: Vec<ExposedService> = match result.get("services") {
+        Some(Value::Array(arr)) => serde_json::from_value(Value::Array(arr.to_vec()))
+            .map_err(|e| {
+                Error::InvalidModuleOutput(format!("Module '{}' services: {}", name, e))
+            })?,
+        _ => Vec::new(),
+    };
+    let changed = result
+        .get("changed")
+        .and_then(Value::as_bool)
+        .unwrap_or(true);
+
+    Ok(ModuleOutput {
+        dependencies,
+        services,
+        changed,
+    })
+}
+
+fn join(violations: &[SpecViolation]) -> String {
+    violations
+        .iter()
+        .map(SpecViolation::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 fn sanitize_module_name(name: &str) -> Result<String> {
@@ -18,6 +18,25 @@ pub enum Error {
     #[error("Module execution error: {0}")]
     ModuleExecution(String),
 
+    #[error("Module '{module}' exited with status: {status}")]
+    ModuleExit {
+        module: String,
+        status: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+
+    #[error("Module '{module}' reported failure: {message}")]
+    ModuleFailed {
+        module: String,
+        message: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+
+    #[error("Module '{module}' timed out after {seconds}s")]
+    ModuleTimeout { module: String, seconds: u64 },
+
     #[error("Task join error: {0}")]
     TaskJoinError(String),
 
@@ -30,15 +49,67 @@ pub enum Error {
     #[error("Invalid plugin type: {0}")]
     FromUtf8Error(#[from] std::string::FromUtf8Error),
 
+    #[error("Invalid module manifest: {0}")]
+    InvalidManifest(String),
+
     #[error("Module not found: {0}")]
     ModuleNotFound(String),
 
+    #[error("Refusing to run module: {0}")]
+    UntrustedModule(String),
+
+    #[error("Sandbox error: {0}")]
+    Sandbox(String),
+
     #[error("Invalid module name: {0}")]
     InvalidModuleName(String),
 
+    #[error("No config file found (searched {0})")]
+    ConfigNotFound(String),
+
+    #[error("Invalid config:\n{}", .0.iter().map(|issue| format!("  {}", issue)).collect::<Vec<_>>().join("\n"))]
+    InvalidConfig(Vec<crate::validate::ConfigIssue>),
+
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+
+    #[error("Agent identity error: {0}")]
+    Identity(String),
+
+    #[error("Transport error: {0}")]
+    Transport(String),
+
+    #[error("Server responded with status: {0}")]
+    ServerStatus(reqwest::StatusCode),
+
+    #[error("Relay error: {0}")]
+    Relay(String),
+
+    #[error("Status listener error: {0}")]
+    Status(String),
+
+    #[error("Logging error: {0}")]
+    Logging(String),
+
     #[error("Detach error: {0}")]
     DetachError(String),
 }
 
+impl Error {
+    /// Whether the server refused a request outright, so that sending it
+    /// again cannot succeed. Network errors, 5xx, 408 and 429 are worth a
+    /// retry.
+    pub fn is_rejection(&self) -> bool {
+        match self {
+            Error::ServerStatus(status) => {
+                status.is_client_error()
+                    && *status != reqwest::StatusCode::REQUEST_TIMEOUT
+                    && *status != reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
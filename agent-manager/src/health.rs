@@ -0,0 +1,141 @@
+use crate::engine::ModuleOutput;
+use crate::Error;
+use crate::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Keep only the tail of a module's stderr; that is where the error usually is.
+const MAX_STDERR_BYTES: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    /// The module ran and reported `failed: true`.
+    Failed,
+    /// The module exited non-zero without a failure report.
+    NonZeroExit,
+    /// The module's stdout was not a valid response.
+    MalformedOutput,
+    /// The module ran past its timeout and was killed.
+    Timeout,
+    NotFound,
+    /// The module file failed its integrity checks and was not run.
+    Untrusted,
+    /// The module could not be started.
+    ExecutionError,
+}
+
+impl HealthStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthStatus::Ok => "ok",
+            HealthStatus::Failed => "failed",
+            HealthStatus::NonZeroExit => "non_zero_exit",
+            HealthStatus::MalformedOutput => "malformed_output",
+            HealthStatus::Timeout => "timeout",
+            HealthStatus::NotFound => "not_found",
+            HealthStatus::Untrusted => "untrusted",
+            HealthStatus::ExecutionError => "execution_error",
+        }
+    }
+}
+
+/// The outcome of a module's latest run, sent to the server with every batch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct ModuleHealth {
+    pub status: HealthStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    pub checked_at: DateTime<Utc>,
+    /// When the current run of failures started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failing_since: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+}
+
+impl ModuleHealth {
+    pub fn is_ok(&self) -> bool {
+        self.status == HealthStatus::Ok
+    }
+}
+
+/// Follows one module's results across runs.
+#[derive(Debug, Clone, Default)]
+pub struct HealthTracker {
+    last_status: Option<HealthStatus>,
+    failing_since: Option<DateTime<Utc>>,
+    consecutive_failures: u32,
+}
+
+impl HealthTracker {
+    pub fn record(&mut self, result: &Result<ModuleOutput>, now: DateTime<Utc>) -> ModuleHealth {
+        let (status, message, exit_code, stderr) = match result {
+            Ok(_) => (HealthStatus::Ok, None, None, None),
+            Err(e) => classify(e),
+        };
+
+        if status == HealthStatus::Ok {
+            self.failing_since = None;
+            self.consecutive_failures = 0;
+        } else {
+            self.failing_since.get_or_insert(now);
+            self.consecutive_failures += 1;
+        }
+        self.last_status = Some(status);
+
+        ModuleHealth {
+            status,
+            message,
+            exit_code,
+            stderr,
+            checked_at: now,
+            failing_since: self.failing_since,
+            consecutive_failures: self.consecutive_failures,
+        }
+    }
+
+    /// Status of the most recently recorded run.
+    pub fn last_status(&self) -> Option<HealthStatus> {
+        self.last_status
+    }
+}
+
+fn classify(error: &Error) -> (HealthStatus, Option<String>, Option<i32>, Option<String>) {
+    let message = Some(error.to_string());
+    match error {
+        Error::ModuleFailed { message, code, stderr, .. } => (
+            HealthStatus::Failed,
+            Some(message.clone()),
+            *code,
+            truncate_stderr(stderr),
+        ),
+        Error::ModuleExit { code, stderr, .. } => {
+            (HealthStatus::NonZeroExit, message, *code, truncate_stderr(stderr))
+        }
+        Error::InvalidModuleOutput(_) | Error::Json(_) => {
+            (HealthStatus::MalformedOutput, message, None, None)
+        }
+        Error::ModuleTimeout { .. } => (HealthStatus::Timeout, message, None, None),
+        Error::ModuleNotFound(_) => (HealthStatus::NotFound, message, None, None),
+        Error::UntrustedModule(_) => (HealthStatus::Untrusted, message, None, None),
+        _ => (HealthStatus::ExecutionError, message, None, None),
+    }
+}
+
+fn truncate_stderr(stderr: &str) -> Option<String> {
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        return None;
+    }
+    let mut start = stderr.len().saturating_sub(MAX_STDERR_BYTES);
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    Some(stderr[start..].to_string())
+}
@@ -0,0 +1,256 @@
+use crate::Error;
+use crate::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const MACHINE_ID_FILES: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// Which agent sent a batch, and from which host.
+///
+/// The server cannot tell hosts apart by `LocalIp` alone: NAT, hosts with
+/// several addresses and DHCP all get in the way. `agent_id` is what stays
+/// the same for the life of an install.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct AgentIdentity {
+    pub agent_id: String,
+    pub hostname: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fqdn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    /// Every address on a non-loopback interface.
+    pub addresses: Vec<InterfaceAddress>,
+    pub agent_version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "PascalCase")]
+pub struct InterfaceAddress {
+    pub interface: String,
+    pub address: String,
+}
+
+/// The server's answer to an enrollment. It may hand the agent a different
+/// ID, for example the one a reinstalled host had before.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct Enrollment {
+    #[serde(default)]
+    pub agent_id: Option<String>,
+}
+
+impl AgentIdentity {
+    /// Reads the agent ID from `<state_dir>/agent_id`, generating and saving
+    /// one on first start. Without a state directory the ID is derived from
+    /// the machine ID, so that it still survives restarts.
+    pub fn load(state_dir: Option<&Path>) -> Result<Self> {
+        let machine_id = machine_id();
+        let agent_id = match state_dir {
+            Some(state_dir) => {
+                let path = id_file(state_dir);
+                match read_id(&path)? {
+                    Some(agent_id) => agent_id,
+                    None => {
+                        let agent_id = generate_id();
+                        write_id(&path, &agent_id)?;
+                        log::info!("Generated agent ID {} in {}", agent_id, path.display());
+                        agent_id
+                    }
+                }
+            }
+            None => match machine_id.as_deref().and_then(uuid_from_hex) {
+                Some(agent_id) => agent_id,
+                None => {
+                    log::warn!("No machine ID and no agent.state_dir, the agent ID changes on every start");
+                    generate_id()
+                }
+            },
+        };
+
+        let mut identity = AgentIdentity {
+            agent_id,
+            hostname: String::new(),
+            fqdn: None,
+            machine_id,
+            addresses: Vec::new(),
+            agent_version: AGENT_VERSION.to_string(),
+        };
+        identity.refresh();
+        Ok(identity)
+    }
+
+    /// Looks up the hostname, FQDN and addresses again. The FQDN may need a
+    /// DNS lookup, so this runs at startup and on reload only.
+    pub fn refresh(&mut self) {
+        self.hostname = hostname().unwrap_or_else(|| "unknown".to_string());
+        self.fqdn = fqdn(&self.hostname);
+        self.refresh_addresses();
+    }
+
+    /// Addresses come and go with DHCP and VPNs, so they are read again for
+    /// every batch.
+    pub fn refresh_addresses(&mut self) {
+        self.addresses = interface_addresses();
+    }
+
+    /// Takes the ID the server assigned, saving it when there is a state
+    /// directory.
+    pub fn adopt_id(&mut self, agent_id: &str, state_dir: Option<&Path>) -> Result<()> {
+        if let Some(state_dir) = state_dir {
+            write_id(&id_file(state_dir), agent_id)?;
+        }
+        self.agent_id = agent_id.to_string();
+        Ok(())
+    }
+}
+
+fn id_file(state_dir: &Path) -> PathBuf {
+    state_dir.join("agent_id")
+}
+
+fn read_id(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let agent_id = content.trim();
+            if agent_id.is_empty() {
+                return Err(Error::Identity(format!("{} is empty", path.display())));
+            }
+            Ok(Some(agent_id.to_string()))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Identity(format!("cannot read {}: {}", path.display(), e))),
+    }
+}
+
+/// Writes the ID through a temporary file, so a crash never leaves a
+/// truncated ID behind.
+fn write_id(path: &Path, agent_id: &str) -> Result<()> {
+    let write = || -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, format!("{}\n", agent_id))?;
+        fs::rename(&temp, path)
+    };
+    write().map_err(|e| Error::Identity(format!("cannot write {}: {}", path.display(), e)))
+}
+
+/// A random (version 4) UUID.
+pub fn generate_id() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    uuid_from_hex(&hex).expect("32 hex digits")
+}
+
+/// Formats 32 hex digits, such as a machine ID, as a UUID.
+fn uuid_from_hex(hex: &str) -> Option<String> {
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = hex.to_ascii_lowercase();
+    Some(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+}
+
+fn machine_id() -> Option<String> {
+    MACHINE_ID_FILES
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|content| content.trim().to_string())
+        .find(|machine_id| !machine_id.is_empty())
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let end = buf.iter().position(|&byte| byte == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..end].to_vec()).ok().filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// The canonical name the resolver gives for `hostname`, when it has a
+/// domain part.
+#[cfg(unix)]
+fn fqdn(hostname: &str) -> Option<String> {
+    use std::ffi::{CStr, CString};
+
+    let host = CString::new(hostname).ok()?;
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    hints.ai_family = libc::AF_UNSPEC;
+    let mut result = std::ptr::null_mut();
+    if unsafe { libc::getaddrinfo(host.as_ptr(), std::ptr::null(), &hints, &mut result) } != 0 {
+        return None;
+    }
+    let name = unsafe { (*result).ai_canonname };
+    let fqdn = (!name.is_null()).then(|| unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned());
+    unsafe { libc::freeaddrinfo(result) };
+    fqdn.filter(|fqdn| fqdn.contains('.'))
+}
+
+#[cfg(not(unix))]
+fn fqdn(_hostname: &str) -> Option<String> {
+    None
+}
+
+#[cfg(unix)]
+fn interface_addresses() -> Vec<InterfaceAddress> {
+    use std::ffi::CStr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Vec::new();
+    }
+    let mut addresses = Vec::new();
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() {
+        let entry = unsafe { &*cursor };
+        cursor = entry.ifa_next;
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+        let address = match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        if address.is_loopback() {
+            continue;
+        }
+        addresses.push(InterfaceAddress {
+            interface: unsafe { CStr::from_ptr(entry.ifa_name) }.to_string_lossy().into_owned(),
+            address: address.to_string(),
+        });
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+#[cfg(not(unix))]
+fn interface_addresses() -> Vec<InterfaceAddress> {
+    Vec::new()
+}
@@ -0,0 +1,250 @@
+use crate::config::{IntegrityConfig, ModuleConfig};
+use crate::Error;
+use crate::Result;
+use base64::Engine as _;
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Module hashes from `integrity.manifest_file`, by module name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashManifest {
+    hashes: HashMap<String, String>,
+}
+
+impl HashManifest {
+    /// Reads the configured manifest and checks its signature. None when no
+    /// manifest is configured. The manifest, signature and key must pass the
+    /// same ownership and permission checks as modules, since whoever can
+    /// replace the key can sign any manifest.
+    pub fn load(integrity: &IntegrityConfig) -> Result<Option<Self>> {
+        let Some(manifest_file) = &integrity.manifest_file else {
+            return Ok(None);
+        };
+        let key_file = integrity
+            .public_key_file
+            .as_ref()
+            .ok_or_else(|| Error::UntrustedModule("integrity.manifest_file needs a public_key_file".to_string()))?;
+        let signature_file = signature_path(integrity).expect("manifest_file is set");
+        for path in [manifest_file, key_file, &signature_file] {
+            check_permissions(integrity, path)?;
+        }
+        let content = read(manifest_file)?;
+        let key = String::from_utf8(read(key_file)?)
+            .map_err(|_| Error::UntrustedModule(format!("{} is not a PEM public key", key_file.display())))?;
+        verify_signature(&content, &read(&signature_file)?, &key).map_err(|e| match e {
+            Error::UntrustedModule(message) => {
+                Error::UntrustedModule(format!("{}: {}", manifest_file.display(), message))
+            }
+            e => e,
+        })?;
+        let content = String::from_utf8(content)
+            .map_err(|_| Error::UntrustedModule(format!("{} is not text", manifest_file.display())))?;
+        Self::parse(&content).map(Some)
+    }
+
+    /// Parses `sha256sum` output. Modules may be named as in the config
+    /// (`std.connections`) or by their path under the module path
+    /// (`std/connections`, `./std/connections`).
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut hashes = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                Error::UntrustedModule(format!(
+                    "line {} of the hash manifest is not '<sha256>  <module>'",
+                    number + 1
+                ))
+            };
+            let (hash, module) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let hash = normalize_hash(hash).ok_or_else(invalid)?;
+            // `sha256sum -b` marks binary files with a `*`.
+            let module = module.trim_start().trim_start_matches('*');
+            let name = module.trim_start_matches("./").replace('/', ".");
+            if name.is_empty() {
+                return Err(invalid());
+            }
+            hashes.insert(name, hash);
+        }
+        Ok(HashManifest { hashes })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.hashes.get(name).map(String::as_str)
+    }
+}
+
+/// The signature file of the configured manifest.
+pub fn signature_path(integrity: &IntegrityConfig) -> Option<PathBuf> {
+    integrity.signature_file.clone().or_else(|| {
+        integrity.manifest_file.as_ref().map(|manifest_file| {
+            let mut path = manifest_file.as_os_str().to_owned();
+            path.push(".sig");
+            PathBuf::from(path)
+        })
+    })
+}
+
+/// Checks an Ed25519 signature, given raw or base64, against a PEM public
+/// key such as `openssl pkey -pubout` writes.
+pub fn verify_signature(content: &[u8], signature: &[u8], public_key_pem: &str) -> Result<()> {
+    let key = VerifyingKey::from_public_key_pem(public_key_pem.trim())
+        .map_err(|e| Error::UntrustedModule(format!("invalid Ed25519 public key: {}", e)))?;
+    let signature = match <[u8; 64]>::try_from(signature) {
+        Ok(raw) => raw,
+        Err(_) => base64::engine::general_purpose::STANDARD
+            .decode(String::from_utf8_lossy(signature).trim())
+            .ok()
+            .and_then(|decoded| <[u8; 64]>::try_from(decoded).ok())
+            .ok_or_else(|| Error::UntrustedModule("signature is neither 64 raw bytes nor base64".to_string()))?,
+    };
+    key.verify(content, &Signature::from_bytes(&signature))
+        .map_err(|_| Error::UntrustedModule("signature does not match".to_string()))
+}
+
+/// Lowercase hex SHA-256 of a file.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let digest = Sha256::digest(read(path)?);
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// A 64-digit hex SHA-256 in lowercase, or None if `hash` is not one.
+pub fn normalize_hash(hash: &str) -> Option<String> {
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())).then(|| hash.to_ascii_lowercase())
+}
+
+/// Checks a module file before it runs: its ownership and permissions, the
+/// `sha256` pinned in its config, and its entry in the signed manifest.
+pub fn verify_module(integrity: &IntegrityConfig, name: &str, path: &Path, module: Option<&ModuleConfig>) -> Result<()> {
+    check_permissions(integrity, path)?;
+
+    let pinned = module.and_then(|module| module.sha256.as_deref());
+    let manifest = HashManifest::load(integrity)?;
+    if pinned.is_none() && manifest.is_none() {
+        return Ok(());
+    }
+    let actual = sha256_file(path)?;
+    if let Some(pinned) = pinned {
+        if normalize_hash(pinned).as_deref() != Some(actual.as_str()) {
+            return Err(Error::UntrustedModule(format!(
+                "{} has SHA-256 {}, but the config pins {}",
+                path.display(),
+                actual,
+                pinned
+            )));
+        }
+    }
+    if let Some(manifest) = manifest {
+        match manifest.get(name) {
+            None => {
+                return Err(Error::UntrustedModule(format!(
+                    "module '{}' is not in the hash manifest",
+                    name
+                )))
+            }
+            Some(expected) if expected != actual => {
+                return Err(Error::UntrustedModule(format!(
+                    "{} has SHA-256 {}, but the hash manifest lists {}",
+                    path.display(),
+                    actual,
+                    expected
+                )))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// The file and every directory above it must be owned by a trusted user,
+/// and only sticky directories may be writable by everyone or by a group
+/// that is not trusted: anyone else could swap the file for their own.
+#[cfg(unix)]
+pub fn check_permissions(integrity: &IntegrityConfig, path: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let trusted = trusted_owners(integrity)?;
+    let trusted_groups = trusted_groups(integrity)?;
+    let path = fs::canonicalize(path)
+        .map_err(|e| Error::UntrustedModule(format!("cannot resolve {}: {}", path.display(), e)))?;
+    for (index, ancestor) in path.ancestors().enumerate() {
+        let metadata = fs::metadata(ancestor)
+            .map_err(|e| Error::UntrustedModule(format!("cannot read {}: {}", ancestor.display(), e)))?;
+        if !trusted.contains(&metadata.uid()) {
+            return Err(Error::UntrustedModule(format!(
+                "{} is owned by uid {}, which is not a trusted owner",
+                ancestor.display(),
+                metadata.uid()
+            )));
+        }
+        let mode = metadata.mode();
+        let sticky_dir = index > 0 && mode & 0o1000 != 0;
+        if mode & 0o002 != 0 && !sticky_dir {
+            return Err(Error::UntrustedModule(format!(
+                "{} is writable by everyone (mode {:o})",
+                ancestor.display(),
+                mode & 0o7777
+            )));
+        }
+        if mode & 0o020 != 0 && !sticky_dir && !trusted_groups.contains(&metadata.gid()) {
+            return Err(Error::UntrustedModule(format!(
+                "{} is writable by gid {} (mode {:o}), which is not a trusted group",
+                ancestor.display(),
+                metadata.gid(),
+                mode & 0o7777
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_permissions(_integrity: &IntegrityConfig, _path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// The uids in `trusted_owners`, or root and the agent's own user.
+#[cfg(unix)]
+pub fn trusted_owners(integrity: &IntegrityConfig) -> Result<Vec<u32>> {
+    if integrity.trusted_owners.is_empty() {
+        return Ok(vec![0, unsafe { libc::geteuid() }]);
+    }
+    integrity
+        .trusted_owners
+        .iter()
+        .map(|owner| {
+            crate::sandbox::lookup_user(owner)
+                .map(|(uid, _)| uid)
+                .ok_or_else(|| Error::UntrustedModule(format!("unknown user '{}' in trusted_owners", owner)))
+        })
+        .collect()
+}
+
+/// The gids in `trusted_groups`, or root's group and the agent's own.
+#[cfg(unix)]
+pub fn trusted_groups(integrity: &IntegrityConfig) -> Result<Vec<u32>> {
+    if integrity.trusted_groups.is_empty() {
+        return Ok(vec![0, unsafe { libc::getegid() }]);
+    }
+    integrity
+        .trusted_groups
+        .iter()
+        .map(|group| {
+            group
+                .parse()
+                .ok()
+                .or_else(|| crate::sandbox::lookup_group(group))
+                .ok_or_else(|| Error::UntrustedModule(format!("unknown group '{}' in trusted_groups", group)))
+        })
+        .collect()
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| Error::UntrustedModule(format!("cannot read {}: {}", path.display(), e)))
+}
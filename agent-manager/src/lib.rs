@@ -1,7 +1,27 @@
+pub mod aggregate;
+pub mod collect;
 pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod delta;
 pub mod engine;
 pub mod error;
+pub mod health;
+pub mod identity;
+pub mod integrity;
+pub mod logging;
+pub mod manifest;
+pub mod modules;
+pub mod outbox;
 pub mod relay;
+pub mod reload;
+pub mod sandbox;
+pub mod scheduler;
+pub mod spec;
+pub mod status;
+pub mod systemd;
+pub mod transport;
+pub mod validate;
 
 pub use config::Config;
 pub use engine::CollectionEngine;
@@ -0,0 +1,197 @@
+use crate::config::{AgentConfig, LogFormat};
+use crate::systemd::{journal_entry, send_datagram, JOURNAL_SOCKET};
+use crate::Error;
+use crate::Result;
+use chrono::{SecondsFormat, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Log target for messages about a module, so that modules can be turned up
+/// or down one at a time with `log.modules`.
+pub fn module_target(name: &str) -> String {
+    format!("module::{}", name)
+}
+
+/// The `env_logger` filter for an agent config: `log_level` first, then one
+/// directive per entry in `log.modules`.
+pub fn filter_spec(agent: &AgentConfig) -> String {
+    let mut modules: Vec<(&String, &String)> = agent.log.modules.iter().collect();
+    modules.sort();
+    let mut directives = vec![agent.log_level.clone()];
+    directives.extend(
+        modules
+            .into_iter()
+            .map(|(module, level)| format!("{}={}", module_target(module), level)),
+    );
+    directives.join(",")
+}
+
+/// Installs the global logger. Only the first call has any effect; the log
+/// settings of a reloaded config apply after a restart.
+pub fn init(agent: &AgentConfig) -> Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&filter_spec(agent));
+
+    if agent.log.format == LogFormat::Journald {
+        let logger = JournalLogger::new(JOURNAL_SOCKET, builder.build());
+        log::set_max_level(logger.filter.filter());
+        return log::set_boxed_logger(Box::new(logger)).map_err(|e| Error::Logging(e.to_string()));
+    }
+    if agent.log.format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", format_json(record)));
+    }
+    if let Some(path) = &agent.log.file {
+        let file = RotatingFile::open(path, agent.log.max_bytes, agent.log.max_files)?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+
+    builder
+        .try_init()
+        .map_err(|e| Error::Logging(e.to_string()))
+}
+
+/// Renders a record as a single JSON object.
+pub fn format_json(record: &log::Record) -> String {
+    serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+/// Sends records to journald over its native protocol, keeping the level as
+/// the priority and the target as a field. Records about a module carry its
+/// name in `DEP_MAP_MODULE`, so `journalctl DEP_MAP_MODULE=<name>` shows one
+/// module.
+#[derive(Debug)]
+pub struct JournalLogger {
+    socket: String,
+    filter: env_logger::Logger,
+}
+
+impl JournalLogger {
+    /// `filter` is only used to decide which records to send.
+    pub fn new(socket: impl Into<String>, filter: env_logger::Logger) -> Self {
+        JournalLogger {
+            socket: socket.into(),
+            filter,
+        }
+    }
+
+    pub fn entry(record: &log::Record) -> Vec<u8> {
+        let priority = match record.level() {
+            log::Level::Error => "3",
+            log::Level::Warn => "4",
+            log::Level::Info => "6",
+            log::Level::Debug | log::Level::Trace => "7",
+        };
+        let message = record.args().to_string();
+        let mut fields = vec![
+            ("MESSAGE", message.as_str()),
+            ("PRIORITY", priority),
+            ("SYSLOG_IDENTIFIER", "dep_map"),
+            ("TARGET", record.target()),
+        ];
+        if let Some(module) = record.target().strip_prefix("module::") {
+            fields.push(("DEP_MAP_MODULE", module));
+        }
+        journal_entry(&fields)
+    }
+}
+
+impl log::Log for JournalLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.filter.matches(record) {
+            // Nowhere left to report a failure to.
+            let _ = send_datagram(&self.socket, &JournalLogger::entry(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// A log file that is rotated once it would grow past `max_bytes`.
+///
+/// On rotation `agent.log` becomes `agent.log.1`, `agent.log.1` becomes
+/// `agent.log.2` and so on; files beyond `max_files` are deleted. A record is
+/// never split across two files.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, max_files: usize) -> Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&self.rotated(self.max_files))?;
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -1,17 +1,21 @@
 use std::path::PathBuf;
-use agent::Config;
+use agent::collect::{any_failed, collect_batch, collect_once, parse_args, render, OutputFormat};
+use agent::config::{AgentConfig, ConfigLoader, ConfigOverrides, LoadedConfig, LogFormat};
+use agent::engine::Batch;
+use agent::reload::ConfigWatcher;
+use agent::relay::Relay;
+use agent::status::{ListenAddr, StatusServer};
+use agent::systemd::Notifier;
 use agent::CollectionEngine;
 use tokio::sync::mpsc;
 use clap::{arg, command, value_parser, Command};
 
 #[cfg(unix)]
-use daemonize::Daemonize;
+use agent::daemon::{open_append, DaemonStatus, PidFile};
 #[cfg(unix)]
-use std::fs::File;
+use daemonize::{Daemonize, Outcome};
 #[cfg(unix)]
-use rand::{thread_rng, Rng};
-#[cfg(unix)]
-use rand::distributions::Alphanumeric;
+use std::time::Duration;
 
 #[cfg(windows)]
 use std::process::Command as StdCommand;
@@ -20,12 +24,45 @@ use std::os::windows::process::CommandExt;
 #[cfg(windows)]
 use winapi::um::winbase::CREATE_NO_WINDOW;
 
-#[tokio::main]
-async fn main() -> agent::Result<()> {
+/// How long `stop` waits for the agent to exit, and `--detach` for it to
+/// come up.
+#[cfg(unix)]
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() -> agent::Result<()> {
     let matches = command!()
         .arg(
             arg!(-c --config <FILE> "Sets the config file to use")
-                .required(true)
+                .required(false)
+                .global(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"log-level" <LEVEL> "Overrides the configured log level")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"server-url" <URL> "Overrides the configured server URL")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"module-path" <DIR> "Searches DIR for modules before the configured paths")
+                .required(false)
+                .global(true)
+                .action(clap::ArgAction::Append)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"show-config" "Print the effective config and where each value came from, then exit")
+                .required(false)
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"pid-file" <FILE> "Overrides the configured pid file of a detached agent")
+                .required(false)
+                .global(true)
                 .value_parser(value_parser!(PathBuf)),
         )
         .arg(
@@ -33,24 +70,299 @@ async fn main() -> agent::Result<()> {
                 .required(false)
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            arg!(--"dry-run" "Log the batches that would be sent instead of sending them")
+                .required(false)
+                .action(clap::ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new("check-config")
+                .about("Validates the config and reports every problem, then exits non-zero if there are errors")
+                .arg(arg!(--strict "Exit non-zero on warnings too").action(clap::ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Runs one module once and prints what it collected")
+                .arg(arg!(<MODULE> "Module to run, e.g. std.connections"))
+                .arg(arg!(--args <JSON> "Module args as a JSON object, replacing the configured ones").required(false))
+                .arg(format_arg()),
+        )
+        .subcommand(
+            Command::new("collect")
+                .about("Runs every configured module and prints what it collected")
+                .arg(arg!(--once "Run each module once, then exit").required(true))
+                .arg(format_arg()),
+        )
+        .subcommand(
+            Command::new("modules")
+                .about("Lists the modules on the module paths")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("Lists every module with its manifest, and warns about shadowed duplicates")
+                        .arg(arg!(--probe "Run modules without a manifest file with --describe").required(false))
+                        .arg(format_arg()),
+                )
+                .subcommand(
+                    Command::new("info")
+                        .about("Shows one module's manifest, running it with --describe if it ships none")
+                        .arg(arg!(<MODULE> "Module name, e.g. std.connections"))
+                        .arg(format_arg()),
+                ),
+        )
+        .subcommand(Command::new("stop").about("Stops the detached agent named by the pid file"))
+        .subcommand(Command::new("status").about("Reports whether the detached agent is running"))
+        .subcommand(
+            Command::new("systemd-unit")
+                .about("Prints a systemd unit that runs this agent with this config")
+                .arg(
+                    arg!(--watchdog <SECS> "WatchdogSec for the unit")
+                        .required(false)
+                        .default_value("120")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
         .get_matches();
 
-    let config_path = matches.get_one::<PathBuf>("config").unwrap();
+    let overrides = ConfigOverrides {
+        config_path: matches.get_one::<PathBuf>("config").cloned(),
+        log_level: matches.get_one::<String>("log-level").cloned(),
+        server_url: matches.get_one::<String>("server-url").cloned(),
+        module_paths: matches
+            .get_many::<PathBuf>("module-path")
+            .map(|paths| paths.cloned().collect())
+            .unwrap_or_default(),
+        pid_file: matches.get_one::<PathBuf>("pid-file").cloned(),
+    };
+    let loader = ConfigLoader::new(overrides.clone());
+
+    if let Some(check) = matches.subcommand_matches("check-config") {
+        check_config(&loader, check.get_flag("strict"));
+    }
+
+    let mut loaded = loader.load()?;
     let detach_mode = matches.get_flag("detach");
+    let dry_run = matches.get_flag("dry-run");
+
+    match matches.subcommand_name() {
+        Some("stop") => return stop_agent(&loaded),
+        Some("status") => status_agent(&loaded),
+        _ => {}
+    }
+    if let Some(run) = matches.subcommand_matches("run") {
+        let module = run.get_one::<String>("MODULE").expect("is required");
+        let args = match run.get_one::<String>("args").map(|args| parse_args(args)).transpose() {
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        init_console_logging(&loaded.config.agent)?;
+        let batch = runtime()?.block_on(collect_batch(&loaded.config, module, args));
+        print_batches(&[batch], output_format(run));
+    }
+    if let Some(collect) = matches.subcommand_matches("collect") {
+        init_console_logging(&loaded.config.agent)?;
+        let batches = runtime()?.block_on(collect_once(&loaded.config));
+        print_batches(&batches, output_format(collect));
+    }
+    if let Some(modules) = matches.subcommand_matches("modules") {
+        init_console_logging(&loaded.config.agent)?;
+        return list_modules(&loaded, modules);
+    }
+    if let Some(unit) = matches.subcommand_matches("systemd-unit") {
+        let watchdog = *unit.get_one::<u64>("watchdog").expect("has a default");
+        return print_systemd_unit(&loaded, watchdog);
+    }
+
+    if matches.get_flag("show-config") {
+        return show_config(&loaded);
+    }
+    let warnings = match loaded.validate() {
+        Ok(warnings) => warnings,
+        Err(e) => {
+            eprintln!("{}: {}", loaded.path.display(), e);
+            std::process::exit(1);
+        }
+    };
 
     if detach_mode {
-        return detach_process(config_path);
+        // On unix this returns in the detached child only.
+        detach_process(&mut loaded, &overrides, dry_run)?;
+    }
+
+    agent::logging::init(&loaded.config.agent)?;
+    for warning in warnings {
+        log::warn!("{}: {}", loaded.path.display(), warning);
+    }
+
+    // The runtime is built only now: its threads would not survive the fork
+    // in `detach_process`.
+    runtime()?.block_on(run_engine(loader, loaded, detach_mode, dry_run))
+}
+
+fn runtime() -> agent::Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread().enable_all().build()?)
+}
+
+fn format_arg() -> clap::Arg {
+    arg!(--format <FORMAT> "Output format")
+        .required(false)
+        .default_value("table")
+        .value_parser(["table", "json"])
+}
+
+fn output_format(matches: &clap::ArgMatches) -> OutputFormat {
+    matches
+        .get_one::<String>("format")
+        .and_then(|format| format.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Logs to stderr whatever the config says, so that module stderr shows up
+/// next to the output with `--log-level debug`.
+fn init_console_logging(agent: &AgentConfig) -> agent::Result<()> {
+    let mut agent = agent.clone();
+    agent.log.file = None;
+    agent.log.format = LogFormat::Text;
+    agent::logging::init(&agent)
+}
+
+/// Prints collected batches and exits, with status 1 if any run failed.
+fn print_batches(batches: &[Batch], format: OutputFormat) -> ! {
+    match render(batches, format) {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    std::process::exit(if any_failed(batches) { 1 } else { 0 });
+}
+
+fn list_modules(loaded: &LoadedConfig, matches: &clap::ArgMatches) -> agent::Result<()> {
+    let output = match matches.subcommand() {
+        Some(("list", list)) => {
+            let mut modules = agent::modules::discover(&loaded.config);
+            if list.get_flag("probe") {
+                runtime()?.block_on(agent::modules::probe(&loaded.config, &mut modules));
+            }
+            agent::modules::render_list(&modules, output_format(list))?
+        }
+        Some(("info", info)) => {
+            let name = info.get_one::<String>("MODULE").expect("is required");
+            let mut module = agent::modules::find(&loaded.config, name)?;
+            runtime()?.block_on(agent::modules::probe(&loaded.config, std::slice::from_mut(&mut module)));
+            agent::modules::render_info(&module, output_format(info))?
+        }
+        _ => unreachable!("a subcommand is required"),
+    };
+    print!("{}", output);
+    Ok(())
+}
+
+fn print_systemd_unit(loaded: &LoadedConfig, watchdog: u64) -> agent::Result<()> {
+    let executable = std::env::current_exe()?;
+    let config = std::fs::canonicalize(&loaded.path)?;
+    print!(
+        "{}",
+        agent::systemd::unit_file(&executable, &config, std::time::Duration::from_secs(watchdog))
+    );
+    Ok(())
+}
+
+fn show_config(loaded: &LoadedConfig) -> agent::Result<()> {
+    println!("# config file: {} ({})", loaded.path.display(), loaded.path_source);
+    for (key, value, source) in loaded.describe()? {
+        println!("{} = {}  # {}", key, value, source);
+    }
+    Ok(())
+}
+
+/// Prints every problem with the config and exits, with status 1 if there
+/// were any.
+fn check_config(loader: &ConfigLoader, strict: bool) -> ! {
+    let path = loader
+        .locate()
+        .map(|(path, _)| path.display().to_string())
+        .unwrap_or_else(|_| "config".to_string());
+    let issues = match loader.load() {
+        Ok(loaded) => agent::validate::validate_loaded(&loaded),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    if issues.is_empty() {
+        println!("{}: OK", path);
+        std::process::exit(0);
     }
+    for issue in &issues {
+        eprintln!("{}: {}", path, issue);
+    }
+    let errors = issues.iter().filter(|issue| issue.is_error()).count();
+    let warnings = issues.len() - errors;
+    eprintln!("{}: {} error(s), {} warning(s)", path, errors, warnings);
+    let failed = errors > 0 || (strict && warnings > 0);
+    std::process::exit(if failed { 1 } else { 0 });
+}
+
+#[cfg(unix)]
+fn stop_agent(loaded: &LoadedConfig) -> agent::Result<()> {
+    let pid_file = PidFile::new(&loaded.config.agent.daemon.pid_file);
+    match pid_file.stop(DAEMON_TIMEOUT)? {
+        Some(pid) => println!("Stopped dep_map (pid {})", pid),
+        None => println!("dep_map is not running"),
+    }
+    Ok(())
+}
+
+/// Prints whether the agent runs and exits with the matching LSB status code.
+#[cfg(unix)]
+fn status_agent(loaded: &LoadedConfig) -> ! {
+    let pid_file = PidFile::new(&loaded.config.agent.daemon.pid_file);
+    let status = match pid_file.status() {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("{}: {}", pid_file.path().display(), e);
+            std::process::exit(4);
+        }
+    };
+    match status {
+        DaemonStatus::Running(pid) => println!("dep_map is running (pid {})", pid),
+        DaemonStatus::Stale(Some(pid)) => println!(
+            "dep_map is not running, but pid file {} names pid {}",
+            pid_file.path().display(),
+            pid
+        ),
+        DaemonStatus::Stale(None) | DaemonStatus::NotRunning => println!("dep_map is not running"),
+    }
+    std::process::exit(status.exit_code());
+}
+
+#[cfg(not(unix))]
+fn stop_agent(_loaded: &LoadedConfig) -> agent::Result<()> {
+    Err(agent::Error::DetachError("stop is not supported on this platform".to_string()))
+}
 
-    run_engine(config_path, detach_mode).await
+#[cfg(not(unix))]
+fn status_agent(_loaded: &LoadedConfig) -> ! {
+    eprintln!("status is not supported on this platform");
+    std::process::exit(4);
 }
 
-fn detach_process(config_path: &PathBuf) -> agent::Result<()> {
+#[cfg_attr(not(windows), allow(unused_variables))]
+fn detach_process(loaded: &mut LoadedConfig, overrides: &ConfigOverrides, dry_run: bool) -> agent::Result<()> {
     #[cfg(unix)]
-    return unix_detach();
+    return unix_detach(loaded);
 
     #[cfg(windows)]
-    return windows_detach(config_path);
+    {
+        windows_detach(loaded, overrides, dry_run)?;
+        std::process::exit(0);
+    }
 
     #[cfg(not(any(unix, windows)))]
     {
@@ -59,47 +371,71 @@ fn detach_process(config_path: &PathBuf) -> agent::Result<()> {
     }
 }
 
+/// Forks the agent into the background. The parent exits once the child
+/// holds the pid file lock; the child returns and goes on to run the engine.
 #[cfg(unix)]
-fn unix_detach() -> agent::Result<()> {
-    let random_id: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(10)
-        .map(char::from)
-        .collect();
-
-    let stdout_path = format!("/tmp/daemon_{}.out", random_id);
-    let stderr_path = format!("/tmp/daemon_{}.err", random_id);
-    let pid_file_path = format!("/tmp/daemon_{}.pid", random_id);
+fn unix_detach(loaded: &mut LoadedConfig) -> agent::Result<()> {
+    let daemon = loaded.config.agent.daemon.clone();
+    let pid_file = PidFile::new(&daemon.pid_file);
+    pid_file.ensure_not_running()?;
+    if let Some(parent) = daemon.pid_file.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
 
-    let stdout = File::create(&stdout_path)?;
-    let stderr = File::create(&stderr_path)?;
+    let log_file = loaded.config.agent.log.file.get_or_insert(daemon.log_file).clone();
+    let mut stderr_path = log_file.clone().into_os_string();
+    stderr_path.push(".stderr");
+    let stderr_path = PathBuf::from(stderr_path);
+    let stderr = open_append(&stderr_path)?;
 
+    // Keep the working directory so that relative paths in the config, and
+    // the config path itself, still resolve on reload.
     let daemonize = Daemonize::new()
-        .pid_file(&pid_file_path)
-        .chown_pid_file(true)
-        .working_directory("/tmp")
-        .stdout(stdout)
+        .pid_file(&daemon.pid_file)
+        .working_directory(std::env::current_dir()?)
         .stderr(stderr);
 
-    match daemonize.start() {
-        Ok(_) => {
-            println!("Successfully daemonized. PID file: {}", pid_file_path);
-            println!("Stdout: {}", stdout_path);
-            println!("Stderr: {}", stderr_path);
-            Ok(())
+    match daemonize.execute() {
+        Outcome::Parent(Ok(_)) => match pid_file.wait_until_running(DAEMON_TIMEOUT)? {
+            DaemonStatus::Running(pid) => {
+                println!("Started dep_map (pid {})", pid);
+                println!("Pid file: {}", daemon.pid_file.display());
+                println!("Log file: {}", log_file.display());
+                std::process::exit(0);
+            }
+            _ => Err(agent::Error::DetachError(format!(
+                "agent did not start, see {}",
+                stderr_path.display()
+            ))),
         },
-        Err(e) => {
+        Outcome::Parent(Err(e)) | Outcome::Child(Err(e)) => {
             eprintln!("Error daemonizing: {}", e);
             Err(agent::Error::DetachError(e.to_string()))
         }
+        Outcome::Child(Ok(_)) => Ok(()),
     }
 }
 
 #[cfg(windows)]
-fn windows_detach(config_path: &PathBuf) -> agent::Result<()> {
+fn windows_detach(loaded: &LoadedConfig, overrides: &ConfigOverrides, dry_run: bool) -> agent::Result<()> {
     let executable = std::env::current_exe()?;
     let mut command = StdCommand::new(executable);
-    command.arg("--config").arg(config_path);
+    command.arg("--config").arg(&loaded.path);
+    if let Some(level) = &overrides.log_level {
+        command.arg("--log-level").arg(level);
+    }
+    if let Some(url) = &overrides.server_url {
+        command.arg("--server-url").arg(url);
+    }
+    if let Some(pid_file) = &overrides.pid_file {
+        command.arg("--pid-file").arg(pid_file);
+    }
+    for path in &overrides.module_paths {
+        command.arg("--module-path").arg(path);
+    }
+    if dry_run {
+        command.arg("--dry-run");
+    }
     command.creation_flags(CREATE_NO_WINDOW);
 
     match command.spawn() {
@@ -114,30 +450,83 @@ fn windows_detach(config_path: &PathBuf) -> agent::Result<()> {
     }
 }
 
-async fn run_engine(config_path: &PathBuf, detach_mode: bool) -> agent::Result<()> {
-    let config_str = std::fs::read_to_string(config_path)?;
-    println!("Config file loaded: {}", config_path.display());
-    let config: Config = serde_yaml::from_str(&config_str)?;
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn run_engine(
+    loader: ConfigLoader,
+    loaded: LoadedConfig,
+    detach_mode: bool,
+    dry_run: bool,
+) -> agent::Result<()> {
+    log::info!("Config file loaded: {} ({})", loaded.path.display(), loaded.path_source);
+    let config = loaded.config;
 
-    println!("Starting agent with config: {:?}", config);
+    log::debug!("Starting agent with config: {:?}", config);
 
     let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
-    let mut engine = CollectionEngine::new(config);
-
-    if !detach_mode {
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
-            println!("Ctrl+C received, initiating shutdown...");
-            shutdown_tx.send(()).await.expect("Failed to send shutdown signal");
-        });
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+    #[cfg(unix)]
+    let pid_file = PidFile::new(&config.agent.daemon.pid_file);
+    let status_listen = config.agent.status.listen.clone();
+    let mut engine = CollectionEngine::with_state(config)?
+        .with_notifier(Notifier::from_env())
+        .with_dry_run(dry_run);
+    if dry_run {
+        log::info!("Dry run: batches are logged, not sent");
     }
+    if let Some(listen) = status_listen {
+        let listen = ListenAddr::parse(&listen).map_err(agent::Error::Status)?;
+        let server = StatusServer::bind(&listen).await?;
+        log::info!("Status listener on {}", listen_description(&listen));
+        tokio::spawn(server.run(engine.stats()));
+    }
+    if let Some(relay_config) = &engine.config().agent.relay {
+        let identity = engine.identity().expect("loaded by with_state");
+        let relay = Relay::bind(engine.config(), identity).await?.with_dry_run(dry_run);
+        let scheme = if relay.is_tls() { "https" } else { "http" };
+        log::info!("Relaying batches from downstream agents on {}://{}", scheme, relay_config.listen);
+        tokio::spawn(relay.run());
+    }
+    tokio::spawn(ConfigWatcher::new(loader, loaded.path).watch(reload_tx));
+
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        let _ = shutdown_tx.send(()).await;
+    });
 
     // Run the engine
-    engine.run(shutdown_rx).await?;
-    
-    if !detach_mode {
-        println!("Shutdown complete.");
+    engine.run_with_reload(shutdown_rx, reload_rx).await?;
+
+    #[cfg(unix)]
+    if detach_mode {
+        pid_file.remove()?;
     }
-    
+    log::info!("Shutdown complete");
+
     Ok(())
 }
+
+fn listen_description(listen: &ListenAddr) -> String {
+    match listen {
+        ListenAddr::Tcp(addr) => format!("http://{}", addr),
+        ListenAddr::Unix(path) => format!("unix:{}", path.display()),
+    }
+}
+
+/// Ctrl+C, or SIGTERM from `dep_map stop` or a service manager.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => log::info!("Ctrl+C received, initiating shutdown"),
+            _ = terminate.recv() => log::info!("SIGTERM received, initiating shutdown"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
+        log::info!("Ctrl+C received, initiating shutdown");
+    }
+}
@@ -0,0 +1,111 @@
+use crate::engine::{run_with_timeout, sandbox_command};
+use crate::sandbox::Sandbox;
+use crate::spec::ModuleSpec;
+use crate::Error;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The argument a module is run with to print its manifest as JSON.
+pub const DESCRIBE_ARG: &str = "--describe";
+
+/// How long a module may take to answer [`DESCRIBE_ARG`].
+pub const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a module says about itself. Shipped as YAML next to the module, see
+/// [`manifest_path`], or printed as JSON by the module when it is run with
+/// `--describe`. Every field is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModuleManifest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Operating systems the module runs on, named as in
+    /// `std::env::consts::OS`: `linux`, `macos`, `windows`. Empty means any.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub os: Vec<String>,
+    /// What the module reports: `dependencies`, `services`, or both.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spec: Option<ModuleSpec>,
+}
+
+impl ModuleManifest {
+    /// Reads the manifest shipped next to the module at `module_path`. None
+    /// when there is no manifest file.
+    pub fn load(module_path: &Path) -> Result<Option<Self>> {
+        let path = manifest_path(module_path);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::InvalidManifest(format!("cannot read {}: {}", path.display(), e))),
+        };
+        serde_yaml::from_str(&content)
+            .map(Some)
+            .map_err(|e| Error::InvalidManifest(format!("{}: {}", path.display(), e)))
+    }
+
+    /// [`Self::load`] for module `name`, failing if the manifest names
+    /// another module; see [`Self::check_name`].
+    pub fn load_for(name: &str, module_path: &Path) -> Result<Option<Self>> {
+        let manifest = Self::load(module_path)?;
+        if let Some(manifest) = &manifest {
+            manifest.check_name(name)?;
+        }
+        Ok(manifest)
+    }
+
+    /// Runs the module with `--describe`, inside `sandbox`, and reads the
+    /// manifest it prints. This executes the module, so it is only done when
+    /// asked for.
+    pub async fn probe(name: &str, module_path: &Path, sandbox: &Sandbox) -> Result<Self> {
+        let mut command = sandbox_command(module_path, sandbox)?;
+        command.arg(DESCRIBE_ARG);
+        let output = run_with_timeout(name, command, DESCRIBE_TIMEOUT).await?;
+        let unanswered = || Error::InvalidManifest(format!("Module '{}' does not answer {}", name, DESCRIBE_ARG));
+        if !output.status.success() {
+            return Err(unanswered());
+        }
+        let value: Value = serde_json::from_slice(&output.stdout).map_err(|_| unanswered())?;
+        // A module that ignores the argument prints its usual report instead.
+        if value.get("dependencies").is_some() || (value.get("name").is_none() && value.get("version").is_none()) {
+            return Err(unanswered());
+        }
+        serde_json::from_value(value)
+            .map_err(|e| Error::InvalidManifest(format!("Module '{}': {}", name, e)))
+    }
+
+    /// Fails when the manifest names a module other than `name`, the one it
+    /// was found for, e.g. after a module was copied or renamed without it.
+    pub fn check_name(&self, name: &str) -> Result<()> {
+        match &self.name {
+            Some(declared) if declared != name => Err(Error::InvalidManifest(format!(
+                "manifest of module '{}' names module '{}'",
+                name, declared
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the module says it runs on this operating system.
+    pub fn supports_this_os(&self) -> bool {
+        self.os.is_empty() || self.os.iter().any(|os| os == std::env::consts::OS)
+    }
+}
+
+/// The manifest file of the module at `module_path`: the same path with
+/// `.yaml` appended, e.g. `std/connections.yaml`.
+pub fn manifest_path(module_path: &Path) -> PathBuf {
+    let mut path = module_path.as_os_str().to_owned();
+    path.push(".yaml");
+    PathBuf::from(path)
+}
@@ -0,0 +1,255 @@
+use crate::collect::{table, OutputFormat};
+use crate::config::Config;
+use crate::integrity::verify_module;
+use crate::manifest::ModuleManifest;
+use crate::sandbox::Sandbox;
+use crate::spec::ParameterSpec;
+use crate::Error;
+use crate::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How many directories deep module paths are searched, i.e. the most dots
+/// a discovered module name can have plus one.
+const MAX_DEPTH: usize = 4;
+
+/// A module found on the module paths, as `dep_map modules` reports it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredModule {
+    pub name: String,
+    /// The file that runs, from the first module path that has the module.
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<ModuleManifest>,
+    /// Why the manifest could not be read or probed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest_error: Option<String>,
+    /// Files with the same name further down the module paths. They never
+    /// run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shadowed: Vec<PathBuf>,
+    /// Whether the config has an entry for the module.
+    pub configured: bool,
+}
+
+/// Every module on `agent.module_paths`, in name order, with the manifests
+/// shipped next to them. Nothing is executed.
+pub fn discover(config: &Config) -> Vec<DiscoveredModule> {
+    let mut found: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for base in &config.agent.module_paths {
+        let mut here = Vec::new();
+        scan(base, base, 1, &mut here);
+        for (name, path) in here {
+            found.entry(name).or_default().push(path);
+        }
+    }
+
+    found
+        .into_iter()
+        .map(|(name, mut paths)| {
+            let path = paths.remove(0);
+            let (manifest, manifest_error) = match ModuleManifest::load_for(&name, &path) {
+                Ok(manifest) => (manifest, None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            DiscoveredModule {
+                configured: config.modules.contains_key(&name),
+                name,
+                path,
+                manifest,
+                manifest_error,
+                shadowed: paths,
+            }
+        })
+        .collect()
+}
+
+/// One module by name, as [`discover`] reports it.
+pub fn find(config: &Config, name: &str) -> Result<DiscoveredModule> {
+    discover(config)
+        .into_iter()
+        .find(|module| module.name == name)
+        .ok_or_else(|| Error::ModuleNotFound(name.to_string()))
+}
+
+/// Asks modules that ship no manifest for one with `--describe`. This runs
+/// the modules, so they must pass the same integrity checks as for a run,
+/// and run in the same sandbox.
+pub async fn probe(config: &Config, modules: &mut [DiscoveredModule]) {
+    for module in modules.iter_mut().filter(|module| module.manifest.is_none() && module.manifest_error.is_none()) {
+        let configured = config.modules.get(&module.name);
+        let sandbox = verify_module(&config.agent.integrity, &module.name, &module.path, configured)
+            .and_then(|_| Sandbox::for_module(&config.agent, configured));
+        let sandbox = match sandbox {
+            Ok(sandbox) => sandbox,
+            Err(e) => {
+                module.manifest_error = Some(e.to_string());
+                continue;
+            }
+        };
+        let manifest = ModuleManifest::probe(&module.name, &module.path, &sandbox)
+            .await
+            .and_then(|manifest| manifest.check_name(&module.name).map(|_| manifest));
+        match manifest {
+            Ok(manifest) => module.manifest = Some(manifest),
+            Err(e) => module.manifest_error = Some(e.to_string()),
+        }
+    }
+}
+
+/// The file a module at `relative` runs from: the first one on
+/// `module_paths` that [`is_executable`], as [`discover`] lists it.
+pub(crate) fn resolve(module_paths: &[PathBuf], relative: &Path) -> Option<PathBuf> {
+    module_paths
+        .iter()
+        .map(|base| base.join(relative))
+        .find(|path| is_executable(path))
+}
+
+/// Collects the executables under `dir` whose path maps back to a module
+/// name, i.e. whose path components are all letters, digits and `_`.
+fn scan(base: &Path, dir: &Path, depth: usize, found: &mut Vec<(String, PathBuf)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        log::debug!("Cannot read module directory {}", dir.display());
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if file_name.is_empty() || !file_name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            continue;
+        }
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if depth < MAX_DEPTH {
+                scan(base, &path, depth + 1, found);
+            }
+        } else if is_executable(&path) {
+            let relative = path.strip_prefix(base).unwrap_or(&path);
+            let name = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(".");
+            found.push((name, path));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+pub fn render_list(modules: &[DiscoveredModule], format: OutputFormat) -> Result<String> {
+    if format == OutputFormat::Json {
+        return Ok(serde_json::to_string_pretty(modules)? + "\n");
+    }
+    let rows = modules
+        .iter()
+        .map(|module| {
+            let manifest = module.manifest.as_ref();
+            vec![
+                module.name.clone(),
+                manifest.and_then(|manifest| manifest.version.clone()).unwrap_or_else(|| "-".to_string()),
+                manifest.map(|manifest| list(&manifest.os)).unwrap_or_else(|| "-".to_string()),
+                manifest.map(|manifest| list(&manifest.outputs)).unwrap_or_else(|| "-".to_string()),
+                if module.configured { "yes" } else { "no" }.to_string(),
+                module.path.display().to_string(),
+            ]
+        })
+        .collect();
+    let mut out = table(&["NAME", "VERSION", "OS", "OUTPUTS", "CONFIGURED", "PATH"], rows);
+    for module in modules {
+        for shadowed in &module.shadowed {
+            let _ = writeln!(
+                out,
+                "warning: {} is shadowed by {} and never runs",
+                shadowed.display(),
+                module.path.display()
+            );
+        }
+        if let Some(error) = &module.manifest_error {
+            let _ = writeln!(out, "warning: {}: {}", module.name, error);
+        }
+    }
+    Ok(out)
+}
+
+pub fn render_info(module: &DiscoveredModule, format: OutputFormat) -> Result<String> {
+    if format == OutputFormat::Json {
+        return Ok(serde_json::to_string_pretty(module)? + "\n");
+    }
+    let manifest = module.manifest.clone().unwrap_or_default();
+    let mut fields = vec![
+        ("name", module.name.clone()),
+        ("path", module.path.display().to_string()),
+        ("configured", if module.configured { "yes" } else { "no" }.to_string()),
+    ];
+    for (key, value) in [
+        ("version", manifest.version),
+        ("description", manifest.description),
+        ("os", Some(list(&manifest.os)).filter(|_| !manifest.os.is_empty())),
+        ("outputs", Some(list(&manifest.outputs)).filter(|_| !manifest.outputs.is_empty())),
+        ("manifest error", module.manifest_error.clone()),
+    ] {
+        if let Some(value) = value {
+            fields.push((key, value));
+        }
+    }
+    for shadowed in &module.shadowed {
+        fields.push(("shadows", shadowed.display().to_string()));
+    }
+
+    let mut out = String::new();
+    for (key, value) in fields {
+        let _ = writeln!(out, "{:<15}{}", format!("{}:", key), value);
+    }
+    if let Some(spec) = &manifest.spec {
+        for (heading, parameters) in [("arguments", &spec.input_parameters), ("return values", &spec.return_values)] {
+            if let Some(parameters) = parameters {
+                let _ = writeln!(out, "\n{}:", heading);
+                out.push_str(&parameter_table(parameters));
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn parameter_table(parameters: &[ParameterSpec]) -> String {
+    let rows = parameters
+        .iter()
+        .map(|parameter| {
+            vec![
+                parameter.name.clone(),
+                format!("{:?}", parameter.value_type),
+                if parameter.required { "yes" } else { "no" }.to_string(),
+                parameter.description.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    table(&["NAME", "TYPE", "REQUIRED", "DESCRIPTION"], rows)
+}
+
+fn list(values: &[String]) -> String {
+    if values.is_empty() {
+        "any".to_string()
+    } else {
+        values.join(",")
+    }
+}
@@ -0,0 +1,164 @@
+use crate::config::OutboxConfig;
+use crate::Result;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+const ENTRY_EXTENSION: &str = "json";
+/// Where batches the server refused are kept, under the outbox directory.
+pub const REJECTED_DIR: &str = "rejected";
+/// How many refused batches are kept for inspection, newest first.
+const MAX_REJECTED: usize = 100;
+
+/// Durable spool of serialized batches waiting to be uploaded.
+///
+/// Every batch is written to its own file named after a monotonically
+/// increasing sequence number, so a directory listing sorted by name is the
+/// order the batches must be delivered in. Files are written to a temporary
+/// name and renamed into place, which keeps a crash from leaving half a batch
+/// behind.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    dir: PathBuf,
+    config: OutboxConfig,
+    next_seq: u64,
+    retry_delay: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl Outbox {
+    pub fn open(dir: impl Into<PathBuf>, config: OutboxConfig) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let next_seq = list_entries(&dir)?
+            .last()
+            .and_then(|path| entry_seq(path))
+            .map_or(0, |seq| seq + 1);
+
+        Ok(Outbox {
+            dir,
+            retry_delay: Duration::from_secs(config.retry_initial),
+            config,
+            next_seq,
+            next_attempt: None,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Appends a batch and then applies the size and age limits.
+    pub fn push(&mut self, body: &[u8]) -> Result<PathBuf> {
+        let path = self.dir.join(format!("{:020}.{}", self.next_seq, ENTRY_EXTENSION));
+        let tmp_path = path.with_extension("tmp");
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(body)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        self.next_seq += 1;
+
+        let dropped = self.enforce_limits()?;
+        if dropped > 0 {
+            log::warn!("Outbox limits exceeded, dropped {} oldest batch(es)", dropped);
+        }
+        Ok(path)
+    }
+
+    /// Pending batches, oldest first.
+    pub fn entries(&self) -> Result<Vec<PathBuf>> {
+        list_entries(&self.dir)
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.entries()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn remove(&self, entry: &Path) -> Result<()> {
+        fs::remove_file(entry)?;
+        Ok(())
+    }
+
+    /// Whether the backoff from the last failed delivery has elapsed.
+    pub fn ready(&self) -> bool {
+        self.next_attempt.is_none_or(|at| Instant::now() >= at)
+    }
+
+    pub fn record_failure(&mut self) {
+        self.next_attempt = Some(Instant::now() + self.retry_delay);
+        self.retry_delay = (self.retry_delay * 2).min(Duration::from_secs(self.config.retry_max));
+    }
+
+    pub fn record_success(&mut self) {
+        self.next_attempt = None;
+        self.retry_delay = Duration::from_secs(self.config.retry_initial);
+    }
+
+    /// Drops the oldest batches until the outbox is within `max_age` and
+    /// `max_bytes`. Returns how many batches were dropped.
+    pub fn enforce_limits(&self) -> Result<usize> {
+        let max_age = Duration::from_secs(self.config.max_age);
+        let now = SystemTime::now();
+
+        let mut entries = Vec::new();
+        for path in self.entries()? {
+            let metadata = fs::metadata(&path)?;
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            entries.push((path, metadata.len(), age));
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        let mut dropped = 0;
+        for (path, size, age) in entries {
+            if total <= self.config.max_bytes && age <= max_age {
+                break;
+            }
+            self.remove(&path)?;
+            total -= size;
+            dropped += 1;
+        }
+        Ok(dropped)
+    }
+}
+
+/// Moves a batch the server refused into [`REJECTED_DIR`], so the batches
+/// queued behind it can still be delivered. Only the newest
+/// [`MAX_REJECTED`] are kept.
+pub fn reject(entry: &Path) -> Result<PathBuf> {
+    let dir = entry.parent().unwrap_or(Path::new(".")).join(REJECTED_DIR);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(entry.file_name().unwrap_or_default());
+    fs::rename(entry, &path)?;
+
+    let rejected = list_entries(&dir)?;
+    for old in &rejected[..rejected.len().saturating_sub(MAX_REJECTED)] {
+        fs::remove_file(old)?;
+    }
+    Ok(path)
+}
+
+fn entry_seq(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn list_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) && entry_seq(path).is_some()
+        })
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
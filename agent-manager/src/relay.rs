@@ -0,0 +1,430 @@
+use crate::config::Config;
+use crate::identity::AgentIdentity;
+use crate::outbox::{self, Outbox};
+use crate::transport::{read_protected, read_secret, Transport};
+use crate::Error;
+use crate::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time;
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+/// Downstream agents post their enrollment here; everything else posted to
+/// the relay is taken for a batch.
+pub const ENROLL_PATH: &str = "/enroll";
+
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// How often the spool is looked at when nothing new arrives, so that
+/// batches are retried once the backoff has elapsed.
+const FORWARD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A relay a batch passed through. Each relay appends one to the batch's
+/// `Relays`, and leaves everything else, including `Agent`, as it came.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Hop<'a> {
+    agent_id: &'a str,
+    hostname: &'a str,
+    /// The address the batch came from, as the relay saw it.
+    received_from: String,
+    received_at: DateTime<Utc>,
+}
+
+/// Accepts batches from downstream agents, spools them under
+/// `<state_dir>/relay` and forwards them to `server` oldest first, with the
+/// same credentials, TLS settings and backoff as the agent's own uploads.
+///
+/// A batch is only acknowledged once it is on disk, so a downstream agent
+/// keeps it in its own outbox until then.
+pub struct Relay {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    shared: Arc<Shared>,
+    /// One permit per connection being handled.
+    connections: Arc<Semaphore>,
+    dry_run: bool,
+}
+
+struct Shared {
+    identity: AgentIdentity,
+    token: Option<String>,
+    max_body_bytes: usize,
+    read_timeout: Duration,
+    spool: Mutex<Outbox>,
+    arrived: Notify,
+    transport: Transport,
+    enroll_url: Option<String>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Relay {
+    /// Binds `agent.relay.listen` and opens the spool. The TLS key and the
+    /// token are read here, so that problems with them stop the agent at
+    /// startup.
+    pub async fn bind(config: &Config, identity: &AgentIdentity) -> Result<Self> {
+        let relay = config
+            .agent
+            .relay
+            .as_ref()
+            .ok_or_else(|| Error::Relay("agent.relay is not set".to_string()))?;
+        let state_dir = config
+            .agent
+            .state_dir
+            .as_ref()
+            .ok_or_else(|| Error::Relay("agent.relay needs agent.state_dir to spool batches".to_string()))?;
+
+        let tls = match (&relay.tls_cert_file, &relay.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let cert = std::fs::read(cert_file)
+                    .map_err(|e| Error::Relay(format!("cannot read {}: {}", cert_file.display(), e)))?;
+                let identity = native_tls::Identity::from_pkcs8(&cert, &read_protected(key_file)?)
+                    .map_err(|e| Error::Relay(format!("{}: {}", cert_file.display(), e)))?;
+                let acceptor = native_tls::TlsAcceptor::new(identity).map_err(|e| Error::Relay(e.to_string()))?;
+                Some(TlsAcceptor::from(acceptor))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(Error::Relay(
+                    "relay.tls_cert_file and relay.tls_key_file must be set together".to_string(),
+                ))
+            }
+        };
+        let token = relay.token_file.as_deref().map(read_secret).transpose()?;
+        if token.is_none() {
+            log::warn!(
+                "agent.relay has no token_file, anyone who can reach {} can send batches as any agent",
+                relay.listen
+            );
+        }
+        let spool = Outbox::open(state_dir.join("relay"), config.agent.outbox.clone())?;
+        let listener = TcpListener::bind(&relay.listen)
+            .await
+            .map_err(|e| Error::Relay(format!("cannot listen on {}: {}", relay.listen, e)))?;
+
+        Ok(Relay {
+            listener,
+            tls,
+            shared: Arc::new(Shared {
+                identity: identity.clone(),
+                token,
+                max_body_bytes: relay.max_body_bytes,
+                read_timeout: Duration::from_secs(relay.read_timeout),
+                spool: Mutex::new(spool),
+                arrived: Notify::new(),
+                transport: Transport::new(&config.server)?,
+                enroll_url: config.server.enroll_url.clone(),
+            }),
+            connections: Arc::new(Semaphore::new(relay.max_connections.max(1))),
+            dry_run: false,
+        })
+    }
+
+    /// Spools batches without forwarding them, and answers enrollments
+    /// itself.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+
+    /// Whether downstream agents have to speak TLS.
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Accepts downstream agents and forwards what they send until the
+    /// returned future is dropped.
+    pub async fn run(self) {
+        let forwarding = async {
+            if self.dry_run {
+                std::future::pending::<()>().await;
+            }
+            forward(&self.shared).await
+        };
+        tokio::select! {
+            _ = forwarding => {}
+            _ = self.accept() => {}
+        }
+    }
+
+    async fn accept(&self) {
+        loop {
+            // Waiting here leaves further agents in the listen backlog.
+            let permit = self
+                .connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("connection limit is never closed");
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Relay accept failed: {}", e);
+                    continue;
+                }
+            };
+            let shared = self.shared.clone();
+            let tls = self.tls.clone();
+            let dry_run = self.dry_run;
+            tokio::spawn(async move {
+                let _permit = permit;
+                match tls {
+                    Some(tls) => match time::timeout(shared.read_timeout, tls.accept(stream)).await {
+                        Ok(Ok(stream)) => handle(stream, peer, &shared, dry_run).await,
+                        Ok(Err(e)) => log::debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => log::debug!("TLS handshake with {} timed out", peer),
+                    },
+                    None => handle(stream, peer, &shared, dry_run).await,
+                }
+            });
+        }
+    }
+}
+
+impl Shared {
+    fn spool(&self) -> Result<MutexGuard<'_, Outbox>> {
+        self.spool.lock().map_err(|_| Error::Relay("spool lock poisoned".to_string()))
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let expected = format!("Bearer {}", token);
+        request
+            .headers
+            .iter()
+            .find(|(name, _)| name == "authorization")
+            .is_some_and(|(_, value)| constant_time_eq(value.as_bytes(), expected.as_bytes()))
+    }
+
+    async fn route(&self, request: Request, peer: SocketAddr, dry_run: bool) -> (&'static str, String) {
+        if request.method != "POST" {
+            return ("405 Method Not Allowed", String::new());
+        }
+        if !self.authorized(&request) {
+            log::warn!("Rejected a request from {} without a valid token", peer);
+            return ("401 Unauthorized", String::new());
+        }
+        if request.path == ENROLL_PATH {
+            return self.enroll(request.body, dry_run).await;
+        }
+
+        let body = match self.stamp(&request.body, peer) {
+            Ok(body) => body,
+            Err(message) => {
+                log::warn!("Rejected a batch from {}: {}", peer, message);
+                return ("400 Bad Request", format!("{}\n", message));
+            }
+        };
+        match self.spool().and_then(|mut spool| spool.push(&body)) {
+            Ok(_) => {
+                self.arrived.notify_one();
+                ("202 Accepted", String::new())
+            }
+            Err(e) => {
+                log::error!("Cannot spool a batch from {}: {}", peer, e);
+                ("503 Service Unavailable", String::new())
+            }
+        }
+    }
+
+    /// Appends this relay to the batch's `Relays`.
+    fn stamp(&self, body: &[u8], peer: SocketAddr) -> std::result::Result<Vec<u8>, String> {
+        let mut batch: Value = serde_json::from_slice(body).map_err(|e| format!("not JSON: {}", e))?;
+        let batch_fields = batch.as_object_mut().ok_or("not a JSON object")?;
+        let hop = Hop {
+            agent_id: &self.identity.agent_id,
+            hostname: &self.identity.hostname,
+            received_from: peer.ip().to_string(),
+            received_at: Utc::now(),
+        };
+        let hop = serde_json::to_value(hop).map_err(|e| e.to_string())?;
+        match batch_fields.entry("Relays").or_insert_with(|| Value::Array(Vec::new())) {
+            Value::Array(hops) => hops.push(hop),
+            _ => return Err("Relays is not an array".to_string()),
+        }
+        serde_json::to_vec(&batch).map_err(|e| e.to_string())
+    }
+
+    /// Passes an enrollment on to `server.enroll_url` and hands back the
+    /// server's answer. Without an enroll URL there is nothing to ask, and
+    /// the downstream agent keeps its own ID.
+    async fn enroll(&self, body: Vec<u8>, dry_run: bool) -> (&'static str, String) {
+        let Some(url) = self.enroll_url.as_ref().filter(|_| !dry_run) else {
+            return ("200 OK", "{}".to_string());
+        };
+        match self.transport.post(url, body).await {
+            Ok(answer) => ("200 OK", String::from_utf8_lossy(&answer).into_owned()),
+            Err(e) => {
+                log::warn!("Passing on an enrollment failed: {}", e);
+                ("502 Bad Gateway", String::new())
+            }
+        }
+    }
+
+    /// Sends spooled batches oldest first, stopping at the first failure
+    /// other than the server refusing a batch.
+    async fn forward(&self) -> Result<()> {
+        let entries = {
+            let spool = self.spool()?;
+            if !spool.ready() {
+                return Ok(());
+            }
+            spool.entries()?
+        };
+        for entry in entries {
+            // The spool limits may have dropped it in the meantime.
+            let body = match std::fs::read(&entry) {
+                Ok(body) => body,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let sent = self.transport.send(body).await;
+            let mut spool = self.spool()?;
+            match sent {
+                Err(e) if e.is_rejection() => {
+                    let moved = outbox::reject(&entry)?;
+                    log::warn!("Server refused a relayed batch ({}), moved it to {}", e, moved.display());
+                    continue;
+                }
+                Err(e) => {
+                    spool.record_failure();
+                    return Err(e);
+                }
+                Ok(()) => {}
+            }
+            match spool.remove(&entry) {
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        self.spool()?.record_success();
+        Ok(())
+    }
+}
+
+async fn forward(shared: &Shared) {
+    loop {
+        tokio::select! {
+            _ = shared.arrived.notified() => {}
+            _ = time::sleep(FORWARD_INTERVAL) => {}
+        }
+        if let Err(e) = shared.forward().await {
+            log::warn!("Relay forwarding failed, will retry: {}", e);
+        }
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, peer: SocketAddr, shared: &Shared, dry_run: bool) {
+    let read = time::timeout(shared.read_timeout, read_request(&mut stream, shared.max_body_bytes)).await;
+    let (status, body) = match read {
+        Ok(Ok(Some(request))) => shared.route(request, peer, dry_run).await,
+        Ok(Ok(None)) => return,
+        Ok(Err(status)) => (status, String::new()),
+        Err(_) => {
+            log::debug!("Dropping {}, which sent no complete request in time", peer);
+            ("408 Request Timeout", String::new())
+        }
+    };
+    let _ = time::timeout(shared.read_timeout, respond(&mut stream, status, &body)).await;
+}
+
+/// Reads one HTTP/1.1 request with a `Content-Length` body. Returns None
+/// when the peer goes away, and the status to answer with when the request
+/// cannot be taken.
+async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_body_bytes: usize,
+) -> std::result::Result<Option<Request>, &'static str> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 8192];
+    let head_end = loop {
+        if let Some(pos) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos;
+        }
+        if data.len() > MAX_HEAD_BYTES {
+            return Err("431 Request Header Fields Too Large");
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return Ok(None),
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or(path).to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let content_length = headers.iter().find(|(name, _)| name == "content-length");
+    let length = match content_length {
+        Some((_, value)) => value.parse::<usize>().map_err(|_| "400 Bad Request")?,
+        None if method == "POST" => return Err("411 Length Required"),
+        None => 0,
+    };
+    if length > max_body_bytes {
+        return Err("413 Content Too Large");
+    }
+    let mut body = data.split_off(head_end + 4);
+    while body.len() < length {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return Ok(None),
+            Ok(n) => body.extend_from_slice(&buf[..n]),
+        }
+    }
+    body.truncate(length);
+
+    Ok(Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, status: &str, body: &str) -> io::Result<()> {
+    // Only enrollment answers carry JSON, everything else is a message.
+    let content_type = if status.starts_with("200") { "application/json" } else { "text/plain" };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    if status.starts_with("401") {
+        response.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
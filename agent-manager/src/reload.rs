@@ -0,0 +1,121 @@
+use crate::config::{Config, ConfigLoader};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time;
+
+/// How often the config file is checked for changes.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads the agent config on SIGHUP and whenever the config file changes,
+/// and hands each config that loads to the engine.
+///
+/// The file is polled rather than watched, which also picks up editors that
+/// replace the file instead of writing it in place and config management
+/// tools that swap a symlink. A config that fails to load or validate is
+/// reported and the engine keeps its current one.
+#[derive(Debug, Clone)]
+pub struct ConfigWatcher {
+    loader: ConfigLoader,
+    path: PathBuf,
+    poll_interval: Duration,
+}
+
+impl ConfigWatcher {
+    pub fn new(loader: ConfigLoader, path: PathBuf) -> Self {
+        ConfigWatcher {
+            loader,
+            path,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Runs until the engine stops listening on `reload_tx`.
+    pub async fn watch(mut self, reload_tx: mpsc::Sender<Config>) {
+        let mut hangup = hangup_signal();
+        let mut poll = time::interval(self.poll_interval);
+        poll.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        let mut last_seen = file_stamp(&self.path);
+
+        loop {
+            tokio::select! {
+                _ = reload_tx.closed() => return,
+                _ = recv_hangup(&mut hangup) => {
+                    log::info!("SIGHUP received, reloading config");
+                }
+                _ = poll.tick() => {
+                    let stamp = file_stamp(&self.path);
+                    if stamp == last_seen {
+                        continue;
+                    }
+                    log::info!("Config file {} changed, reloading", self.path.display());
+                }
+            }
+            last_seen = file_stamp(&self.path);
+
+            let reloaded = self.loader.load().and_then(|loaded| {
+                for warning in loaded.validate()? {
+                    log::warn!("{}: {}", loaded.path.display(), warning);
+                }
+                Ok(loaded)
+            });
+            match reloaded {
+                Ok(loaded) => {
+                    self.path = loaded.path;
+                    if reload_tx.send(loaded.config).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => log::error!("Keeping current config, failed to reload: {}", e),
+            }
+        }
+    }
+}
+
+/// Modification time and size, enough to notice a rewrite. `None` while the
+/// file is missing, so deleting it and putting it back are both changes.
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            log::warn!("Cannot listen for SIGHUP, reloading on file change only: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {}
+
+#[cfg(unix)]
+async fn recv_hangup(hangup: &mut Hangup) {
+    match hangup {
+        Some(signal) => {
+            signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn recv_hangup(_hangup: &mut Hangup) {
+    std::future::pending().await
+}
@@ -0,0 +1,358 @@
+use crate::config::{AgentConfig, ModuleConfig, Namespace, SandboxConfig};
+use crate::Error;
+use crate::Result;
+use std::path::{Path, PathBuf};
+
+/// Restrictions for one module process, with users resolved and settings
+/// checked, ready to apply to the command that spawns it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    /// `None` passes the agent's environment on unchanged.
+    env: Option<Vec<(String, String)>>,
+    working_dir: Option<PathBuf>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    limits: Vec<(Limit, u64)>,
+    no_new_privs: bool,
+    namespaces: Vec<Namespace>,
+    seccomp: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+    Cpu,
+    Memory,
+    OpenFiles,
+}
+
+impl Sandbox {
+    pub fn new(config: &SandboxConfig) -> Result<Self> {
+        let env = config.env.as_ref().map(|entries| resolve_env(entries)).transpose()?;
+        let limits: Vec<(Limit, u64)> = [
+            (Limit::Cpu, config.max_cpu_seconds),
+            (Limit::Memory, config.max_memory_bytes),
+            (Limit::OpenFiles, config.max_open_files),
+        ]
+        .into_iter()
+        .filter_map(|(limit, value)| value.map(|value| (limit, value)))
+        .collect();
+        if limits.iter().any(|(_, value)| *value == 0) {
+            return Err(Error::Sandbox("resource limits must be greater than 0".to_string()));
+        }
+        let (uid, gid) = resolve_ids(config.user.as_deref(), config.group.as_deref())?;
+
+        let sandbox = Sandbox {
+            env,
+            working_dir: config.working_dir.clone(),
+            uid,
+            gid,
+            limits,
+            seccomp: config.seccomp.unwrap_or(false),
+            no_new_privs: config.no_new_privs.unwrap_or(false) || config.seccomp.unwrap_or(false),
+            namespaces: config.namespaces.clone().unwrap_or_default(),
+        };
+        sandbox.check_platform()?;
+        Ok(sandbox)
+    }
+
+    /// The sandbox for a module: `agent.sandbox` with the module's own
+    /// settings on top.
+    pub fn for_module(agent: &AgentConfig, module: Option<&ModuleConfig>) -> Result<Self> {
+        Self::new(&agent.sandbox.overridden_by(module.and_then(|module| module.sandbox.as_ref())))
+    }
+
+    /// The uid and gid the module runs as, when they are not the agent's.
+    /// Files the module must read have to belong to them.
+    pub fn owner(&self) -> Option<(Option<u32>, Option<u32>)> {
+        (self.uid.is_some() || self.gid.is_some()).then_some((self.uid, self.gid))
+    }
+
+    pub fn working_dir(&self) -> Option<&Path> {
+        self.working_dir.as_deref()
+    }
+
+    /// Sets up `command` to spawn the module inside the sandbox.
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        if let Some(env) = &self.env {
+            command.env_clear();
+            command.envs(env.iter().map(|(name, value)| (name, value)));
+        }
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
+        }
+        #[cfg(unix)]
+        if self.needs_pre_exec() {
+            let restrict = self.pre_exec();
+            // Safety: the closure only makes async-signal-safe syscalls and
+            // does not allocate.
+            unsafe { command.pre_exec(restrict) };
+        }
+    }
+
+    #[cfg(unix)]
+    fn needs_pre_exec(&self) -> bool {
+        self.owner().is_some() || !self.limits.is_empty() || self.no_new_privs || !self.namespaces.is_empty()
+    }
+
+    fn check_platform(&self) -> Result<()> {
+        let unsupported =
+            |what: &str| Err(Error::Sandbox(format!("{} is not supported on {}", what, std::env::consts::OS)));
+        if !cfg!(unix) && !self.limits.is_empty() {
+            return unsupported("resource limits");
+        }
+        if !cfg!(target_os = "linux") {
+            if self.seccomp {
+                return unsupported("seccomp");
+            }
+            if self.no_new_privs {
+                return unsupported("no_new_privs");
+            }
+            if !self.namespaces.is_empty() {
+                return unsupported("namespaces");
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if self.seccomp && seccomp::AUDIT_ARCH.is_none() {
+            return Err(Error::Sandbox(format!("seccomp is not supported on {}", std::env::consts::ARCH)));
+        }
+        Ok(())
+    }
+
+    /// Runs in the forked child, before `exec`: enter namespaces while still
+    /// privileged, set limits, drop the user, then lock the process down.
+    #[cfg(unix)]
+    fn pre_exec(&self) -> impl FnMut() -> std::io::Result<()> + Send + Sync + 'static {
+        use std::io::Error as IoError;
+
+        let (uid, gid) = (self.uid, self.gid);
+        let limits = self.limits.clone();
+        #[cfg(target_os = "linux")]
+        let (no_new_privs, unshare_flags, mut filter) = (
+            self.no_new_privs,
+            self.namespaces.iter().fold(0, |flags, namespace| flags | clone_flag(*namespace)),
+            self.seccomp.then(seccomp::filter),
+        );
+
+        move || {
+            #[cfg(target_os = "linux")]
+            if unshare_flags != 0 && unsafe { libc::unshare(unshare_flags) } != 0 {
+                return Err(IoError::last_os_error());
+            }
+            for (limit, value) in &limits {
+                let resource = match limit {
+                    Limit::Cpu => libc::RLIMIT_CPU,
+                    Limit::Memory => libc::RLIMIT_AS,
+                    Limit::OpenFiles => libc::RLIMIT_NOFILE,
+                };
+                let rlimit = libc::rlimit {
+                    rlim_cur: *value as libc::rlim_t,
+                    rlim_max: *value as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+            if let Some(gid) = gid {
+                // Drop the agent's supplementary groups along with its group.
+                let groups = [gid as libc::gid_t];
+                if unsafe { libc::setgroups(1, groups.as_ptr()) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+            if let Some(uid) = uid {
+                if unsafe { libc::setuid(uid) } != 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            {
+                if no_new_privs && unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+                    return Err(IoError::last_os_error());
+                }
+                if let Some(filter) = &mut filter {
+                    let program = libc::sock_fprog {
+                        len: filter.len() as libc::c_ushort,
+                        filter: filter.as_mut_ptr(),
+                    };
+                    if unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program) } != 0 {
+                        return Err(IoError::last_os_error());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// `NAME` takes the agent's value, if it has one; `NAME=value` sets it.
+fn resolve_env(entries: &[String]) -> Result<Vec<(String, String)>> {
+    let mut env = Vec::new();
+    for entry in entries {
+        let (name, value) = match entry.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (entry.as_str(), None),
+        };
+        if name.is_empty() {
+            return Err(Error::Sandbox(format!("'{}' in env does not name a variable", entry)));
+        }
+        if let Some(value) = value.or_else(|| std::env::var(name).ok()) {
+            env.push((name.to_string(), value));
+        }
+    }
+    Ok(env)
+}
+
+#[cfg(unix)]
+fn resolve_ids(user: Option<&str>, group: Option<&str>) -> Result<(Option<u32>, Option<u32>)> {
+    let user = user
+        .map(|user| lookup_user(user).ok_or_else(|| Error::Sandbox(format!("unknown user '{}'", user))))
+        .transpose()?;
+    let gid = match group {
+        Some(group) => Some(
+            group
+                .parse()
+                .ok()
+                .or_else(|| lookup_group(group))
+                .ok_or_else(|| Error::Sandbox(format!("unknown group '{}'", group)))?,
+        ),
+        None => user.map(|(_, gid)| gid),
+    };
+    Ok((user.map(|(uid, _)| uid), gid))
+}
+
+#[cfg(not(unix))]
+fn resolve_ids(user: Option<&str>, group: Option<&str>) -> Result<(Option<u32>, Option<u32>)> {
+    if user.is_some() || group.is_some() {
+        return Err(Error::Sandbox(format!("user and group are not supported on {}", std::env::consts::OS)));
+    }
+    Ok((None, None))
+}
+
+/// The uid and primary gid of a user given by name or uid.
+#[cfg(unix)]
+pub(crate) fn lookup_user(user: &str) -> Option<(u32, u32)> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let status = match user.parse::<u32>() {
+        Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) },
+        Err(_) => {
+            let name = std::ffi::CString::new(user).ok()?;
+            unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) }
+        }
+    };
+    if status == 0 && !result.is_null() {
+        return Some((passwd.pw_uid, passwd.pw_gid));
+    }
+    // A uid without a passwd entry runs with the group of the same number.
+    user.parse().ok().map(|uid| (uid, uid))
+}
+
+#[cfg(unix)]
+pub(crate) fn lookup_group(group: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(group).ok()?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let status = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+    (status == 0 && !result.is_null()).then_some(entry.gr_gid)
+}
+
+#[cfg(target_os = "linux")]
+fn clone_flag(namespace: Namespace) -> libc::c_int {
+    match namespace {
+        Namespace::Network => libc::CLONE_NEWNET,
+        Namespace::Ipc => libc::CLONE_NEWIPC,
+        Namespace::Uts => libc::CLONE_NEWUTS,
+    }
+}
+
+/// A seccomp filter that fails the listed syscalls with `EPERM` and allows
+/// the rest. Syscalls of a foreign architecture kill the process, as their
+/// numbers mean something else.
+#[cfg(target_os = "linux")]
+mod seccomp {
+    use libc::{sock_filter, BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_KILL_PROCESS};
+
+    #[cfg(target_arch = "x86_64")]
+    pub const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+    #[cfg(target_arch = "aarch64")]
+    pub const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub const AUDIT_ARCH: Option<u32> = None;
+
+    /// Offsets into `struct seccomp_data`.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_clock_adjtime,
+        libc::SYS_adjtimex,
+        libc::SYS_sethostname,
+        libc::SYS_setdomainname,
+        libc::SYS_quotactl,
+    ];
+
+    fn statement(code: u32, k: u32) -> sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    pub fn filter() -> Vec<sock_filter> {
+        let arch = AUDIT_ARCH.expect("checked when the sandbox was built");
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut program = vec![
+            statement(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0),
+            statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        ];
+        // The x32 ABI shares x86_64's audit arch, with this bit set on every
+        // syscall number.
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(BPF_JMP | BPF_JGE | BPF_K, 0x4000_0000, 0, 1),
+            statement(BPF_RET | BPF_K, deny),
+        ]);
+        for nr in DENIED {
+            program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 1));
+            program.push(statement(BPF_RET | BPF_K, deny));
+        }
+        program.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+        program
+    }
+}
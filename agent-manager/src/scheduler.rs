@@ -0,0 +1,134 @@
+use crate::config::{AgentConfig, ModuleConfig};
+use crate::Error;
+use crate::Result;
+use chrono::{DateTime, Local};
+use rand::Rng;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Shortest gap between two runs of the same module.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What decides when a module runs.
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    /// Every `interval`, on a fixed grid.
+    Interval(Duration),
+    /// At the times a cron expression matches, in local time.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Trigger {
+    /// A module's `schedule` takes precedence over its `interval`; without
+    /// either it runs every `agent.default_interval` seconds.
+    pub fn for_module(module: &ModuleConfig, agent: &AgentConfig) -> Result<Self> {
+        if let Some(expression) = &module.schedule {
+            let schedule = cron::Schedule::from_str(expression)
+                .map_err(|e| Error::InvalidSchedule(format!("'{}': {}", expression, e)))?;
+            return Ok(Trigger::Cron(Box::new(schedule)));
+        }
+        let interval = module.interval.unwrap_or(agent.default_interval);
+        Ok(Trigger::Interval(Duration::from_secs(interval).max(MIN_INTERVAL)))
+    }
+
+    /// The first time after `now` this trigger fires.
+    fn next_after(&self, now: Instant) -> Instant {
+        match self {
+            Trigger::Interval(interval) => now + *interval,
+            Trigger::Cron(schedule) => {
+                let wall_now: DateTime<Local> = Local::now();
+                schedule
+                    .after(&wall_now)
+                    .next()
+                    .and_then(|at| (at - wall_now).to_std().ok())
+                    // An expression that never fires again, e.g. a past year.
+                    .map_or(now + Duration::from_secs(365 * 24 * 60 * 60), |wait| now + wait)
+            }
+        }
+    }
+}
+
+/// When a module runs next and whether an instance of it is in flight.
+///
+/// Each module gets a random `splay` offset, picked once, that shifts all of
+/// its runs. Agents that start at the same moment therefore spread their
+/// runs out, while each agent keeps an even cadence. Interval runs sit on a
+/// fixed grid, so a slow or skipped run does not push later runs back.
+#[derive(Debug, Clone)]
+pub struct ModuleSchedule {
+    pub next_run: Instant,
+    pub last_run: Option<Instant>,
+    pub running: bool,
+    /// A run came due while the previous one was still going and the module's
+    /// overlap policy is `queue`.
+    pub queued: bool,
+    trigger: Trigger,
+    offset: Duration,
+}
+
+impl ModuleSchedule {
+    pub fn for_module(module: &ModuleConfig, agent: &AgentConfig, now: Instant) -> Result<Self> {
+        let trigger = Trigger::for_module(module, agent)?;
+        let splay = module.splay.unwrap_or(agent.splay);
+        let offset = if splay == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(rand::thread_rng().gen_range(0..splay * 1000))
+        };
+        // Interval modules run at startup unless told otherwise; cron modules
+        // wait for their first match.
+        let run_at_startup = module
+            .run_at_startup
+            .unwrap_or(matches!(trigger, Trigger::Interval(_)));
+        Ok(ModuleSchedule::new(trigger, offset, run_at_startup, now))
+    }
+
+    pub fn new(trigger: Trigger, offset: Duration, run_at_startup: bool, now: Instant) -> Self {
+        let next_run = if run_at_startup {
+            now + offset
+        } else {
+            trigger.next_after(now) + offset
+        };
+        ModuleSchedule {
+            next_run,
+            last_run: None,
+            running: false,
+            queued: false,
+            trigger,
+            offset,
+        }
+    }
+
+    /// Builds the schedule of a module whose timing settings changed on
+    /// reload. It waits for the new trigger rather than running right away,
+    /// and keeps the in-flight state of `previous`.
+    pub fn rescheduled(
+        module: &ModuleConfig,
+        agent: &AgentConfig,
+        previous: &ModuleSchedule,
+        now: Instant,
+    ) -> Result<Self> {
+        let fresh = ModuleSchedule::for_module(module, agent, now)?;
+        let mut schedule = ModuleSchedule::new(fresh.trigger, fresh.offset, false, now);
+        schedule.last_run = previous.last_run;
+        schedule.running = previous.running;
+        schedule.queued = previous.queued;
+        Ok(schedule)
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_run
+    }
+
+    /// Moves `next_run` past `now`.
+    pub fn advance(&mut self, now: Instant) {
+        match &self.trigger {
+            Trigger::Interval(interval) => {
+                while self.next_run <= now {
+                    self.next_run += *interval;
+                }
+            }
+            Trigger::Cron(_) => self.next_run = self.trigger.next_after(now) + self.offset,
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use crate::config::{IntegrityConfig, ModuleConfig};
+use crate::integrity::check_permissions;
+use crate::manifest::{manifest_path, ModuleManifest};
+use crate::Error;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// What a module takes and what it prints, in the format of `plugins.yaml`.
+/// Part of the module's manifest:
+///
+/// ```yaml
+/// spec:
+///   input_parameters:
+///     - {name: directory, type: String, required: true}
+///   return_values:
+///     - {name: count, type: Integer, required: true}
+/// ```
+///
+/// A section that is left out is not checked. An empty `input_parameters`
+/// means the module takes no args at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModuleSpec {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_parameters: Option<Vec<ParameterSpec>>,
+    /// Top-level fields of the module's JSON output, besides `dependencies`,
+    /// `services`, `changed` and `failed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_values: Option<Vec<ParameterSpec>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ParameterSpec {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub value_type: ValueType,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ValueType {
+    String,
+    Integer,
+    /// Any number, integers included.
+    Float,
+    Boolean,
+    List,
+    Object,
+    Any,
+}
+
+impl ValueType {
+    pub fn matches(self, value: &Value) -> bool {
+        match self {
+            ValueType::String => value.is_string(),
+            ValueType::Integer => value.is_i64() || value.is_u64(),
+            ValueType::Float => value.is_number(),
+            ValueType::Boolean => value.is_boolean(),
+            ValueType::List => value.is_array(),
+            ValueType::Object => value.is_object(),
+            ValueType::Any => true,
+        }
+    }
+}
+
+/// A value that does not fit the spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecViolation {
+    /// The arg or output field.
+    pub name: String,
+    pub message: String,
+}
+
+impl fmt::Display for SpecViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+/// The spec that applies to a module: the one in its config, or else the one
+/// in the manifest shipped next to it. None when there is neither. The
+/// manifest decides how args and output are checked, so it has to pass the
+/// same ownership and permission checks as the module.
+pub fn load_spec(
+    name: &str,
+    module_path: Option<&Path>,
+    module: &ModuleConfig,
+    integrity: &IntegrityConfig,
+) -> Result<Option<ModuleSpec>> {
+    if let Some(spec) = &module.spec {
+        return Ok(Some(spec.clone()));
+    }
+    let Some(path) = module_path else {
+        return Ok(None);
+    };
+    let manifest_file = manifest_path(path);
+    if manifest_file.exists() {
+        check_permissions(integrity, &manifest_file)?;
+    }
+    Ok(ModuleManifest::load_for(name, path)?.and_then(|manifest| manifest.spec))
+}
+
+/// Specs by module name, loaded on a module's first run and kept until
+/// [`SpecCache::clear`], which the engine calls when the config is reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct SpecCache {
+    specs: Arc<Mutex<HashMap<String, Option<ModuleSpec>>>>,
+}
+
+impl SpecCache {
+    /// The spec of module `name` at `path`, see [`load_spec`]. It is read off
+    /// the async runtime. Failures are not cached, so the next run retries.
+    pub async fn get(
+        &self,
+        name: &str,
+        path: &Path,
+        module: &ModuleConfig,
+        integrity: &IntegrityConfig,
+    ) -> Result<Option<ModuleSpec>> {
+        if let Some(spec) = self.specs.lock().ok().and_then(|specs| specs.get(name).cloned()) {
+            return Ok(spec);
+        }
+        let path = path.to_path_buf();
+        let module = module.clone();
+        let integrity = integrity.clone();
+        let owned_name = name.to_string();
+        let spec = tokio::task::spawn_blocking(move || load_spec(&owned_name, Some(&path), &module, &integrity))
+            .await
+            .map_err(|e| Error::TaskJoinError(e.to_string()))??;
+        if let Ok(mut specs) = self.specs.lock() {
+            specs.insert(name.to_string(), spec.clone());
+        }
+        Ok(spec)
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut specs) = self.specs.lock() {
+            specs.clear();
+        }
+    }
+}
+
+impl ModuleSpec {
+    /// Checks configured args: every name must be declared, required ones
+    /// must be there, and each value must have the declared type.
+    pub fn check_args(&self, args: Option<&HashMap<String, Value>>) -> Vec<SpecViolation> {
+        let Some(parameters) = &self.input_parameters else {
+            return Vec::new();
+        };
+        let empty = HashMap::new();
+        let args = args.unwrap_or(&empty);
+
+        let mut violations = check_values(parameters, |name| args.get(name));
+        let mut unknown: Vec<&String> = args
+            .keys()
+            .filter(|name| !parameters.iter().any(|parameter| parameter.name == **name))
+            .collect();
+        unknown.sort();
+        for name in unknown {
+            let message = match closest(name, parameters.iter().map(|parameter| parameter.name.as_str())) {
+                Some(suggestion) => format!("unknown argument, did you mean '{}'?", suggestion),
+                None => "unknown argument".to_string(),
+            };
+            violations.push(SpecViolation {
+                name: name.clone(),
+                message,
+            });
+        }
+        violations
+    }
+
+    /// Checks a module's JSON output against `return_values`. Fields the spec
+    /// does not declare are allowed.
+    pub fn check_output(&self, output: &Value) -> Vec<SpecViolation> {
+        match &self.return_values {
+            Some(return_values) => check_values(return_values, |name| output.get(name)),
+            None => Vec::new(),
+        }
+    }
+}
+
+fn check_values<'a>(parameters: &[ParameterSpec], get: impl Fn(&str) -> Option<&'a Value>) -> Vec<SpecViolation> {
+    let mut violations = Vec::new();
+    for parameter in parameters {
+        let message = match get(&parameter.name) {
+            None | Some(Value::Null) if parameter.required => "is required but missing".to_string(),
+            None | Some(Value::Null) => continue,
+            Some(value) if !parameter.value_type.matches(value) => {
+                format!("expected {:?}, got {}", parameter.value_type, json_type(value))
+            }
+            Some(_) => continue,
+        };
+        violations.push(SpecViolation {
+            name: parameter.name.clone(),
+            message,
+        });
+    }
+    violations
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(n) if n.is_f64() => "a float",
+        Value::Number(_) => "an integer",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// The candidate nearest to `name`, if it is close enough to be a typo.
+fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= 2 && *distance < candidate.len())
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,366 @@
+use crate::config::Config;
+use crate::health::{HealthStatus, ModuleHealth};
+use crate::Error;
+use crate::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bounds in seconds of the module run duration histogram buckets.
+pub const DURATION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Largest request head the status listener reads.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// How long a client gets to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Module args may hold credentials, so `/config` leaves them out.
+const REDACTED: &str = "<redacted>";
+
+/// Cumulative histogram in the Prometheus sense: each bucket counts the
+/// observations at or below its bound.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// What is known about one module's runs.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModuleStats {
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_duration_seconds: Option<f64>,
+    pub last_status: Option<HealthStatus>,
+    pub exit_code: Option<i32>,
+    /// Dependencies in the last successful run, after aggregation.
+    pub dependency_count: Option<usize>,
+    pub last_error: Option<String>,
+    /// Number of runs by outcome.
+    pub runs: BTreeMap<&'static str, u64>,
+    #[serde(skip)]
+    pub duration: Histogram,
+}
+
+/// Everything the status listener exposes. The engine updates it as it
+/// goes; the listener only reads it.
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentStats {
+    pub started_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub config: Config,
+    pub modules: BTreeMap<String, ModuleStats>,
+    /// Batches waiting in the outbox, when there is one.
+    pub outbox_depth: Option<usize>,
+    /// Uploads to the server by result, `ok` or `error`.
+    pub uploads: BTreeMap<&'static str, u64>,
+}
+
+pub type SharedStats = Arc<Mutex<AgentStats>>;
+
+impl AgentStats {
+    pub fn new(config: Config) -> Self {
+        AgentStats {
+            started_at: Utc::now(),
+            config,
+            modules: BTreeMap::new(),
+            outbox_depth: None,
+            uploads: BTreeMap::new(),
+        }
+    }
+
+    pub fn record_run(
+        &mut self,
+        module: &str,
+        started_at: DateTime<Utc>,
+        duration: Duration,
+        health: &ModuleHealth,
+        dependency_count: Option<usize>,
+    ) {
+        let stats = self.modules.entry(module.to_string()).or_default();
+        stats.last_run = Some(started_at);
+        stats.last_duration_seconds = Some(duration.as_secs_f64());
+        stats.last_status = Some(health.status);
+        stats.exit_code = health.exit_code;
+        if dependency_count.is_some() {
+            stats.dependency_count = dependency_count;
+        }
+        if !health.is_ok() {
+            stats.last_error = health.message.clone();
+        }
+        *stats.runs.entry(health.status.as_str()).or_default() += 1;
+        stats.duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn record_upload(&mut self, ok: bool) {
+        *self.uploads.entry(if ok { "ok" } else { "error" }).or_default() += 1;
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP dep_map_module_runs_total Module runs by outcome.\n");
+        out.push_str("# TYPE dep_map_module_runs_total counter\n");
+        for (module, stats) in &self.modules {
+            for (status, count) in &stats.runs {
+                let _ = writeln!(
+                    out,
+                    "dep_map_module_runs_total{{module=\"{}\",status=\"{}\"}} {}",
+                    escape_label(module),
+                    status,
+                    count
+                );
+            }
+        }
+
+        out.push_str("# HELP dep_map_module_duration_seconds How long module runs took.\n");
+        out.push_str("# TYPE dep_map_module_duration_seconds histogram\n");
+        for (module, stats) in &self.modules {
+            let module = escape_label(module);
+            let histogram = &stats.duration;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "dep_map_module_duration_seconds_bucket{{module=\"{}\",le=\"{}\"}} {}",
+                    module, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "dep_map_module_duration_seconds_bucket{{module=\"{}\",le=\"+Inf\"}} {}",
+                module, histogram.count
+            );
+            let _ = writeln!(out, "dep_map_module_duration_seconds_sum{{module=\"{}\"}} {}", module, histogram.sum);
+            let _ = writeln!(out, "dep_map_module_duration_seconds_count{{module=\"{}\"}} {}", module, histogram.count);
+        }
+
+        out.push_str("# HELP dep_map_module_dependencies Dependencies found by the last successful run.\n");
+        out.push_str("# TYPE dep_map_module_dependencies gauge\n");
+        for (module, stats) in &self.modules {
+            if let Some(count) = stats.dependency_count {
+                let _ = writeln!(out, "dep_map_module_dependencies{{module=\"{}\"}} {}", escape_label(module), count);
+            }
+        }
+
+        out.push_str("# HELP dep_map_module_last_run_timestamp_seconds Start of the last run.\n");
+        out.push_str("# TYPE dep_map_module_last_run_timestamp_seconds gauge\n");
+        for (module, stats) in &self.modules {
+            if let Some(last_run) = stats.last_run {
+                let _ = writeln!(
+                    out,
+                    "dep_map_module_last_run_timestamp_seconds{{module=\"{}\"}} {}",
+                    escape_label(module),
+                    last_run.timestamp()
+                );
+            }
+        }
+
+        out.push_str("# HELP dep_map_uploads_total Batch uploads to the server by result.\n");
+        out.push_str("# TYPE dep_map_uploads_total counter\n");
+        for (result, count) in &self.uploads {
+            let _ = writeln!(out, "dep_map_uploads_total{{result=\"{}\"}} {}", result, count);
+        }
+
+        if let Some(depth) = self.outbox_depth {
+            out.push_str("# HELP dep_map_outbox_batches Batches waiting in the outbox.\n");
+            out.push_str("# TYPE dep_map_outbox_batches gauge\n");
+            let _ = writeln!(out, "dep_map_outbox_batches {}", depth);
+        }
+
+        out.push_str("# HELP dep_map_start_time_seconds When the agent started.\n");
+        out.push_str("# TYPE dep_map_start_time_seconds gauge\n");
+        let _ = writeln!(out, "dep_map_start_time_seconds {}", self.started_at.timestamp());
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Where the status listener accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// A unix socket path, written as `unix:/path/to/socket`.
+    Unix(std::path::PathBuf),
+}
+
+impl ListenAddr {
+    /// Parses `127.0.0.1:9464`, `[::1]:9464` or `unix:/run/dep_map.sock`.
+    /// TCP addresses must be loopback: the listener has no authentication.
+    pub fn parse(listen: &str) -> std::result::Result<Self, String> {
+        if let Some(path) = listen.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket path is empty".to_string());
+            }
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        let addr: SocketAddr = listen
+            .parse()
+            .map_err(|_| format!("'{}' is neither host:port nor unix:<path>", listen))?;
+        if !addr.ip().is_loopback() {
+            return Err(format!("'{}' is not a loopback address", listen));
+        }
+        Ok(ListenAddr::Tcp(addr))
+    }
+}
+
+/// A bound status listener. Serves:
+///
+/// - `/status`: per-module run details, outbox depth and upload counts as JSON
+/// - `/config`: the effective config as JSON, without module args
+/// - `/metrics`: Prometheus metrics
+///
+/// A unix socket is only accessible to the agent's user.
+pub enum StatusServer {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl StatusServer {
+    pub async fn bind(listen: &ListenAddr) -> Result<Self> {
+        match listen {
+            ListenAddr::Tcp(addr) => Ok(StatusServer::Tcp(tokio::net::TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // A socket file left behind by a previous run blocks the bind.
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                // The umask gives the socket mode 0600 as it is created, so
+                // there is no window in which other users could connect.
+                let old_umask = unsafe { libc::umask(0o177) };
+                let listener = tokio::net::UnixListener::bind(path);
+                unsafe { libc::umask(old_umask) };
+                Ok(StatusServer::Unix(listener?))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(Error::Status("unix sockets are not supported on this platform".to_string())),
+        }
+    }
+
+    /// The bound TCP address, useful when binding port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            StatusServer::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            StatusServer::Unix(_) => None,
+        }
+    }
+
+    pub async fn run(self, stats: SharedStats) {
+        loop {
+            let stats = stats.clone();
+            match &self {
+                StatusServer::Tcp(listener) => match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle(stream, stats));
+                    }
+                    Err(e) => log::warn!("Status listener accept failed: {}", e),
+                },
+                #[cfg(unix)]
+                StatusServer::Unix(listener) => match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle(stream, stats));
+                    }
+                    Err(e) => log::warn!("Status listener accept failed: {}", e),
+                },
+            }
+        }
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, stats: SharedStats) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => return,
+            Ok(Ok(n)) => request.extend_from_slice(&buf[..n]),
+            Err(_) => {
+                let _ = tokio::time::timeout(READ_TIMEOUT, respond(&mut stream, "408 Request Timeout", "text/plain", "")).await;
+                return;
+            }
+        }
+        if request.len() > MAX_REQUEST_BYTES {
+            let _ = respond(&mut stream, "431 Request Header Fields Too Large", "text/plain", "").await;
+            return;
+        }
+    }
+
+    let head = String::from_utf8_lossy(&request);
+    let mut parts = head.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or(path);
+    let (status, content_type, body) = if method != "GET" {
+        ("405 Method Not Allowed", "text/plain", String::new())
+    } else {
+        match route(path, &stats) {
+            Ok(Some((content_type, body))) => ("200 OK", content_type, body),
+            Ok(None) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+            Err(e) => ("500 Internal Server Error", "text/plain", format!("{}\n", e)),
+        }
+    };
+    let _ = tokio::time::timeout(READ_TIMEOUT, respond(&mut stream, status, content_type, &body)).await;
+}
+
+fn route(path: &str, stats: &SharedStats) -> Result<Option<(&'static str, String)>> {
+    let stats = stats.lock().map_err(|_| Error::Status("stats lock poisoned".to_string()))?;
+    Ok(match path {
+        "/" | "/status" => Some(("application/json", serde_json::to_string_pretty(&*stats)?)),
+        "/config" => Some(("application/json", serde_json::to_string_pretty(&redacted_config(&stats.config)?)?)),
+        "/metrics" => Some(("text/plain; version=0.0.4", stats.render_metrics())),
+        _ => None,
+    })
+}
+
+/// The config as JSON, with every module's `args` replaced.
+fn redacted_config(config: &Config) -> Result<serde_json::Value> {
+    let mut value = serde_json::to_value(config)?;
+    if let Some(modules) = value.get_mut("modules").and_then(serde_json::Value::as_object_mut) {
+        for module in modules.values_mut() {
+            if let Some(args) = module.get_mut("args").filter(|args| !args.is_null()) {
+                *args = serde_json::Value::String(REDACTED.to_string());
+            }
+        }
+    }
+    Ok(value)
+}
+
+async fn respond<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
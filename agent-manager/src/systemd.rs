@@ -0,0 +1,176 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const ENV_NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+pub const ENV_WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+pub const ENV_WATCHDOG_PID: &str = "WATCHDOG_PID";
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends `sd_notify` messages to the service manager.
+///
+/// Notifications are enabled only when systemd passed a `NOTIFY_SOCKET`, so
+/// a notifier can always be created and used; outside systemd every call is
+/// a no-op. Failed sends are logged and otherwise ignored, since the agent
+/// keeps collecting whether or not systemd is listening.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    socket: Option<String>,
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Notifier {
+    /// A notifier that never sends anything.
+    pub fn disabled() -> Self {
+        Notifier::default()
+    }
+
+    pub fn new(socket: Option<String>, watchdog: Option<Duration>) -> Self {
+        Notifier {
+            socket: socket.filter(|socket| !socket.is_empty()),
+            watchdog,
+            last_ping: None,
+        }
+    }
+
+    /// Reads `NOTIFY_SOCKET` and, if the watchdog is meant for this process,
+    /// `WATCHDOG_USEC`.
+    pub fn from_env() -> Self {
+        let watchdog_pid = std::env::var(ENV_WATCHDOG_PID).ok();
+        let watchdog = std::env::var(ENV_WATCHDOG_USEC)
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| {
+                watchdog_pid.is_none_or(|pid| pid.parse() == Ok(std::process::id()))
+            })
+            .map(Duration::from_micros);
+        Notifier::new(std::env::var(ENV_NOTIFY_SOCKET).ok(), watchdog)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    pub fn watchdog(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn reloading(&self) {
+        self.notify("RELOADING=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Free-form status line, shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")));
+    }
+
+    /// Pings the watchdog once half its interval has passed since the last
+    /// ping. Called from the engine loop, so a wedged loop stops pinging and
+    /// systemd restarts the agent.
+    pub fn keepalive(&mut self, now: Instant) {
+        let Some(watchdog) = self.watchdog else {
+            return;
+        };
+        if self.last_ping.is_some_and(|last| now.duration_since(last) < watchdog / 2) {
+            return;
+        }
+        self.last_ping = Some(now);
+        self.notify("WATCHDOG=1");
+    }
+
+    pub fn notify(&self, state: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        if let Err(e) = send_datagram(socket, state.as_bytes()) {
+            log::debug!("Cannot notify systemd at {}: {}", socket, e);
+        }
+    }
+}
+
+/// Sends one datagram to a unix socket path, or to an abstract socket when
+/// the name starts with `@`.
+#[cfg(unix)]
+pub(crate) fn send_datagram(socket: &str, message: &[u8]) -> std::io::Result<usize> {
+    use std::os::unix::net::UnixDatagram;
+
+    let sender = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sender.send_to_addr(message, &address)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "abstract sockets are only supported on Linux",
+        )),
+        None => sender.send_to(message, socket),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn send_datagram(_socket: &str, _message: &[u8]) -> std::io::Result<usize> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+/// Encodes journald native protocol fields. Values containing a newline use
+/// the length-prefixed binary form.
+pub fn journal_entry(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    for (name, value) in fields {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// A `Type=notify` unit that runs the agent in the foreground with `config`.
+///
+/// `WatchdogSec` should be well above `server.timeout`, because the engine
+/// loop waits for uploads.
+pub fn unit_file(executable: &Path, config: &Path, watchdog: Duration) -> String {
+    format!(
+        "[Unit]
+Description=dep_map dependency mapping agent
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={} --config {}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+RestartSec=5
+WatchdogSec={}
+TimeoutStopSec=30
+
+[Install]
+WantedBy=multi-user.target
+",
+        executable.display(),
+        config.display(),
+        watchdog.as_secs()
+    )
+}
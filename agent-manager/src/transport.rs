@@ -0,0 +1,181 @@
+use crate::config::{ProxyConfig, ServerConfig};
+use crate::Error;
+use crate::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// The HTTP client that uploads batches to the server, built once from
+/// `server` so that connections, and TLS sessions, are reused.
+///
+/// Everything secret comes from files: the bearer token or API key, the
+/// client key for mutual TLS and the proxy password. Those files are refused
+/// when group or others can read them.
+#[derive(Debug, Clone)]
+pub struct Transport {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Transport {
+    pub fn new(server: &ServerConfig) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(server.timeout))
+            .default_headers(auth_headers(server)?);
+
+        let tls = &server.tls;
+        if let Some(ca_file) = &tls.ca_file {
+            let certificates = reqwest::Certificate::from_pem_bundle(&read_file(ca_file)?)
+                .map_err(|e| Error::Transport(format!("{}: {}", ca_file.display(), e)))?;
+            if certificates.is_empty() {
+                return Err(Error::Transport(format!("{}: no certificates found", ca_file.display())));
+            }
+            builder = builder.tls_built_in_root_certs(false);
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        match (&tls.client_cert_file, &tls.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(&read_file(cert_file)?, &read_protected(key_file)?)
+                    .map_err(|e| Error::Transport(format!("{}: {}", cert_file.display(), e)))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(Error::Transport(
+                    "tls.client_cert_file and tls.client_key_file must be set together".to_string(),
+                ))
+            }
+        }
+        if let Some(proxy) = &server.proxy {
+            builder = builder.proxy(proxy_for(proxy)?);
+        }
+
+        Ok(Transport {
+            client: builder.build()?,
+            url: server.url.clone(),
+        })
+    }
+
+    /// Uploads a batch to `server.url`.
+    pub async fn send(&self, body: Vec<u8>) -> Result<()> {
+        self.post(&self.url, body).await.map(|_| ())
+    }
+
+    /// Posts JSON to `url` with the same credentials and TLS settings as
+    /// uploads, and returns the response body.
+    pub async fn post(&self, url: &str, body: Vec<u8>) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Error::ServerStatus(response.status()));
+        }
+
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+fn auth_headers(server: &ServerConfig) -> Result<HeaderMap> {
+    let auth = &server.auth;
+    let mut headers = HeaderMap::new();
+    let (name, value) = match (&auth.token_file, &auth.api_key_file) {
+        (Some(_), Some(_)) => {
+            return Err(Error::Transport(
+                "set either auth.token_file or auth.api_key_file, not both".to_string(),
+            ))
+        }
+        (Some(token_file), None) => (AUTHORIZATION, format!("Bearer {}", read_secret(token_file)?)),
+        (None, Some(api_key_file)) => {
+            let name = HeaderName::from_bytes(auth.api_key_header.as_bytes()).map_err(|_| {
+                Error::Transport(format!("'{}' is not a valid header name", auth.api_key_header))
+            })?;
+            (name, read_secret(api_key_file)?)
+        }
+        (None, None) => return Ok(headers),
+    };
+    let mut value = HeaderValue::from_str(&value)
+        .map_err(|_| Error::Transport("credential contains characters not allowed in a header".to_string()))?;
+    value.set_sensitive(true);
+    headers.insert(name, value);
+    Ok(headers)
+}
+
+fn proxy_for(config: &ProxyConfig) -> Result<reqwest::Proxy> {
+    let mut proxy = reqwest::Proxy::all(&config.url)
+        .map_err(|e| Error::Transport(format!("proxy url '{}': {}", config.url, e)))?;
+    if let Some(no_proxy) = &config.no_proxy {
+        proxy = proxy.no_proxy(reqwest::NoProxy::from_string(no_proxy));
+    }
+    if let Some(username) = &config.username {
+        let password = match &config.password_file {
+            Some(password_file) => read_secret(password_file)?,
+            None => String::new(),
+        };
+        proxy = proxy.basic_auth(username, &password);
+    }
+    Ok(proxy)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| Error::Transport(format!("cannot read {}: {}", path.display(), e)))
+}
+
+/// Reads a file holding a secret, after checking that only its owner can
+/// read it.
+pub fn read_protected(path: &Path) -> Result<Vec<u8>> {
+    check_permissions(path)?;
+    read_file(path)
+}
+
+/// [`read_protected`] for a one-line secret such as a token. Surrounding
+/// whitespace, including the trailing newline, is dropped.
+pub fn read_secret(path: &Path) -> Result<String> {
+    let secret = String::from_utf8(read_protected(path)?)
+        .map_err(|_| Error::Transport(format!("{} is not valid UTF-8", path.display())))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return Err(Error::Transport(format!("{} is empty", path.display())));
+    }
+    Ok(secret.to_string())
+}
+
+/// Secret files must belong to the agent's user or root and must not be
+/// accessible by group or others.
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata =
+        fs::metadata(path).map_err(|e| Error::Transport(format!("cannot read {}: {}", path.display(), e)))?;
+    let mode = metadata.mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(Error::Transport(format!(
+            "{} is accessible by group or others (mode {:o}), expected 600 or stricter",
+            path.display(),
+            mode
+        )));
+    }
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != uid && metadata.uid() != 0 {
+        return Err(Error::Transport(format!(
+            "{} is owned by uid {}, expected uid {} or root",
+            path.display(),
+            metadata.uid(),
+            uid
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,506 @@
+use crate::config::{AgentConfig, Config, ConfigSource, IntegrityConfig, LoadedConfig, RelayConfig, SandboxConfig};
+use crate::engine::find_module_path;
+use crate::integrity::{check_permissions, normalize_hash, verify_module, HashManifest};
+use crate::manifest::{manifest_path, ModuleManifest};
+use crate::sandbox::Sandbox;
+use crate::scheduler::Trigger;
+use crate::status::ListenAddr;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+pub const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The agent cannot run with this config.
+    Error,
+    /// The config is usable but depends on something missing on this host,
+    /// such as a module binary.
+    Warning,
+}
+
+/// A problem with a config value, found after the config parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    /// Dotted path of the offending value, e.g. `modules.connections.interval`.
+    pub key: String,
+    pub message: String,
+    pub severity: Severity,
+    /// 1-based position of the key in the config file, when it is there.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Set when the value did not come from the file.
+    pub source: Option<ConfigSource>,
+}
+
+impl ConfigIssue {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigIssue {
+            key: key.into(),
+            message: message.into(),
+            severity: Severity::Error,
+            line: None,
+            column: None,
+            source: None,
+        }
+    }
+
+    fn warning(key: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigIssue {
+            severity: Severity::Warning,
+            ..ConfigIssue::new(key, message)
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.severity == Severity::Warning {
+            write!(f, "warning: ")?;
+        }
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "line {} column {}: ", line, column)?;
+        }
+        write!(f, "{}: {}", self.key, self.message)?;
+        if let Some(source) = &self.source {
+            write!(f, " (from {})", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the values serde accepts but the agent cannot use. Every problem
+/// is returned, not just the first.
+pub fn validate(config: &Config) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+
+    check_http_url("server.url", &config.server.url, &mut issues);
+    if let Some(enroll_url) = &config.server.enroll_url {
+        check_http_url("server.enroll_url", enroll_url, &mut issues);
+    }
+    if config.server.timeout == 0 {
+        issues.push(ConfigIssue::new("server.timeout", "must be greater than 0"));
+    }
+    check_transport(config, &mut issues);
+
+    let agent = &config.agent;
+    if let Err(message) = check_log_filter(&agent.log_level) {
+        issues.push(ConfigIssue::new("agent.log_level", message));
+    }
+    if agent.log.max_bytes == 0 {
+        issues.push(ConfigIssue::new("agent.log.max_bytes", "must be greater than 0"));
+    }
+    let mut log_modules: Vec<(&String, &String)> = agent.log.modules.iter().collect();
+    log_modules.sort();
+    for (module, level) in log_modules {
+        if log::LevelFilter::from_str(level).is_err() {
+            issues.push(ConfigIssue::new(
+                format!("agent.log.modules.{}", module),
+                format!("unknown level '{}', expected one of {}", level, LOG_LEVELS.join(", ")),
+            ));
+        }
+    }
+    for (key, value) in [
+        ("agent.default_interval", agent.default_interval),
+        ("agent.module_timeout", agent.module_timeout),
+        ("agent.max_concurrency", agent.max_concurrency as u64),
+    ] {
+        if value == 0 {
+            issues.push(ConfigIssue::new(key, "must be greater than 0"));
+        }
+    }
+    if let Some(listen) = &agent.status.listen {
+        if let Err(message) = ListenAddr::parse(listen) {
+            issues.push(ConfigIssue::new("agent.status.listen", message));
+        }
+    }
+    if let Some(relay) = &agent.relay {
+        check_relay(relay, agent, &mut issues);
+    }
+    let integrity_ok = check_integrity(&agent.integrity, &mut issues);
+    let sandbox_ok = check_sandbox("agent.sandbox", &agent.sandbox, &mut issues);
+    if agent.outbox.retry_initial > agent.outbox.retry_max {
+        issues.push(ConfigIssue::new(
+            "agent.outbox.retry_initial",
+            format!("is greater than agent.outbox.retry_max ({})", agent.outbox.retry_max),
+        ));
+    }
+
+    let mut names: Vec<&String> = config.modules.keys().collect();
+    names.sort();
+    for name in names {
+        let module = &config.modules[name];
+        let key = format!("modules.{}", name);
+
+        let path = match find_module_path(&agent.module_paths, name) {
+            Ok(path) => Some(path),
+            Err(crate::Error::InvalidModuleName(_)) => {
+                issues.push(ConfigIssue::new(
+                    &key,
+                    "module names may only contain letters, digits, '_' and single inner dots",
+                ));
+                None
+            }
+            Err(_) => {
+                issues.push(ConfigIssue::warning(
+                    &key,
+                    "module not found in any of agent.module_paths",
+                ));
+                None
+            }
+        };
+        let manifest = match path.as_deref().map(|path| ModuleManifest::load_for(name, path)).transpose() {
+            Ok(manifest) => manifest.flatten(),
+            Err(e) => {
+                issues.push(ConfigIssue::new(&key, e.to_string()));
+                None
+            }
+        };
+        if let Some(manifest) = manifest.as_ref().filter(|manifest| !manifest.supports_this_os()) {
+            issues.push(ConfigIssue::warning(
+                &key,
+                format!("module supports {} only, not {}", manifest.os.join(", "), std::env::consts::OS),
+            ));
+        }
+        if let Some(sha256) = &module.sha256 {
+            if normalize_hash(sha256).is_none() {
+                issues.push(ConfigIssue::new(format!("{}.sha256", key), "is not a 64-digit hex SHA-256"));
+            }
+        }
+        if let Some(path) = path.as_deref().filter(|_| integrity_ok) {
+            if let Err(e) = verify_module(&agent.integrity, name, path, Some(module)) {
+                issues.push(ConfigIssue::warning(&key, integrity_message(e)));
+            }
+            let manifest_file = manifest_path(path);
+            if manifest_file.exists() {
+                if let Err(e) = check_permissions(&agent.integrity, &manifest_file) {
+                    issues.push(ConfigIssue::warning(&key, integrity_message(e)));
+                }
+            }
+        }
+        if let Some(sandbox) = module.sandbox.as_ref().filter(|_| sandbox_ok) {
+            check_sandbox(&format!("{}.sandbox", key), &agent.sandbox.overridden_by(Some(sandbox)), &mut issues);
+        }
+        let spec = module.spec.as_ref().or(manifest.as_ref().and_then(|manifest| manifest.spec.as_ref()));
+        for violation in spec.map(|spec| spec.check_args(module.args.as_ref())).unwrap_or_default() {
+            issues.push(ConfigIssue::new(
+                format!("{}.args.{}", key, violation.name),
+                violation.message,
+            ));
+        }
+        if module.interval == Some(0) {
+            issues.push(ConfigIssue::new(format!("{}.interval", key), "must be greater than 0"));
+        }
+        if module.timeout == Some(0) {
+            issues.push(ConfigIssue::new(format!("{}.timeout", key), "must be greater than 0"));
+        }
+        if module.schedule.is_some() {
+            if let Err(e) = Trigger::for_module(module, agent) {
+                // Cron errors come with a caret diagram; keep only the text.
+                let message = e
+                    .to_string()
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && *line != "^")
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                issues.push(ConfigIssue::new(format!("{}.schedule", key), message));
+            }
+        }
+    }
+
+    issues
+}
+
+fn check_http_url(key: &str, url: &str, issues: &mut Vec<ConfigIssue>) {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {}
+        Ok(parsed) => issues.push(ConfigIssue::new(
+            key,
+            format!("unsupported scheme '{}', expected http or https", parsed.scheme()),
+        )),
+        Err(e) => issues.push(ConfigIssue::new(key, format!("'{}' is not a valid URL: {}", url, e))),
+    }
+}
+
+/// Checks that the auth, TLS and proxy settings fit together. Whether the
+/// files exist and are private is checked when the agent starts.
+fn check_transport(config: &Config, issues: &mut Vec<ConfigIssue>) {
+    let server = &config.server;
+    let auth = &server.auth;
+    if auth.token_file.is_some() && auth.api_key_file.is_some() {
+        issues.push(ConfigIssue::new(
+            "server.auth.api_key_file",
+            "set either auth.token_file or auth.api_key_file, not both",
+        ));
+    }
+    if reqwest::header::HeaderName::from_bytes(auth.api_key_header.as_bytes()).is_err() {
+        issues.push(ConfigIssue::new(
+            "server.auth.api_key_header",
+            format!("'{}' is not a valid header name", auth.api_key_header),
+        ));
+    }
+    let has_credentials = auth.token_file.is_some() || auth.api_key_file.is_some();
+    if has_credentials && server.url.starts_with("http://") {
+        issues.push(ConfigIssue::warning(
+            "server.url",
+            "credentials are sent over plain http, use https",
+        ));
+    }
+
+    let tls = &server.tls;
+    match (&tls.client_cert_file, &tls.client_key_file) {
+        (Some(_), None) => issues.push(ConfigIssue::new(
+            "server.tls.client_cert_file",
+            "is set without server.tls.client_key_file",
+        )),
+        (None, Some(_)) => issues.push(ConfigIssue::new(
+            "server.tls.client_key_file",
+            "is set without server.tls.client_cert_file",
+        )),
+        _ => {}
+    }
+
+    if let Some(proxy) = &server.proxy {
+        if let Err(e) = reqwest::Url::parse(&proxy.url) {
+            issues.push(ConfigIssue::new(
+                "server.proxy.url",
+                format!("'{}' is not a valid URL: {}", proxy.url, e),
+            ));
+        }
+        if proxy.password_file.is_some() && proxy.username.is_none() {
+            issues.push(ConfigIssue::new(
+                "server.proxy.password_file",
+                "is set without server.proxy.username",
+            ));
+        }
+    }
+}
+
+fn check_relay(relay: &RelayConfig, agent: &AgentConfig, issues: &mut Vec<ConfigIssue>) {
+    if relay.listen.parse::<SocketAddr>().is_err() {
+        issues.push(ConfigIssue::new(
+            "agent.relay.listen",
+            format!("'{}' is not an ip:port address", relay.listen),
+        ));
+    }
+    if agent.state_dir.is_none() {
+        issues.push(ConfigIssue::new("agent.relay", "needs agent.state_dir to spool batches"));
+    }
+    match (&relay.tls_cert_file, &relay.tls_key_file) {
+        (Some(_), None) => issues.push(ConfigIssue::new(
+            "agent.relay.tls_cert_file",
+            "is set without agent.relay.tls_key_file",
+        )),
+        (None, Some(_)) => issues.push(ConfigIssue::new(
+            "agent.relay.tls_key_file",
+            "is set without agent.relay.tls_cert_file",
+        )),
+        (None, None) if relay.token_file.is_some() => issues.push(ConfigIssue::warning(
+            "agent.relay.token_file",
+            "downstream agents send the token over plain http, set tls_cert_file and tls_key_file",
+        )),
+        _ => {}
+    }
+    if relay.token_file.is_none() {
+        issues.push(ConfigIssue::warning(
+            "agent.relay",
+            "anyone who can reach agent.relay.listen can send batches as any agent, set token_file",
+        ));
+    }
+    if relay.max_body_bytes == 0 {
+        issues.push(ConfigIssue::new("agent.relay.max_body_bytes", "must be greater than 0"));
+    }
+    if relay.read_timeout == 0 {
+        issues.push(ConfigIssue::new("agent.relay.read_timeout", "must be greater than 0"));
+    }
+    if relay.max_connections == 0 {
+        issues.push(ConfigIssue::new("agent.relay.max_connections", "must be greater than 0"));
+    }
+}
+
+/// Checks that the hash manifest and its signature can be read and verified.
+/// Returns false if modules cannot be verified at all.
+fn check_integrity(integrity: &IntegrityConfig, issues: &mut Vec<ConfigIssue>) -> bool {
+    let before = issues.len();
+    match (&integrity.manifest_file, &integrity.public_key_file) {
+        (Some(_), None) => issues.push(ConfigIssue::new(
+            "agent.integrity.manifest_file",
+            "is set without agent.integrity.public_key_file",
+        )),
+        (None, Some(_)) => issues.push(ConfigIssue::new(
+            "agent.integrity.public_key_file",
+            "is set without agent.integrity.manifest_file",
+        )),
+        (Some(_), Some(_)) => {
+            if let Err(e) = HashManifest::load(integrity) {
+                issues.push(ConfigIssue::new("agent.integrity.manifest_file", integrity_message(e)));
+            }
+        }
+        (None, None) => {}
+    }
+    #[cfg(unix)]
+    if let Err(e) = crate::integrity::trusted_owners(integrity) {
+        issues.push(ConfigIssue::new("agent.integrity.trusted_owners", integrity_message(e)));
+    }
+    #[cfg(unix)]
+    if let Err(e) = crate::integrity::trusted_groups(integrity) {
+        issues.push(ConfigIssue::new("agent.integrity.trusted_groups", integrity_message(e)));
+    }
+    issues.len() == before
+}
+
+/// Checks sandbox settings, as they apply after merging. Returns false if
+/// modules cannot be sandboxed as configured.
+fn check_sandbox(key: &str, sandbox: &SandboxConfig, issues: &mut Vec<ConfigIssue>) -> bool {
+    if let Err(e) = Sandbox::new(sandbox) {
+        let message = match e {
+            crate::Error::Sandbox(message) => message,
+            e => e.to_string(),
+        };
+        issues.push(ConfigIssue::new(key, message));
+        return false;
+    }
+    #[cfg(unix)]
+    if (sandbox.user.is_some() || sandbox.group.is_some()) && unsafe { libc::geteuid() } != 0 {
+        let field = if sandbox.user.is_some() { "user" } else { "group" };
+        issues.push(ConfigIssue::warning(
+            format!("{}.{}", key, field),
+            "only root can run modules as another user or group",
+        ));
+    }
+    if let Some(working_dir) = sandbox.working_dir.as_ref().filter(|dir| !dir.is_dir()) {
+        issues.push(ConfigIssue::warning(
+            format!("{}.working_dir", key),
+            format!("{} is not a directory", working_dir.display()),
+        ));
+    }
+    true
+}
+
+fn integrity_message(error: crate::Error) -> String {
+    match error {
+        crate::Error::UntrustedModule(message) => message,
+        e => e.to_string(),
+    }
+}
+
+/// Checks an `env_logger` filter: comma-separated directives, each a level,
+/// a target or `target=level`, optionally followed by `/regex`.
+fn check_log_filter(filter: &str) -> std::result::Result<(), String> {
+    let directives = filter.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let level = match directive.split_once('=') {
+            Some((_, level)) => level,
+            // As in env_logger, a bare word that is not a level is a target
+            // logged at every level.
+            None if directive.chars().all(|c| c.is_alphanumeric() || "_:-.".contains(c)) => continue,
+            None => return Err(format!("'{}' is neither a level nor a target", directive)),
+        };
+        if log::LevelFilter::from_str(level.trim()).is_err() {
+            return Err(format!(
+                "unknown level '{}', expected one of {}",
+                level.trim(),
+                LOG_LEVELS.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// [`validate`] for a loaded config, with each issue pointing at its
+/// position in the config file or naming the override it came from.
+pub fn validate_loaded(loaded: &LoadedConfig) -> Vec<ConfigIssue> {
+    validate(&loaded.config)
+        .into_iter()
+        .map(|mut issue| {
+            match loaded.source_of(&issue.key) {
+                ConfigSource::File(_) | ConfigSource::Default => {
+                    let segments = key_segments(&loaded.config, &issue.key);
+                    if let Some((line, column)) = locate_key(loaded.content(), &segments) {
+                        issue.line = Some(line);
+                        issue.column = Some(column);
+                    }
+                }
+                source => issue.source = Some(source),
+            }
+            issue
+        })
+        .collect()
+}
+
+/// Splits a dotted key into its path, keeping dotted module names such as
+/// `std.connections` in one piece.
+fn key_segments<'a>(config: &Config, key: &'a str) -> Vec<&'a str> {
+    if let Some(rest) = key.strip_prefix("modules.") {
+        let module = config
+            .modules
+            .keys()
+            .filter(|name| rest == name.as_str() || rest.starts_with(&format!("{}.", name)))
+            .max_by_key(|name| name.len());
+        if let Some(module) = module {
+            let (name, tail) = rest.split_at(module.len());
+            let mut segments = vec!["modules", name];
+            segments.extend(tail.split('.').filter(|segment| !segment.is_empty()));
+            return segments;
+        }
+    }
+    key.split('.').collect()
+}
+
+/// Finds the line and column of a key path in block-style YAML. Sequence
+/// items are addressed by index. When the key itself is missing, the closest
+/// enclosing key that exists is returned instead.
+pub fn locate_key(content: &str, segments: &[&str]) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut found = None;
+    let mut start = 0;
+    let mut parent_indent: Option<usize> = None;
+
+    for segment in segments {
+        let mut child_indent = None;
+        let mut item = 0;
+        let mut next = None;
+        for (i, line) in lines.iter().enumerate().skip(start) {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let indent = line.len() - trimmed.len();
+            let is_item = trimmed == "-" || trimmed.starts_with("- ");
+            // A sequence may sit at the same indent as its key.
+            if parent_indent.is_some_and(|parent| indent < parent || (indent == parent && !is_item)) {
+                break;
+            }
+            if *child_indent.get_or_insert(indent) != indent {
+                continue;
+            }
+            let name = if is_item {
+                let name = item.to_string();
+                item += 1;
+                name
+            } else {
+                match trimmed.split_once(':') {
+                    Some((name, _)) => name.trim().trim_matches(|c| c == '"' || c == '\'').to_string(),
+                    None => continue,
+                }
+            };
+            if name == *segment {
+                next = Some((i, indent));
+                break;
+            }
+        }
+        let Some((line, indent)) = next else {
+            break;
+        };
+        found = Some((line + 1, indent + 1));
+        start = line + 1;
+        parent_indent = Some(indent);
+    }
+    found
+}
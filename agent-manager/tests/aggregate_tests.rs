@@ -0,0 +1,94 @@
+use agent::aggregate::Aggregator;
+use agent::engine::{Dependency, Direction};
+use chrono::{Duration, Utc};
+use crate::common::outbound;
+
+#[test]
+fn test_aggregate_collapses_connection_pool() {
+    let pool: Vec<Dependency> = (40000..40200)
+        .map(|port| outbound(port, "10.0.0.9", 5432))
+        .collect();
+
+    let aggregated = Aggregator::default().aggregate(pool, Utc::now());
+
+    assert_eq!(aggregated.len(), 1);
+    assert_eq!(aggregated[0].count, 200);
+    assert_eq!(aggregated[0].service_port, Some(5432));
+    assert_eq!((aggregated[0].local_port, aggregated[0].remote_port), (0, 5432));
+}
+
+#[test]
+fn test_aggregate_reports_the_same_ports_across_collections() {
+    let mut aggregator = Aggregator::default();
+    let first = aggregator.aggregate(
+        vec![outbound(40000, "10.0.0.9", 5432), outbound(40001, "10.0.0.9", 5432)],
+        Utc::now(),
+    );
+    let second = aggregator.aggregate(
+        vec![outbound(51000, "10.0.0.9", 5432), outbound(51001, "10.0.0.9", 5432)],
+        Utc::now(),
+    );
+
+    assert_eq!((first[0].local_port, first[0].remote_port), (second[0].local_port, second[0].remote_port));
+
+    // An inbound pool keeps the local service port and drops the clients'.
+    let mut inbound = vec![outbound(5432, "10.0.0.20", 40000), outbound(5432, "10.0.0.20", 40001)];
+    for dependency in &mut inbound {
+        dependency.direction = Direction::Inbound;
+        dependency.service_port = Some(5432);
+    }
+    let aggregated = aggregator.aggregate(inbound, Utc::now());
+    assert_eq!(aggregated[0].count, 2);
+    assert_eq!((aggregated[0].local_port, aggregated[0].remote_port), (5432, 0));
+}
+
+#[test]
+fn test_aggregate_record_depends_only_on_key() {
+    let mut aggregator = Aggregator::default();
+    let mut identity = |dependencies: Vec<Dependency>| {
+        let aggregated = aggregator.aggregate(dependencies, Utc::now());
+        assert_eq!(aggregated.len(), 1);
+        let d = &aggregated[0];
+        (d.local_ip.clone(), d.remote_ip.clone(), d.module.clone(), d.local_port, d.remote_port, d.service_port)
+    };
+
+    let pooled = identity(vec![outbound(40000, "10.0.0.9", 5432), outbound(40001, "10.0.0.9", 5432)]);
+    let single = identity(vec![outbound(40001, "10.0.0.9", 5432)]);
+    let reconnected = identity(vec![outbound(52000, "10.0.0.9", 5432)]);
+
+    assert_eq!(pooled, single);
+    assert_eq!(single, reconnected);
+    assert_eq!((pooled.3, pooled.4), (0, 5432));
+}
+
+#[test]
+fn test_aggregate_keeps_distinct_services_apart() {
+    let dependencies = vec![
+        outbound(40000, "10.0.0.9", 5432),
+        outbound(40001, "10.0.0.9", 6379),
+        outbound(40002, "10.0.0.10", 5432),
+    ];
+
+    let aggregated = Aggregator::default().aggregate(dependencies, Utc::now());
+
+    assert_eq!(aggregated.len(), 3);
+    assert!(aggregated.iter().all(|d| d.count == 1 && d.local_port == 0));
+}
+
+#[test]
+fn test_aggregate_remembers_first_seen_while_present() {
+    let mut aggregator = Aggregator::default();
+    let t0 = Utc::now();
+    let t1 = t0 + Duration::seconds(30);
+    let t2 = t1 + Duration::seconds(30);
+
+    aggregator.aggregate(vec![outbound(40000, "10.0.0.9", 5432)], t0);
+    let second = aggregator.aggregate(vec![outbound(40001, "10.0.0.9", 5432)], t1);
+    assert_eq!(second[0].first_seen, Some(t0));
+    assert_eq!(second[0].last_seen, Some(t1));
+
+    // Once the dependency is gone for a collection it starts over.
+    aggregator.aggregate(vec![], t1);
+    let third = aggregator.aggregate(vec![outbound(40002, "10.0.0.9", 5432)], t2);
+    assert_eq!(third[0].first_seen, Some(t2));
+}
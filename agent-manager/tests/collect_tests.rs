@@ -0,0 +1,92 @@
+use agent::collect::{any_failed, collect_batch, collect_once, dependency_table, parse_args, render, OutputFormat};
+use agent::config::Config;
+use agent::engine::BatchKind;
+use agent::health::HealthStatus;
+use agent::CollectionEngine;
+use serde_json::Value;
+use std::time::Duration;
+use tempfile::TempDir;
+use crate::common::{create_module, outbound, run_engine_for, spawn_http_stub};
+
+const ECHO_ARGS: &str = r#"
+retries=$(sed -n 's/.*"retries":\([0-9]*\).*/\1/p' "$ARGS_FILE")
+cat <<JSON
+{"dependencies": [
+  {"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40000, "remote_port": 5432, "description": "retries $retries", "protocol": "TCP", "direction": "outbound", "service_port": 5432},
+  {"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40001, "remote_port": 5432, "description": "retries $retries", "protocol": "TCP", "direction": "outbound", "service_port": 5432}
+ ], "changed": true, "failed": false}
+JSON
+"#;
+
+fn config(url: &str, module_dir: &TempDir, modules: &str) -> Config {
+    serde_yaml::from_str(&format!(r#"
+server: {{url: "{}", timeout: 5}}
+agent: {{module_paths: ["{}"], log_level: info}}
+modules: {}
+"#, url, module_dir.path().display(), modules)).unwrap()
+}
+
+#[tokio::test]
+async fn test_run_once_aggregates_and_takes_args() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "pool", ECHO_ARGS);
+    let config = config("http://127.0.0.1:9", &module_dir, "{pool: {args: {retries: 3}}}");
+
+    let batch = collect_batch(&config, "pool", None).await;
+    assert_eq!(batch.kind, BatchKind::Full);
+    assert_eq!(batch.dependencies.len(), 1);
+    assert_eq!(batch.dependencies[0].count, 2);
+    assert_eq!(batch.dependencies[0].description, "retries 3");
+
+    let args = parse_args(r#"{"retries": 7}"#).unwrap();
+    let batch = collect_batch(&config, "pool", Some(args)).await;
+    assert_eq!(batch.dependencies[0].description, "retries 7");
+
+    assert!(parse_args("[1, 2]").is_err());
+}
+
+#[tokio::test]
+async fn test_collect_once_reports_failures() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "pool", ECHO_ARGS);
+    let config = config("http://127.0.0.1:9", &module_dir, "{pool: {}, missing: {}}");
+
+    let batches = collect_once(&config).await;
+    let names: Vec<&str> = batches.iter().map(|batch| batch.module.as_str()).collect();
+    assert_eq!(names, vec!["missing", "pool"]);
+    assert_eq!(batches[0].kind, BatchKind::Health);
+    assert_eq!(batches[0].health.as_ref().unwrap().status, HealthStatus::NotFound);
+    assert!(any_failed(&batches));
+    assert!(!any_failed(&batches[1..]));
+
+    let json: Value = serde_json::from_str(&render(&batches, OutputFormat::Json).unwrap()).unwrap();
+    assert_eq!(json[1]["Dependencies"][0]["Count"], 2);
+    let table = render(&batches, OutputFormat::Table).unwrap();
+    assert!(table.contains("== missing: not_found"));
+    assert!(table.contains("== pool: 1 dependencies, 0 services"));
+}
+
+#[test]
+fn test_dependency_table_aligns_columns() {
+    let table = dependency_table(&[outbound(40000, "10.0.0.9", 5432), outbound(40001, "fd00::1", 443)]);
+    assert_eq!(
+        table,
+        "DIRECTION  LOCAL           REMOTE         PROTOCOL  PROCESS  COUNT\n\
+         outbound   10.0.0.5:40000  10.0.0.9:5432  TCP       -        1\n\
+         outbound   10.0.0.5:40001  [fd00::1]:443  TCP       -        1\n"
+    );
+}
+
+#[tokio::test]
+async fn test_dry_run_sends_nothing() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "pool", ECHO_ARGS);
+    let (url, requests) = spawn_http_stub(200).await;
+    let config = config(&url, &module_dir, "{pool: {interval: 60}}");
+
+    let mut engine = CollectionEngine::new(config).with_dry_run(true);
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    assert!(requests.lock().unwrap().is_empty());
+    assert_eq!(engine.stats().lock().unwrap().modules["pool"].dependency_count, Some(1));
+}
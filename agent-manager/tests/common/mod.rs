@@ -1,7 +1,19 @@
+#![allow(dead_code)]
+
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use agent::engine::{Dependency, Direction};
+use agent::CollectionEngine;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+#[cfg(unix)]
+pub mod pki;
 
 pub fn create_temp_script(content: &str) -> (TempDir, PathBuf) {
     let dir = TempDir::new().unwrap();
@@ -26,3 +38,154 @@ pub fn create_temp_config(content: &str) -> (TempDir, PathBuf) {
     file.flush().unwrap();
     (dir, file_path)
 }
+
+/// A captured request received by [`spawn_http_stub`].
+#[derive(Debug, Clone, Default)]
+pub struct StubRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Starts a minimal HTTP/1.1 server on localhost that records every request
+/// and answers each with `status`. Returns the base URL and the request log.
+pub async fn spawn_http_stub(status: u16) -> (String, Arc<Mutex<Vec<StubRequest>>>) {
+    spawn_http_stub_with_body(status, "").await
+}
+
+/// Like [`spawn_http_stub`], answering every request with `body` as well.
+pub async fn spawn_http_stub_with_body(status: u16, body: &'static str) -> (String, Arc<Mutex<Vec<StubRequest>>>) {
+    spawn_http_stub_with(move |_| (status, body)).await
+}
+
+/// Like [`spawn_http_stub`], answering each request with the status and body
+/// `respond` picks for it.
+pub async fn spawn_http_stub_with(
+    respond: impl Fn(&StubRequest) -> (u16, &'static str) + Send + Sync + 'static,
+) -> (String, Arc<Mutex<Vec<StubRequest>>>) {
+    let respond = Arc::new(respond);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let log = requests.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return;
+            };
+            let log = log.clone();
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let (status, body) = match read_request(&mut stream).await {
+                    Some(request) => {
+                        let answer = respond(&request);
+                        log.lock().unwrap().push(request);
+                        answer
+                    }
+                    None => respond(&StubRequest::default()),
+                };
+                let response = format!(
+                    "HTTP/1.1 {} Stub\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    (url, requests)
+}
+
+/// Reads one HTTP/1.1 request, headers and body, from `stream`.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Option<StubRequest> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k == "content-length")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let mut body = buf[header_end..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+
+    Some(StubRequest { method, path, headers, body })
+}
+
+/// Writes an executable bash module called `name` into `dir`, using the same
+/// dotted-name-to-path mapping as the engine.
+pub fn create_module(dir: &Path, name: &str, content: &str) -> PathBuf {
+    let file_path = dir.join(name.replace('.', "/"));
+    fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+    let mut file = File::create(&file_path).unwrap();
+    writeln!(file, "#!/bin/bash").unwrap();
+    write!(file, "{}", content).unwrap();
+    file.flush().unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    file_path
+}
+
+/// Runs the engine's main loop for `duration` and then shuts it down.
+pub async fn run_engine_for(engine: &mut CollectionEngine, duration: Duration) {
+    let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        let _ = shutdown_tx.send(()).await;
+    });
+    engine.run(shutdown_rx).await.unwrap();
+}
+
+/// An outbound TCP dependency from 10.0.0.5 as the connections module reports it.
+pub fn outbound(local_port: u16, remote_ip: &str, remote_port: u16) -> Dependency {
+    Dependency {
+        local_ip: "10.0.0.5".to_string(),
+        local_os: "Linux".to_string(),
+        remote_ip: remote_ip.to_string(),
+        local_port,
+        remote_port,
+        module: "Connections".to_string(),
+        description: "TCP connection".to_string(),
+        protocol: Some("TCP".to_string()),
+        process: None,
+        direction: Direction::Outbound,
+        service_port: Some(remote_port),
+        count: 1,
+        first_seen: None,
+        last_seen: None,
+    }
+}
@@ -0,0 +1,94 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName};
+use openssl::x509::{X509NameBuilder, X509};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// A CA with a server certificate for 127.0.0.1 and a client certificate,
+/// written as PEM files.
+pub struct Pki {
+    pub dir: TempDir,
+    pub ca: PathBuf,
+    pub server_cert: PathBuf,
+    pub server_key: PathBuf,
+    pub client_cert: PathBuf,
+    pub client_key: PathBuf,
+}
+
+fn generate_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+/// Issues a certificate for `common_name`, self-signed when `issuer` is None.
+fn issue(common_name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+    let name = name.build();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(issuer.map_or(&name, |(cert, _)| cert.subject_name())).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    match issuer {
+        None => {
+            builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        }
+        Some((ca, _)) => {
+            let san = SubjectAlternativeName::new()
+                .ip("127.0.0.1")
+                .dns("localhost")
+                .build(&builder.x509v3_context(Some(ca), None))
+                .unwrap();
+            builder.append_extension(san).unwrap();
+            builder
+                .append_extension(ExtendedKeyUsage::new().server_auth().client_auth().build().unwrap())
+                .unwrap();
+        }
+    }
+    builder.sign(issuer.map_or(key, |(_, key)| key), MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+pub fn write_private(path: &Path, content: &[u8]) {
+    fs::write(path, content).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+}
+
+pub fn pki() -> Pki {
+    let dir = TempDir::new().unwrap();
+    let ca_key = generate_key();
+    let ca = issue("dep_map test CA", &ca_key, None);
+    let server_key = generate_key();
+    let server = issue("localhost", &server_key, Some((&ca, &ca_key)));
+    let client_key = generate_key();
+    let client = issue("agent-1", &client_key, Some((&ca, &ca_key)));
+
+    let pki = Pki {
+        ca: dir.path().join("ca.pem"),
+        server_cert: dir.path().join("server.pem"),
+        server_key: dir.path().join("server.key"),
+        client_cert: dir.path().join("client.pem"),
+        client_key: dir.path().join("client.key"),
+        dir,
+    };
+    fs::write(&pki.ca, ca.to_pem().unwrap()).unwrap();
+    fs::write(&pki.server_cert, server.to_pem().unwrap()).unwrap();
+    write_private(&pki.server_key, &server_key.private_key_to_pem_pkcs8().unwrap());
+    fs::write(&pki.client_cert, client.to_pem().unwrap()).unwrap();
+    write_private(&pki.client_key, &client_key.private_key_to_pem_pkcs8().unwrap());
+    pki
+}
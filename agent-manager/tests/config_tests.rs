@@ -1,36 +1,8 @@
-use agent::config::{Config, ServerConfig, AgentConfig, ModuleConfig};
+use agent::config::{Config, ConfigLoader, ConfigOverrides, ConfigSource, ServerConfig, AgentConfig, AuthConfig, ModuleConfig, TlsConfig};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use serde_json::json;
-use std::fs::{self, File};
-use std::io::Write;
-use tempfile::TempDir;
-
-
-pub fn create_temp_script(content: &str) -> (TempDir, PathBuf) {
-    let dir = TempDir::new().unwrap();
-    let file_path = dir.path().join("script.sh");
-    let mut file = File::create(&file_path).unwrap();
-    writeln!(file, "#!/bin/bash").unwrap();
-    write!(file, "{}", content).unwrap();
-    file.flush().unwrap();
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755)).unwrap();
-    }
-    (dir, file_path)
-}
-
-pub fn create_temp_config(content: &str) -> (TempDir, PathBuf) {
-    let dir = TempDir::new().unwrap();
-    let file_path = dir.path().join("config.yaml");
-    let mut file = File::create(&file_path).unwrap();
-    write!(file, "{}", content).unwrap();
-    file.flush().unwrap();
-    (dir, file_path)
-}
-
+use crate::common::create_temp_config;
 
 #[test]
 fn test_deserialize_valid_config() {
@@ -56,6 +28,10 @@ fn test_deserialize_valid_config() {
     assert_eq!(config.server, ServerConfig {
         url: "http://localhost:8000/api/v1/collect".to_string(),
         timeout: 30,
+        auth: AuthConfig::default(),
+        tls: TlsConfig::default(),
+        proxy: None,
+        enroll_url: None,
     });
     
     assert_eq!(config.agent, AgentConfig {
@@ -65,14 +41,16 @@ fn test_deserialize_valid_config() {
             PathBuf::from("/tmp"),
         ],
         log_level: "info".to_string(),
+        ..Default::default()
     });
     
     let expected_modules = {
         let mut map = HashMap::new();
         map.insert("std.modules.connection".to_string(), ModuleConfig {
             description: Some("Description of connection module".to_string()),
-            interval: 15,
+            interval: Some(15),
             args: None,
+            ..Default::default()
         });
         map
     };
@@ -107,13 +85,14 @@ fn test_deserialize_multiple_modules() {
     
     assert_eq!(config.modules.get("std.modules.connection"), Some(&ModuleConfig {
         description: Some("Connection module".to_string()),
-        interval: 15,
+        interval: Some(15),
         args: None,
+        ..Default::default()
     }));
     
     let custom_module = config.modules.get("custom.module").unwrap();
     assert_eq!(custom_module.description, None);
-    assert_eq!(custom_module.interval, 300);
+    assert_eq!(custom_module.interval, Some(300));
     assert_eq!(custom_module.args, Some({
         let mut map = HashMap::new();
         map.insert("key".to_string(), json!("value"));
@@ -156,3 +135,135 @@ fn test_deserialize_invalid_config() {
     
     assert!(result.is_err());
 }
+
+#[test]
+fn test_deserialize_default_interval() {
+    let config_content = r#"
+    server:
+      url: "http://localhost:8000/api/v1/collect"
+      timeout: 30
+    agent:
+      default_interval: 120
+      module_paths: []
+      log_level: "info"
+    modules:
+      inventory:
+        description: "Runs on the default interval"
+    "#;
+    let (_dir, config_file) = create_temp_config(config_content);
+
+    let config: Config = serde_yaml::from_str(&std::fs::read_to_string(config_file).unwrap()).unwrap();
+
+    assert_eq!(config.agent.default_interval, 120);
+    assert_eq!(config.modules["inventory"].interval, None);
+}
+
+const MINIMAL_CONFIG: &str = r#"
+server:
+  url: "http://file.example/api"
+  timeout: 30
+agent:
+  module_paths: ["/opt/modules"]
+  log_level: "info"
+modules: {}
+"#;
+
+fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+    vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn test_loader_uses_first_existing_search_path() {
+    let (_dir, config_file) = create_temp_config(MINIMAL_CONFIG);
+    let loader = ConfigLoader::new(ConfigOverrides::default())
+        .with_env(env(&[]))
+        .with_search_paths(vec![PathBuf::from("/nonexistent/dep_map.yaml"), config_file.clone()]);
+
+    let loaded = loader.load().unwrap();
+
+    assert_eq!(loaded.path, config_file);
+    assert_eq!(loaded.config.server.url, "http://file.example/api");
+}
+
+#[test]
+fn test_loader_prefers_cli_then_env_config_path() {
+    let (_dir, cli_file) = create_temp_config(MINIMAL_CONFIG);
+    let (_env_dir, env_file) = create_temp_config(MINIMAL_CONFIG);
+    let env_vars = env(&[("DEP_MAP_CONFIG", env_file.to_str().unwrap())]);
+
+    let from_env = ConfigLoader::new(ConfigOverrides::default()).with_env(env_vars.clone());
+    assert_eq!(from_env.locate().unwrap(), (env_file, ConfigSource::Env("DEP_MAP_CONFIG".to_string())));
+
+    let overrides = ConfigOverrides { config_path: Some(cli_file.clone()), ..Default::default() };
+    let from_cli = ConfigLoader::new(overrides).with_env(env_vars);
+    assert_eq!(from_cli.locate().unwrap(), (cli_file, ConfigSource::Cli("config".to_string())));
+}
+
+#[test]
+fn test_loader_reports_missing_config() {
+    let loader = ConfigLoader::new(ConfigOverrides::default())
+        .with_env(env(&[]))
+        .with_search_paths(vec![PathBuf::from("/nonexistent/dep_map.yaml")]);
+
+    assert!(matches!(loader.load(), Err(agent::Error::ConfigNotFound(_))));
+}
+
+#[test]
+fn test_loader_layers_env_and_cli_over_file() {
+    let (_dir, config_file) = create_temp_config(MINIMAL_CONFIG);
+    let env_vars = env(&[
+        ("DEP_MAP_SERVER_URL", "http://env.example/api"),
+        ("DEP_MAP_LOG_LEVEL", "warn"),
+        ("DEP_MAP_MODULE_PATH", "/env/a:/env/b"),
+    ]);
+    let overrides = ConfigOverrides {
+        config_path: Some(config_file.clone()),
+        log_level: Some("debug".to_string()),
+        ..Default::default()
+    };
+
+    let loaded = ConfigLoader::new(overrides).with_env(env_vars).load().unwrap();
+
+    assert_eq!(loaded.config.server.url, "http://env.example/api");
+    assert_eq!(loaded.config.agent.log_level, "debug");
+    assert_eq!(loaded.config.agent.module_paths, vec![
+        PathBuf::from("/env/a"),
+        PathBuf::from("/env/b"),
+        PathBuf::from("/opt/modules"),
+    ]);
+
+    assert_eq!(loaded.source_of("server.url"), ConfigSource::Env("DEP_MAP_SERVER_URL".to_string()));
+    assert_eq!(loaded.source_of("agent.log_level"), ConfigSource::Cli("log-level".to_string()));
+    assert_eq!(loaded.source_of("server.timeout"), ConfigSource::File(config_file.clone()));
+    assert_eq!(loaded.source_of("agent.default_interval"), ConfigSource::Default);
+    assert_eq!(loaded.source_of("agent.module_paths"), ConfigSource::Layered(vec![
+        ConfigSource::Env("DEP_MAP_MODULE_PATH".to_string()),
+        ConfigSource::File(config_file.clone()),
+    ]));
+}
+
+#[test]
+fn test_loader_reports_every_source_of_module_paths() {
+    let (_dir, config_file) = create_temp_config(MINIMAL_CONFIG);
+    let overrides = ConfigOverrides {
+        config_path: Some(config_file.clone()),
+        module_paths: vec![PathBuf::from("/cli")],
+        ..Default::default()
+    };
+
+    let loaded = ConfigLoader::new(overrides)
+        .with_env(env(&[("DEP_MAP_MODULE_PATH", "/env")]))
+        .load()
+        .unwrap();
+
+    assert_eq!(loaded.config.agent.module_paths, vec![
+        PathBuf::from("/cli"),
+        PathBuf::from("/env"),
+        PathBuf::from("/opt/modules"),
+    ]);
+    let source = loaded.source_of("agent.module_paths");
+    assert_eq!(
+        source.to_string(),
+        format!("cli --module-path + env DEP_MAP_MODULE_PATH + file {}", config_file.display())
+    );
+}
@@ -0,0 +1,39 @@
+use agent::daemon::{DaemonStatus, PidFile};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Takes the lock the way a running agent does and keeps it until dropped.
+fn hold_lock(path: &std::path::Path, pid: i32) -> File {
+    std::fs::write(path, format!("{}\n", pid)).unwrap();
+    let file = File::open(path).unwrap();
+    assert_eq!(unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }, 0);
+    file
+}
+
+#[test]
+fn test_status_follows_the_pid_file_lock() {
+    let dir = TempDir::new().unwrap();
+    let pid_file = PidFile::new(dir.path().join("dep_map.pid"));
+    assert_eq!(pid_file.status().unwrap(), DaemonStatus::NotRunning);
+    assert_eq!(DaemonStatus::NotRunning.exit_code(), 3);
+
+    let lock = hold_lock(pid_file.path(), 4242);
+    assert_eq!(pid_file.status().unwrap(), DaemonStatus::Running(4242));
+    assert!(pid_file.ensure_not_running().is_err());
+
+    drop(lock);
+    assert_eq!(pid_file.status().unwrap(), DaemonStatus::Stale(Some(4242)));
+    assert!(pid_file.ensure_not_running().is_ok());
+}
+
+#[test]
+fn test_stop_cleans_up_stale_pid_file() {
+    let dir = TempDir::new().unwrap();
+    let pid_file = PidFile::new(dir.path().join("dep_map.pid"));
+    std::fs::write(pid_file.path(), "4242\n").unwrap();
+
+    assert_eq!(pid_file.stop(Duration::from_secs(1)).unwrap(), None);
+    assert!(!pid_file.path().exists());
+}
@@ -0,0 +1,87 @@
+use agent::delta::DeltaTracker;
+use agent::engine::{BatchKind, ModuleOutput};
+use std::time::{Duration, Instant};
+use crate::common::outbound;
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn output(ports: &[u16], changed: bool) -> ModuleOutput {
+    ModuleOutput {
+        dependencies: ports.iter().map(|&port| outbound(40000, "10.0.0.9", port)).collect(),
+        services: vec![],
+        changed,
+    }
+}
+
+#[test]
+fn test_first_batch_is_full_snapshot() {
+    let tracker = DeltaTracker::default();
+    let batch = tracker.batch("conn", &output(&[5432, 6379], true), HOUR, false, Instant::now()).unwrap();
+
+    assert_eq!(batch.kind, BatchKind::Full);
+    assert_eq!(batch.dependencies.len(), 2);
+    assert!(batch.removed.is_empty());
+}
+
+#[test]
+fn test_delta_reports_additions_and_removals() {
+    let now = Instant::now();
+    let mut tracker = DeltaTracker::default();
+    tracker.commit(output(&[5432, 6379], true), BatchKind::Full, now);
+
+    let batch = tracker.batch("conn", &output(&[5432, 443], true), HOUR, false, now).unwrap();
+
+    assert_eq!(batch.kind, BatchKind::Delta);
+    assert_eq!(batch.dependencies.len(), 1);
+    assert_eq!(batch.dependencies[0].remote_port, 443);
+    assert_eq!(batch.removed.len(), 1);
+    assert_eq!(batch.removed[0].remote_port, 6379);
+}
+
+#[test]
+fn test_removal_names_the_record_that_was_sent() {
+    let now = Instant::now();
+    let mut tracker = DeltaTracker::default();
+    let first = output(&[5432], true);
+    let sent = first.dependencies[0].clone();
+    tracker.commit(first, BatchKind::Full, now);
+
+    let mut moved = output(&[5432], true);
+    moved.dependencies[0].description = "reconnected".to_string();
+    assert!(tracker.batch("conn", &moved, HOUR, false, now).is_none());
+    tracker.commit(moved, BatchKind::Delta, now);
+
+    let batch = tracker.batch("conn", &output(&[], true), HOUR, false, now).unwrap();
+    assert_eq!(batch.removed.len(), 1);
+    assert_eq!(serde_json::to_value(&batch.removed[0]).unwrap(), serde_json::to_value(&sent).unwrap());
+}
+
+#[test]
+fn test_unchanged_output_sends_nothing() {
+    let now = Instant::now();
+    let mut tracker = DeltaTracker::default();
+    tracker.commit(output(&[5432], true), BatchKind::Full, now);
+
+    assert!(tracker.batch("conn", &output(&[5432], true), HOUR, false, now).is_none());
+}
+
+#[test]
+fn test_module_changed_flag_short_circuits_when_trusted() {
+    let now = Instant::now();
+    let mut tracker = DeltaTracker::default();
+    tracker.commit(output(&[5432], true), BatchKind::Full, now);
+
+    assert!(tracker.batch("conn", &output(&[443], false), HOUR, true, now).is_none());
+    assert!(tracker.batch("conn", &output(&[443], false), HOUR, false, now).is_some());
+}
+
+#[test]
+fn test_full_snapshot_when_interval_elapsed() {
+    let start = Instant::now();
+    let mut tracker = DeltaTracker::default();
+    tracker.commit(output(&[5432], true), BatchKind::Full, start);
+
+    let batch = tracker.batch("conn", &output(&[5432], true), HOUR, false, start + HOUR).unwrap();
+    assert_eq!(batch.kind, BatchKind::Full);
+    assert_eq!(batch.dependencies.len(), 1);
+}
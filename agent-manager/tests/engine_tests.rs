@@ -0,0 +1,318 @@
+use agent::config::Config;
+use agent::CollectionEngine;
+use serde_json::Value;
+use std::time::Duration;
+use tempfile::TempDir;
+use crate::common::{create_module, run_engine_for, spawn_http_stub};
+
+fn engine_config(url: &str, module_dir: &TempDir, modules: &str) -> Config {
+    let config_content = format!(r#"
+    server:
+      url: "{}"
+      timeout: 5
+    agent:
+      module_paths: ["{}"]
+      log_level: "info"
+    modules:
+{}
+    "#, url, module_dir.path().display(), modules);
+    serde_yaml::from_str(&config_content).unwrap()
+}
+
+#[tokio::test]
+async fn test_engine_reports_services_separately() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "listeners", r#"
+cat <<'JSON'
+{"dependencies": [{"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40000, "remote_port": 5432, "description": "TCP connection", "protocol": "TCP", "direction": "outbound", "service_port": 5432}],
+ "services": [{"module": "Connections", "bind_ip": "0.0.0.0", "port": 22, "protocol": "TCP", "local_os": "Linux"}],
+ "changed": false, "failed": false}
+JSON
+"#);
+    let (url, requests) = spawn_http_stub(200).await;
+    let config = engine_config(&url, &module_dir, "      listeners:\n        interval: 60");
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let batch: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(batch["Module"], "listeners");
+    assert_eq!(batch["Dependencies"].as_array().unwrap().len(), 1);
+    assert_eq!(batch["Dependencies"][0]["RemotePort"], 5432);
+    assert_eq!(batch["Dependencies"][0]["Direction"], "outbound");
+    assert_eq!(batch["Dependencies"][0]["ServicePort"], 5432);
+    assert_eq!(batch["Services"][0]["BindIp"], "0.0.0.0");
+    assert_eq!(batch["Services"][0]["Port"], 22);
+}
+
+#[tokio::test]
+async fn test_engine_reports_module_failures_as_health() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "reported", r#"
+echo '{"dependencies": [], "changed": false, "failed": true, "error": "permission denied reading /proc"}'
+exit 1
+"#);
+    create_module(module_dir.path(), "crashed", r#"
+echo "segfault in collector" >&2
+exit 3
+"#);
+    create_module(module_dir.path(), "garbled", r#"
+echo "not json"
+"#);
+    let (url, requests) = spawn_http_stub(200).await;
+    let modules = ["reported", "crashed", "garbled", "missing"]
+        .iter()
+        .map(|name| format!("      {}:\n        interval: 60\n", name))
+        .collect::<String>();
+    let config = engine_config(&url, &module_dir, &modules);
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    let batches: Vec<Value> = requests.lock().unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect();
+    let health = |module: &str| -> Value {
+        let batch = batches.iter().find(|batch| batch["Module"] == module).unwrap();
+        assert_eq!(batch["Kind"], "health");
+        batch["Health"].clone()
+    };
+
+    let reported = health("reported");
+    assert_eq!(reported["Status"], "failed");
+    assert_eq!(reported["Message"], "permission denied reading /proc");
+    assert_eq!(reported["ConsecutiveFailures"], 1);
+
+    let crashed = health("crashed");
+    assert_eq!(crashed["Status"], "non_zero_exit");
+    assert_eq!(crashed["ExitCode"], 3);
+    assert_eq!(crashed["Stderr"], "segfault in collector");
+
+    assert_eq!(health("garbled")["Status"], "malformed_output");
+    assert_eq!(health("missing")["Status"], "not_found");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_engine_kills_hung_module_and_its_children() {
+    let module_dir = TempDir::new().unwrap();
+    let pid_file = module_dir.path().join("child.pid");
+    create_module(module_dir.path(), "hung", &format!(r#"
+sleep 30 &
+echo $! > {}
+wait
+"#, pid_file.display()));
+    let (url, requests) = spawn_http_stub(200).await;
+    let config = engine_config(&url, &module_dir, "      hung:\n        interval: 60\n        timeout: 1");
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(2500)).await;
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let batch: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(batch["Health"]["Status"], "timeout");
+
+    let child_pid = std::fs::read_to_string(&pid_file).unwrap();
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", child_pid.trim())).unwrap_or_default();
+    // Killed children may linger as zombies until reaped by init; either way they no longer run.
+    assert!(stat.is_empty() || stat.contains(") Z "));
+}
+
+#[tokio::test]
+async fn test_slow_module_does_not_delay_others() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "slow", r#"
+sleep 3
+echo '{"dependencies": [], "changed": true, "failed": false}'
+"#);
+    create_module(module_dir.path(), "fast", r#"
+echo "{\"dependencies\": [{\"module\": \"fast\", \"local_ip\": \"10.0.0.5\", \"local_os\": \"Linux\", \"remote_ip\": \"10.0.0.9\", \"local_port\": 40000, \"remote_port\": $RANDOM, \"description\": \"\"}], \"changed\": true, \"failed\": false}"
+"#);
+    let (url, requests) = spawn_http_stub(200).await;
+    let config = engine_config(&url, &module_dir,
+        "      slow:\n        interval: 60\n      fast:\n        interval: 1");
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(2500)).await;
+
+    let modules: Vec<String> = requests.lock().unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice::<Value>(&request.body).unwrap()["Module"].as_str().unwrap().to_string())
+        .collect();
+    assert!(modules.iter().filter(|m| *m == "fast").count() >= 2);
+    assert!(!modules.contains(&"slow".to_string()));
+}
+
+#[tokio::test]
+async fn test_module_never_overlaps_itself() {
+    let module_dir = TempDir::new().unwrap();
+    let lock_dir = module_dir.path().join("running");
+    let runs = module_dir.path().join("runs");
+    create_module(module_dir.path(), "sluggish", &format!(r#"
+mkdir {lock} || echo overlap >> {runs}
+echo run >> {runs}
+sleep 1.5
+rmdir {lock}
+echo '{{"dependencies": [], "changed": true, "failed": false}}'
+"#, lock = lock_dir.display(), runs = runs.display()));
+    let (url, _requests) = spawn_http_stub(200).await;
+    let config = engine_config(&url, &module_dir,
+        "      sluggish:\n        interval: 1\n        overlap: queue");
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(3500)).await;
+
+    let runs = std::fs::read_to_string(&runs).unwrap();
+    assert!(!runs.contains("overlap"));
+    assert!(runs.lines().count() >= 2);
+}
+
+#[tokio::test]
+async fn test_slow_server_does_not_hold_back_module_runs() {
+    let module_dir = TempDir::new().unwrap();
+    let runs = module_dir.path().join("runs");
+    create_module(module_dir.path(), "ticker", &format!(r#"
+echo run >> {}
+echo "{{\"dependencies\": [{{\"module\": \"ticker\", \"local_ip\": \"10.0.0.5\", \"local_os\": \"Linux\", \"remote_ip\": \"10.0.0.9\", \"local_port\": 40000, \"remote_port\": $RANDOM, \"description\": \"\"}}], \"changed\": true, \"failed\": false}}"
+"#, runs.display()));
+    // Accepts connections and never answers.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+    let mut config = engine_config(&url, &module_dir, "      ticker:\n        interval: 1");
+    config.server.timeout = 3;
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(2500)).await;
+
+    let runs = std::fs::read_to_string(&runs).unwrap();
+    assert!(runs.lines().count() >= 3);
+}
+
+#[tokio::test]
+async fn test_trust_changed_reports_deltas_only_when_module_says_changed() {
+    let module_dir = TempDir::new().unwrap();
+    for (name, changed) in [("moving", "true"), ("quiet", "false")] {
+        create_module(module_dir.path(), name, &format!(r#"
+echo "{{\"dependencies\": [{{\"module\": \"{}\", \"local_ip\": \"10.0.0.5\", \"local_os\": \"Linux\", \"remote_ip\": \"10.0.0.9\", \"local_port\": 40000, \"remote_port\": $RANDOM, \"description\": \"\"}}], \"changed\": {}, \"failed\": false}}"
+"#, name, changed));
+    }
+    let (url, requests) = spawn_http_stub(200).await;
+    let mut config = engine_config(&url, &module_dir,
+        "      moving:\n        interval: 1\n        trust_changed: true\n      quiet:\n        interval: 1\n        trust_changed: true");
+    config.agent.full_snapshot_interval = 3600;
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(2500)).await;
+
+    let batches: Vec<Value> = requests.lock().unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect();
+    let kinds = |module: &str| -> Vec<String> {
+        batches.iter()
+            .filter(|batch| batch["Module"] == module)
+            .map(|batch| batch["Kind"].as_str().unwrap().to_string())
+            .collect()
+    };
+    let moving = kinds("moving");
+    assert!(moving.len() >= 2);
+    assert_eq!(moving[0], "full");
+    assert!(moving[1..].iter().all(|kind| kind == "delta"));
+    // The quiet module's output changes too, but it says it did not.
+    assert_eq!(kinds("quiet"), ["full"]);
+}
+
+#[tokio::test]
+async fn test_removal_repeats_the_record_first_sent() {
+    let module_dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    // The database connection reconnects with a new description on the
+    // second run, when a web connection shows up, and is gone on the third.
+    create_module(module_dir.path(), "flaky", &format!(r#"
+runs_file="{}/runs"
+run=$(( $(cat "$runs_file" 2>/dev/null || echo 0) + 1 ))
+echo $run > "$runs_file"
+cache='{{"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.10", "local_port": 41000, "remote_port": 6379, "description": "", "direction": "outbound", "service_port": 6379}}'
+db='{{"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 4000'$run', "remote_port": 5432, "description": "run '$run'", "direction": "outbound", "service_port": 5432}}'
+web='{{"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.11", "local_port": 42000, "remote_port": 443, "description": "", "direction": "outbound", "service_port": 443}}'
+case $run in
+  1) echo "{{\"dependencies\": [$cache, $db], \"changed\": true, \"failed\": false}}" ;;
+  2) echo "{{\"dependencies\": [$cache, $db, $web], \"changed\": true, \"failed\": false}}" ;;
+  *) echo "{{\"dependencies\": [$cache, $web], \"changed\": true, \"failed\": false}}" ;;
+esac
+"#, state_dir.path().display()));
+    let (url, requests) = spawn_http_stub(200).await;
+    let mut config = engine_config(&url, &module_dir, "      flaky:\n        interval: 1");
+    config.agent.full_snapshot_interval = 3600;
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(3000)).await;
+
+    let batches: Vec<Value> = requests.lock().unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect();
+    assert_eq!(batches.len(), 3, "{:?}", batches);
+    assert_eq!(batches[0]["Kind"], "full");
+    let sent = batches[0]["Dependencies"].as_array().unwrap()
+        .iter()
+        .find(|d| d["RemoteIp"] == "10.0.0.9")
+        .unwrap();
+    assert_eq!(sent["Description"], "run 1");
+    assert_eq!(batches[1]["Dependencies"][0]["RemoteIp"], "10.0.0.11");
+    assert_eq!(batches[2]["Kind"], "delta");
+    assert_eq!(batches[2]["Removed"], Value::Array(vec![sent.clone()]));
+}
+
+#[tokio::test]
+async fn test_engine_reports_direction_and_service_port() {
+    let module_dir = TempDir::new().unwrap();
+    // What the connections module reports for a client of a local database,
+    // a pool of two sockets to a remote HTTPS service, and a connection
+    // between two ephemeral ports.
+    create_module(module_dir.path(), "conns", r#"
+cat <<'JSON'
+{"dependencies": [
+  {"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.8", "local_port": 5432, "remote_port": 51000, "description": "", "protocol": "TCP", "direction": "inbound", "service_port": 5432},
+  {"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40000, "remote_port": 443, "description": "", "protocol": "TCP", "direction": "outbound", "service_port": 443},
+  {"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40001, "remote_port": 443, "description": "", "protocol": "TCP", "direction": "outbound", "service_port": 443},
+  {"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.7", "local_port": 50000, "remote_port": 45000, "description": "", "protocol": "TCP", "direction": "unknown", "service_port": 45000}
+], "changed": true, "failed": false}
+JSON
+"#);
+    let (url, requests) = spawn_http_stub(200).await;
+    let config = engine_config(&url, &module_dir, "      conns:\n        interval: 60");
+
+    let mut engine = CollectionEngine::new(config);
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    let requests = requests.lock().unwrap();
+    let batch: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    let dependencies = batch["Dependencies"].as_array().unwrap();
+    let by_remote = |remote_ip: &str| dependencies.iter().find(|d| d["RemoteIp"] == remote_ip).unwrap();
+    assert_eq!(dependencies.len(), 3);
+
+    let inbound = by_remote("10.0.0.8");
+    assert_eq!(inbound["Direction"], "inbound");
+    assert_eq!(inbound["ServicePort"], 5432);
+
+    // The pool collapses on the service port, whatever the client ports.
+    let outbound = by_remote("10.0.0.9");
+    assert_eq!(outbound["Direction"], "outbound");
+    assert_eq!(outbound["ServicePort"], 443);
+    assert_eq!(outbound["Count"], 2);
+
+    let unknown = by_remote("10.0.0.7");
+    assert_eq!(unknown["Direction"], "unknown");
+    assert_eq!(unknown["ServicePort"], 45000);
+}
@@ -0,0 +1,128 @@
+use agent::config::Config;
+use agent::identity::{generate_id, AgentIdentity, AGENT_VERSION};
+use agent::CollectionEngine;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+use crate::common::{create_module, run_engine_for, spawn_http_stub, spawn_http_stub_with_body, StubRequest};
+
+fn is_uuid(id: &str) -> bool {
+    let groups: Vec<&str> = id.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups.iter().all(|group| group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// A config with one module that reports a single dependency.
+fn config(module_dir: &Path, server: &str, state_dir: Option<&Path>) -> Config {
+    create_module(module_dir, "conns", r#"
+echo '{"dependencies": [{"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40000, "remote_port": 5432, "description": "TCP connection", "protocol": "TCP", "direction": "outbound", "service_port": 5432}], "changed": true, "failed": false}'
+"#);
+    let state_dir = state_dir.map(|dir| format!(", state_dir: \"{}\"", dir.display())).unwrap_or_default();
+    serde_yaml::from_str(&format!(r#"
+server: {}
+agent: {{module_paths: ["{}"], log_level: info{}}}
+modules: {{conns: {{interval: 60}}}}
+"#, server, module_dir.display(), state_dir)).unwrap()
+}
+
+fn json(request: &StubRequest) -> Value {
+    serde_json::from_slice(&request.body).unwrap()
+}
+
+#[test]
+fn test_agent_id_survives_restarts() {
+    let state_dir = TempDir::new().unwrap();
+    let first = AgentIdentity::load(Some(state_dir.path())).unwrap();
+    assert!(is_uuid(&first.agent_id), "{}", first.agent_id);
+    assert_eq!(first.agent_version, AGENT_VERSION);
+    assert!(!first.hostname.is_empty());
+    assert!(first.addresses.iter().all(|a| a.address != "127.0.0.1" && a.address != "::1"));
+
+    let second = AgentIdentity::load(Some(state_dir.path())).unwrap();
+    assert_eq!(second.agent_id, first.agent_id);
+    let saved = fs::read_to_string(state_dir.path().join("agent_id")).unwrap();
+    assert_eq!(saved.trim(), first.agent_id);
+
+    fs::write(state_dir.path().join("agent_id"), "\n").unwrap();
+    assert!(AgentIdentity::load(Some(state_dir.path())).unwrap_err().to_string().contains("is empty"));
+
+    assert!(is_uuid(&generate_id()));
+    assert_ne!(generate_id(), generate_id());
+}
+
+#[tokio::test]
+async fn test_batches_carry_agent_identity() {
+    let module_dir = TempDir::new().unwrap();
+    let (url, requests) = spawn_http_stub(200).await;
+    let mut engine = CollectionEngine::new(config(module_dir.path(), &format!("{{url: \"{}\", timeout: 5}}", url), None));
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let body = json(&requests[0]);
+    // The batch itself is unchanged, the identity sits next to it.
+    assert_eq!(body["Module"], "conns");
+    assert_eq!(body["Dependencies"][0]["RemoteIp"], "10.0.0.9");
+    assert_eq!(body["Agent"]["AgentId"], engine.identity().unwrap().agent_id.as_str());
+    assert_eq!(body["Agent"]["Hostname"], engine.identity().unwrap().hostname.as_str());
+    assert_eq!(body["Agent"]["AgentVersion"], AGENT_VERSION);
+    assert!(body["Agent"]["Addresses"].is_array());
+}
+
+#[tokio::test]
+async fn test_enrollment_adopts_server_assigned_id() {
+    let module_dir = TempDir::new().unwrap();
+    let state_dir = TempDir::new().unwrap();
+    let (url, requests) = spawn_http_stub_with_body(200, r#"{"AgentId": "host-42"}"#).await;
+    let server = format!("{{url: \"{0}/api\", enroll_url: \"{0}/enroll\", timeout: 5}}", url);
+    let mut engine = CollectionEngine::with_state(config(module_dir.path(), &server, Some(state_dir.path()))).unwrap();
+    let generated = engine.identity().unwrap().agent_id.clone();
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    let requests = requests.lock().unwrap();
+    let paths: Vec<&str> = requests.iter().map(|request| request.path.as_str()).collect();
+    assert_eq!(paths, ["/enroll", "/api"]);
+    let enrollment = json(&requests[0]);
+    assert_eq!(enrollment["AgentId"], generated.as_str());
+    assert_eq!(enrollment["AgentVersion"], AGENT_VERSION);
+    assert_eq!(json(&requests[1])["Agent"]["AgentId"], "host-42");
+
+    assert_eq!(engine.identity().unwrap().agent_id, "host-42");
+    let saved = fs::read_to_string(state_dir.path().join("agent_id")).unwrap();
+    assert_eq!(saved.trim(), "host-42");
+}
+
+#[tokio::test]
+async fn test_enrollment_refuses_implausible_ids() {
+    let long_id: &'static str = format!(r#"{{"AgentId": "{}"}}"#, "x".repeat(1000)).leak();
+    for body in [r#"{"AgentId": "host\n42"}"#, r#"{"AgentId": "host 42"}"#, long_id] {
+        let module_dir = TempDir::new().unwrap();
+        let state_dir = TempDir::new().unwrap();
+        let (url, _requests) = spawn_http_stub_with_body(200, body).await;
+        let server = format!("{{url: \"{0}/api\", enroll_url: \"{0}/enroll\", timeout: 5}}", url);
+        let mut engine =
+            CollectionEngine::with_state(config(module_dir.path(), &server, Some(state_dir.path()))).unwrap();
+        let generated = engine.identity().unwrap().agent_id.clone();
+        run_engine_for(&mut engine, Duration::from_millis(500)).await;
+
+        assert_eq!(engine.identity().unwrap().agent_id, generated, "{}", body);
+        let saved = fs::read_to_string(state_dir.path().join("agent_id")).unwrap();
+        assert_eq!(saved.trim(), generated);
+    }
+}
+
+#[tokio::test]
+async fn test_failed_enrollment_does_not_hold_back_batches() {
+    let module_dir = TempDir::new().unwrap();
+    let (url, requests) = spawn_http_stub(503).await;
+    let (upload_url, uploads) = spawn_http_stub(200).await;
+    let server = format!("{{url: \"{}\", enroll_url: \"{}/enroll\", timeout: 5}}", upload_url, url);
+    let mut engine = CollectionEngine::new(config(module_dir.path(), &server, None));
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    // Tried at startup and again before the upload.
+    assert_eq!(requests.lock().unwrap().len(), 2);
+    assert_eq!(uploads.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,147 @@
+use agent::collect::collect_batch;
+use agent::config::Config;
+use agent::engine::BatchKind;
+use agent::health::HealthStatus;
+use agent::integrity::sha256_file;
+use agent::validate::{validate, Severity};
+use base64::Engine as _;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tempfile::TempDir;
+use crate::common::create_module;
+
+const OUTPUT: &str = "echo '{\"dependencies\": [], \"changed\": true, \"failed\": false}'\n";
+
+fn config(module_dir: &Path, integrity: &str, modules: &str) -> Config {
+    serde_yaml::from_str(&format!(r#"
+server: {{url: "http://127.0.0.1:9", timeout: 5}}
+agent:
+  module_paths: ["{}"]
+  log_level: info
+  integrity: {{{}}}
+modules: {}
+"#, module_dir.display(), integrity, modules)).unwrap()
+}
+
+async fn status(config: &Config, name: &str) -> Option<HealthStatus> {
+    let batch = collect_batch(config, name, None).await;
+    match batch.kind {
+        BatchKind::Full => None,
+        _ => Some(batch.health.unwrap().status),
+    }
+}
+
+fn sign(key: &PKey<Private>, content: &[u8]) -> Vec<u8> {
+    Signer::new_without_digest(key).unwrap().sign_oneshot_to_vec(content).unwrap()
+}
+
+#[tokio::test]
+async fn test_pinned_hash_must_match() {
+    let dir = TempDir::new().unwrap();
+    let path = create_module(dir.path(), "app.http", OUTPUT);
+    let hash = sha256_file(&path).unwrap();
+
+    let pinned = config(dir.path(), "", &format!("{{app.http: {{sha256: {}}}}}", hash.to_uppercase()));
+    assert_eq!(status(&pinned, "app.http").await, None);
+
+    fs::write(&path, format!("#!/bin/bash\n{}", OUTPUT.replace("true", "false"))).unwrap();
+    let batch = collect_batch(&pinned, "app.http", None).await;
+    let health = batch.health.unwrap();
+    assert_eq!(health.status, HealthStatus::Untrusted);
+    assert!(health.message.unwrap().contains(&format!("but the config pins {}", hash.to_uppercase())));
+}
+
+#[tokio::test]
+async fn test_signed_manifest_lists_allowed_modules() {
+    let dir = TempDir::new().unwrap();
+    let keys = TempDir::new().unwrap();
+    let listed = create_module(dir.path(), "app.http", OUTPUT);
+    create_module(dir.path(), "app.extra", OUTPUT);
+    let key = PKey::generate_ed25519().unwrap();
+    let manifest = format!("# generated by sha256sum\n{}  ./app/http\n", sha256_file(&listed).unwrap());
+    fs::write(keys.path().join("modules.sha256"), &manifest).unwrap();
+    fs::write(keys.path().join("modules.sha256.sig"), sign(&key, manifest.as_bytes())).unwrap();
+    fs::write(keys.path().join("modules.pub"), key.public_key_to_pem().unwrap()).unwrap();
+    let integrity = format!(
+        "manifest_file: \"{0}/modules.sha256\", public_key_file: \"{0}/modules.pub\"",
+        keys.path().display()
+    );
+    let config = config(dir.path(), &integrity, "{app.http: {}, app.extra: {}}");
+
+    assert_eq!(status(&config, "app.http").await, None);
+    assert_eq!(status(&config, "app.extra").await, Some(HealthStatus::Untrusted));
+    let issues = validate(&config);
+    assert!(issues.iter().any(|issue| issue.key == "modules.app.extra"
+        && issue.severity == Severity::Warning
+        && issue.message == "module 'app.extra' is not in the hash manifest"));
+
+    // A base64 signature works as well as a raw one.
+    let signature = base64::engine::general_purpose::STANDARD.encode(sign(&key, manifest.as_bytes()));
+    fs::write(keys.path().join("modules.sha256.sig"), signature + "\n").unwrap();
+    assert_eq!(status(&config, "app.http").await, None);
+
+    // Whoever can replace the key can sign any manifest.
+    let key_file = keys.path().join("modules.pub");
+    fs::set_permissions(&key_file, fs::Permissions::from_mode(0o666)).unwrap();
+    assert_eq!(status(&config, "app.http").await, Some(HealthStatus::Untrusted));
+    let issue = validate(&config).into_iter().find(|issue| issue.key == "agent.integrity.manifest_file").unwrap();
+    assert!(issue.message.contains("modules.pub is writable by everyone"), "{}", issue.message);
+    fs::set_permissions(&key_file, fs::Permissions::from_mode(0o644)).unwrap();
+
+    // Nothing runs once the manifest has been tampered with.
+    fs::write(keys.path().join("modules.sha256"), manifest + &format!("{}  app/extra\n", "0".repeat(64))).unwrap();
+    assert_eq!(status(&config, "app.http").await, Some(HealthStatus::Untrusted));
+    let issue = validate(&config).into_iter().find(|issue| issue.key == "agent.integrity.manifest_file").unwrap();
+    assert!(issue.message.ends_with("signature does not match"), "{}", issue.message);
+}
+
+#[tokio::test]
+async fn test_writable_or_foreign_modules_are_refused() {
+    let dir = TempDir::new().unwrap();
+    let path = create_module(dir.path(), "app.http", OUTPUT);
+    let trusting = config(dir.path(), "", "{app.http: {}}");
+    assert_eq!(status(&trusting, "app.http").await, None);
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o777)).unwrap();
+    let batch = collect_batch(&trusting, "app.http", None).await;
+    assert!(batch.health.unwrap().message.unwrap().contains("is writable by everyone (mode 777)"));
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    // Anyone could replace the module in a world-writable directory, unless
+    // it is sticky like /tmp.
+    fs::set_permissions(path.parent().unwrap(), fs::Permissions::from_mode(0o777)).unwrap();
+    assert_eq!(status(&trusting, "app.http").await, Some(HealthStatus::Untrusted));
+    fs::set_permissions(path.parent().unwrap(), fs::Permissions::from_mode(0o1777)).unwrap();
+    assert_eq!(status(&trusting, "app.http").await, None);
+
+    let strangers = config(dir.path(), "trusted_owners: [\"4242\"]", "{app.http: {}}");
+    let batch = collect_batch(&strangers, "app.http", None).await;
+    assert!(batch.health.unwrap().message.unwrap().contains("which is not a trusted owner"));
+}
+
+#[tokio::test]
+async fn test_group_writable_modules_need_a_trusted_group() {
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipping: handing a directory to another group needs root");
+        return;
+    }
+    let dir = TempDir::new().unwrap();
+    create_module(dir.path(), "app.http", OUTPUT);
+    std::os::unix::fs::chown(dir.path(), None, Some(4242)).unwrap();
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o775)).unwrap();
+
+    let default_groups = config(dir.path(), "", "{app.http: {}}");
+    let batch = collect_batch(&default_groups, "app.http", None).await;
+    let message = batch.health.unwrap().message.unwrap();
+    assert!(message.contains("is writable by gid 4242 (mode 775), which is not a trusted group"), "{}", message);
+
+    let trusting = config(dir.path(), "trusted_groups: [\"0\", \"4242\"]", "{app.http: {}}");
+    assert_eq!(status(&trusting, "app.http").await, None);
+
+    // Without group write access the group does not matter.
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(status(&default_groups, "app.http").await, None);
+}
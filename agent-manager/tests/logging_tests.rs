@@ -0,0 +1,53 @@
+use agent::config::AgentConfig;
+use agent::logging::{filter_spec, format_json, RotatingFile};
+use serde_json::Value;
+use std::io::Write;
+use tempfile::TempDir;
+
+#[test]
+fn test_filter_spec_adds_per_module_directives() {
+    let mut agent = AgentConfig {
+        log_level: "warn".to_string(),
+        ..Default::default()
+    };
+    agent.log.modules.insert("std.connections".to_string(), "debug".to_string());
+    agent.log.modules.insert("aws".to_string(), "error".to_string());
+
+    assert_eq!(filter_spec(&agent), "warn,module::aws=error,module::std.connections=debug");
+}
+
+#[test]
+fn test_json_format_is_one_object_per_record() {
+    let line = format_json(
+        &log::Record::builder()
+            .args(format_args!("Module 'x' timed out"))
+            .level(log::Level::Warn)
+            .target("module::x")
+            .build(),
+    );
+
+    let record: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(record["level"], "WARN");
+    assert_eq!(record["target"], "module::x");
+    assert_eq!(record["message"], "Module 'x' timed out");
+    assert!(record["timestamp"].is_string());
+    assert!(!line.contains('\n'));
+}
+
+#[test]
+fn test_log_file_rotates_and_keeps_max_files() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("logs/agent.log");
+    let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+
+    for i in 0..4 {
+        file.write_all(format!("record number {}\n", i).as_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+
+    let read = |name: &str| std::fs::read_to_string(dir.path().join("logs").join(name)).unwrap();
+    assert_eq!(read("agent.log"), "record number 3\n");
+    assert_eq!(read("agent.log.1"), "record number 2\n");
+    assert_eq!(read("agent.log.2"), "record number 1\n");
+    assert!(!dir.path().join("logs/agent.log.3").exists());
+}
@@ -1,5 +1,29 @@
+mod aggregate_tests;
+mod collect_tests;
 mod config_tests;
+#[cfg(unix)]
+mod daemon_tests;
+mod delta_tests;
 mod engine_tests;
+mod identity_tests;
+#[cfg(unix)]
+mod integrity_tests;
+mod logging_tests;
+mod modules_tests;
+mod outbox_tests;
+#[cfg(unix)]
+mod relay_tests;
+mod reload_tests;
+#[cfg(unix)]
+mod sandbox_tests;
+mod scheduler_tests;
+mod spec_tests;
+mod status_tests;
+#[cfg(unix)]
+mod systemd_tests;
+#[cfg(unix)]
+mod transport_tests;
+mod validate_tests;
 pub(crate) mod common;
 
 
@@ -0,0 +1,149 @@
+use agent::collect::{collect_batch, OutputFormat};
+use agent::config::{Config, ConfigLoader, ConfigOverrides, ConfigSource};
+use agent::engine::BatchKind;
+use agent::modules::{discover, find, probe, render_info, render_list};
+use agent::validate::{validate_loaded, Severity};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use crate::common::create_module;
+
+const DESCRIBE: &str = r#"
+if [ "$1" = "--describe" ]; then
+  echo '{"name": "app.http", "version": "2.1.0", "os": ["linux"], "outputs": ["dependencies"], "spec": {"input_parameters": [{"name": "port", "type": "Integer", "required": true}]}}'
+  exit 0
+fi
+echo '{"dependencies": [], "changed": true, "failed": false}'
+"#;
+
+fn config(module_paths: &[&Path], modules: &str) -> Config {
+    let paths: Vec<String> = module_paths.iter().map(|path| format!("\"{}\"", path.display())).collect();
+    serde_yaml::from_str(&format!(r#"
+server: {{url: "http://127.0.0.1:9", timeout: 5}}
+agent: {{module_paths: [{}], log_level: info}}
+modules: {}
+"#, paths.join(", "), modules)).unwrap()
+}
+
+#[test]
+fn test_discovery_reports_shadowed_modules() {
+    let first = TempDir::new().unwrap();
+    let second = TempDir::new().unwrap();
+    create_module(first.path(), "std.connections", "echo '{}'\n");
+    create_module(second.path(), "std.connections", "echo '{}'\n");
+    create_module(second.path(), "app.http", "echo '{}'\n");
+    fs::write(first.path().join("std/connections.yaml"), "name: std.connections\nversion: 1.0.0\nos: [linux]\n").unwrap();
+    fs::write(second.path().join("README"), "not a module").unwrap();
+    fs::write(second.path().join("app/http.yaml"), "version: [oops").unwrap();
+
+    let config = config(&[first.path(), second.path()], "{std.connections: {}}");
+    let modules = discover(&config);
+    let names: Vec<&str> = modules.iter().map(|module| module.name.as_str()).collect();
+    assert_eq!(names, ["app.http", "std.connections"]);
+
+    let connections = &modules[1];
+    assert_eq!(connections.path, first.path().join("std/connections"));
+    assert_eq!(connections.shadowed, [second.path().join("std/connections")]);
+    assert!(connections.configured);
+    assert_eq!(connections.manifest.as_ref().unwrap().version.as_deref(), Some("1.0.0"));
+    assert!(modules[0].manifest_error.as_ref().unwrap().contains("http.yaml"));
+
+    let table = render_list(&modules, OutputFormat::Table).unwrap();
+    assert!(table.starts_with("NAME "), "{}", table);
+    assert!(table.contains(&format!(
+        "warning: {} is shadowed by {}",
+        second.path().join("std/connections").display(),
+        first.path().join("std/connections").display()
+    )));
+    let json: serde_json::Value = serde_json::from_str(&render_list(&modules, OutputFormat::Json).unwrap()).unwrap();
+    assert_eq!(json[1]["manifest"]["os"][0], "linux");
+}
+
+#[tokio::test]
+async fn test_modules_without_manifest_are_probed() {
+    let dir = TempDir::new().unwrap();
+    create_module(dir.path(), "app.http", DESCRIBE);
+    create_module(dir.path(), "app.plain", "echo '{\"dependencies\": [], \"changed\": true, \"failed\": false}'\n");
+    let config = config(&[dir.path()], "{}");
+
+    let mut modules = discover(&config);
+    assert!(modules.iter().all(|module| module.manifest.is_none()));
+    probe(&config, &mut modules).await;
+    let manifest = modules[0].manifest.as_ref().unwrap();
+    assert_eq!(manifest.version.as_deref(), Some("2.1.0"));
+    assert_eq!(manifest.outputs, ["dependencies"]);
+    // A module that ignores --describe is not mistaken for one that answers.
+    assert!(modules[1].manifest.is_none());
+    assert!(modules[1].manifest_error.as_ref().unwrap().contains("does not answer --describe"));
+
+    let info = render_info(&modules[0], OutputFormat::Table).unwrap();
+    assert!(info.contains("version:       2.1.0"), "{}", info);
+    assert!(info.contains("port  Integer  yes"), "{}", info);
+    assert!(find(&config, "app.missing").is_err());
+}
+
+#[test]
+fn test_check_config_warns_about_unsupported_os() {
+    let dir = TempDir::new().unwrap();
+    create_module(dir.path(), "win.services", "echo '{}'\n");
+    fs::write(dir.path().join("win/services.yaml"), "os: [windows, plan9]\n").unwrap();
+    let content = format!(r#"
+server: {{url: "http://127.0.0.1:9", timeout: 5}}
+agent: {{module_paths: ["{}"], log_level: info}}
+modules: {{win.services: {{}}}}
+"#, dir.path().display());
+    let loaded = ConfigLoader::new(ConfigOverrides::default())
+        .load_str(&content, PathBuf::from("agent.yaml"), ConfigSource::Cli("config".to_string()))
+        .unwrap();
+
+    let issue = validate_loaded(&loaded)
+        .into_iter()
+        .find(|issue| issue.key == "modules.win.services")
+        .unwrap();
+    assert_eq!(issue.severity, Severity::Warning);
+    assert!(issue.message.starts_with("module supports windows, plan9 only"), "{}", issue.message);
+}
+
+#[tokio::test]
+async fn test_runs_the_module_that_discovery_lists() {
+    let first = TempDir::new().unwrap();
+    let second = TempDir::new().unwrap();
+    // Not executable, so neither listed nor run.
+    fs::create_dir(first.path().join("app")).unwrap();
+    fs::write(first.path().join("app/http"), "#!/bin/bash\nexit 1\n").unwrap();
+    create_module(second.path(), "app.http", "echo '{\"dependencies\": [], \"changed\": true, \"failed\": false}'\n");
+    let config = config(&[first.path(), second.path()], "{app.http: {}}");
+
+    let modules = discover(&config);
+    assert_eq!(modules.len(), 1);
+    assert_eq!(modules[0].path, second.path().join("app/http"));
+    assert!(modules[0].shadowed.is_empty());
+    assert_eq!(collect_batch(&config, "app.http", None).await.kind, BatchKind::Full);
+}
+
+#[test]
+fn test_manifest_must_name_its_module() {
+    let dir = TempDir::new().unwrap();
+    create_module(dir.path(), "app.http", "echo '{}'\n");
+    fs::write(dir.path().join("app/http.yaml"), "name: std.connections\nversion: 1.0.0\n").unwrap();
+    let content = format!(r#"
+server: {{url: "http://127.0.0.1:9", timeout: 5}}
+agent: {{module_paths: ["{}"], log_level: info}}
+modules: {{app.http: {{}}}}
+"#, dir.path().display());
+    let loaded = ConfigLoader::new(ConfigOverrides::default())
+        .load_str(&content, PathBuf::from("agent.yaml"), ConfigSource::Cli("config".to_string()))
+        .unwrap();
+
+    let modules = discover(&loaded.config);
+    assert!(modules[0].manifest.is_none());
+    let error = modules[0].manifest_error.as_deref().unwrap();
+    assert!(error.contains("manifest of module 'app.http' names module 'std.connections'"), "{}", error);
+
+    let issue = validate_loaded(&loaded)
+        .into_iter()
+        .find(|issue| issue.key == "modules.app.http")
+        .unwrap();
+    assert_eq!(issue.severity, Severity::Error);
+    assert!(issue.message.contains("names module 'std.connections'"), "{}", issue.message);
+}
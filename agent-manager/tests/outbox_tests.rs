@@ -0,0 +1,129 @@
+use agent::config::{Config, OutboxConfig};
+use agent::outbox::Outbox;
+use agent::CollectionEngine;
+use std::fs;
+use tempfile::TempDir;
+use crate::common::{spawn_http_stub, spawn_http_stub_with};
+
+fn engine_config(url: &str, state_dir: &TempDir) -> Config {
+    let config_content = format!(r#"
+    server:
+      url: "{}"
+      timeout: 5
+    agent:
+      module_paths: []
+      log_level: "info"
+      state_dir: "{}"
+      outbox:
+        retry_initial: 0
+    modules: {{}}
+    "#, url, state_dir.path().display());
+    serde_yaml::from_str(&config_content).unwrap()
+}
+
+#[test]
+fn test_outbox_preserves_order() {
+    let dir = TempDir::new().unwrap();
+    let mut outbox = Outbox::open(dir.path(), OutboxConfig::default()).unwrap();
+
+    outbox.push(b"[1]").unwrap();
+    outbox.push(b"[2]").unwrap();
+    outbox.push(b"[3]").unwrap();
+
+    let bodies: Vec<String> = outbox.entries().unwrap()
+        .iter()
+        .map(|entry| fs::read_to_string(entry).unwrap())
+        .collect();
+    assert_eq!(bodies, vec!["[1]", "[2]", "[3]"]);
+}
+
+#[test]
+fn test_outbox_resumes_sequence_after_reopen() {
+    let dir = TempDir::new().unwrap();
+    let mut outbox = Outbox::open(dir.path(), OutboxConfig::default()).unwrap();
+    outbox.push(b"[1]").unwrap();
+    drop(outbox);
+
+    let mut reopened = Outbox::open(dir.path(), OutboxConfig::default()).unwrap();
+    reopened.push(b"[2]").unwrap();
+
+    let entries = reopened.entries().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(fs::read_to_string(&entries[1]).unwrap(), "[2]");
+}
+
+#[test]
+fn test_outbox_drops_oldest_over_size_cap() {
+    let dir = TempDir::new().unwrap();
+    let config = OutboxConfig { max_bytes: 10, ..OutboxConfig::default() };
+    let mut outbox = Outbox::open(dir.path(), config).unwrap();
+
+    outbox.push(b"[\"aaaa\"]").unwrap();
+    outbox.push(b"[\"bbbb\"]").unwrap();
+
+    let entries = outbox.entries().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(fs::read_to_string(&entries[0]).unwrap(), "[\"bbbb\"]");
+}
+
+#[test]
+fn test_outbox_backoff() {
+    let dir = TempDir::new().unwrap();
+    let config = OutboxConfig { retry_initial: 60, ..OutboxConfig::default() };
+    let mut outbox = Outbox::open(dir.path(), config).unwrap();
+
+    assert!(outbox.ready());
+    outbox.record_failure();
+    assert!(!outbox.ready());
+    outbox.record_success();
+    assert!(outbox.ready());
+}
+
+#[tokio::test]
+async fn test_engine_drains_outbox_in_order_once_server_is_back() {
+    let state_dir = TempDir::new().unwrap();
+    let mut outbox = Outbox::open(state_dir.path().join("outbox"), OutboxConfig::default()).unwrap();
+    outbox.push(b"[\"first\"]").unwrap();
+    outbox.push(b"[\"second\"]").unwrap();
+
+    // Nothing listens on the discard port, so delivery fails and the batches stay queued.
+    let mut engine = CollectionEngine::with_state(engine_config("http://127.0.0.1:9", &state_dir)).unwrap();
+    assert!(engine.flush_outbox().await.is_err());
+    assert_eq!(engine.outbox().unwrap().len().unwrap(), 2);
+
+    let (url, requests) = spawn_http_stub(200).await;
+    let mut engine = CollectionEngine::with_state(engine_config(&url, &state_dir)).unwrap();
+    engine.flush_outbox().await.unwrap();
+
+    assert!(engine.outbox().unwrap().is_empty().unwrap());
+    let bodies: Vec<Vec<u8>> = requests.lock().unwrap().iter().map(|r| r.body.clone()).collect();
+    assert_eq!(bodies, vec![b"[\"first\"]".to_vec(), b"[\"second\"]".to_vec()]);
+}
+
+#[tokio::test]
+async fn test_engine_sets_aside_batches_the_server_refuses() {
+    let state_dir = TempDir::new().unwrap();
+    let mut outbox = Outbox::open(state_dir.path().join("outbox"), OutboxConfig::default()).unwrap();
+    outbox.push(b"[\"first\"]").unwrap();
+    let refused = outbox.push(b"[\"malformed\"]").unwrap();
+    outbox.push(b"[\"third\"]").unwrap();
+
+    // A server error is worth retrying, so everything stays queued.
+    let (url, _) = spawn_http_stub(503).await;
+    let mut engine = CollectionEngine::with_state(engine_config(&url, &state_dir)).unwrap();
+    assert!(engine.flush_outbox().await.is_err());
+    assert_eq!(engine.outbox().unwrap().len().unwrap(), 3);
+
+    let (url, requests) = spawn_http_stub_with(|request| {
+        if request.body == b"[\"malformed\"]" { (400, "") } else { (200, "") }
+    })
+    .await;
+    let mut engine = CollectionEngine::with_state(engine_config(&url, &state_dir)).unwrap();
+    engine.flush_outbox().await.unwrap();
+
+    assert!(engine.outbox().unwrap().is_empty().unwrap());
+    let bodies: Vec<Vec<u8>> = requests.lock().unwrap().iter().map(|r| r.body.clone()).collect();
+    assert_eq!(bodies, vec![b"[\"first\"]".to_vec(), b"[\"malformed\"]".to_vec(), b"[\"third\"]".to_vec()]);
+    let set_aside = state_dir.path().join("outbox/rejected").join(refused.file_name().unwrap());
+    assert_eq!(fs::read(set_aside).unwrap(), b"[\"malformed\"]");
+}
@@ -0,0 +1,180 @@
+use agent::config::{Config, ServerConfig};
+use agent::identity::AgentIdentity;
+use agent::relay::Relay;
+use agent::transport::Transport;
+use agent::CollectionEngine;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+use crate::common::pki::{pki, write_private};
+use crate::common::{create_module, run_engine_for, spawn_http_stub, spawn_http_stub_with_body, StubRequest};
+
+/// A relay config spooling under `state_dir` and forwarding to `upstream`.
+fn relay_config(state_dir: &Path, upstream: &str, extra: &str) -> Config {
+    serde_yaml::from_str(&format!(r#"
+server: {{url: "{0}/api", enroll_url: "{0}/enroll", timeout: 5}}
+agent:
+  module_paths: []
+  log_level: info
+  state_dir: "{1}"
+  outbox: {{retry_initial: 1, retry_max: 1}}
+  relay:
+    listen: "127.0.0.1:0"
+{2}
+modules: {{}}
+"#, upstream, state_dir.display(), extra)).unwrap()
+}
+
+/// Binds a relay and runs it in the background. Returns its address.
+async fn start_relay(config: &Config) -> (String, tokio::task::JoinHandle<()>) {
+    let identity = AgentIdentity::load(config.agent.state_dir.as_deref()).unwrap();
+    let relay = Relay::bind(config, &identity).await.unwrap();
+    let addr = relay.local_addr().unwrap();
+    (addr.to_string(), tokio::spawn(relay.run()))
+}
+
+fn downstream(url: &str, extra: &str) -> ServerConfig {
+    let config: Config = serde_yaml::from_str(&format!(r#"
+server:
+  url: "{}"
+  timeout: 5
+{}
+agent: {{module_paths: [], log_level: info}}
+modules: {{}}
+"#, url, extra)).unwrap();
+    config.server
+}
+
+fn json(request: &StubRequest) -> Value {
+    serde_json::from_slice(&request.body).unwrap()
+}
+
+fn spooled(state_dir: &Path) -> usize {
+    fs::read_dir(state_dir.join("relay"))
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "json"))
+        .count()
+}
+
+#[tokio::test]
+async fn test_relay_forwards_batches_with_sender_identity() {
+    let state_dir = TempDir::new().unwrap();
+    let (upstream, requests) = spawn_http_stub_with_body(200, r#"{"AgentId": "host-7"}"#).await;
+    let config = relay_config(state_dir.path(), &upstream, "");
+    let relay_id = AgentIdentity::load(Some(state_dir.path())).unwrap().agent_id;
+    let (relay, _handle) = start_relay(&config).await;
+
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "conns", r#"
+echo '{"dependencies": [{"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40000, "remote_port": 5432, "description": "TCP connection", "protocol": "TCP", "direction": "outbound", "service_port": 5432}], "changed": true, "failed": false}'
+"#);
+    let agent: Config = serde_yaml::from_str(&format!(r#"
+server: {{url: "http://{0}/api/dependencies", enroll_url: "http://{0}/enroll", timeout: 5}}
+agent: {{module_paths: ["{1}"], log_level: info}}
+modules: {{conns: {{interval: 60}}}}
+"#, relay, module_dir.path().display())).unwrap();
+    let mut engine = CollectionEngine::new(agent);
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // The enrollment went through to the server, which assigned an ID.
+    assert_eq!(engine.identity().unwrap().agent_id, "host-7");
+    let requests = requests.lock().unwrap();
+    let paths: Vec<&str> = requests.iter().map(|request| request.path.as_str()).collect();
+    assert_eq!(paths, ["/enroll", "/api"]);
+    assert_eq!(json(&requests[0])["Hostname"], engine.identity().unwrap().hostname.as_str());
+
+    let batch = json(&requests[1]);
+    assert_eq!(batch["Module"], "conns");
+    assert_eq!(batch["Dependencies"][0]["RemoteIp"], "10.0.0.9");
+    assert_eq!(batch["Agent"]["AgentId"], "host-7");
+    assert_eq!(batch["Relays"].as_array().unwrap().len(), 1);
+    assert_eq!(batch["Relays"][0]["AgentId"], relay_id.as_str());
+    assert_eq!(batch["Relays"][0]["ReceivedFrom"], "127.0.0.1");
+    assert_eq!(spooled(state_dir.path()), 0);
+}
+
+#[tokio::test]
+async fn test_relay_spools_until_upstream_is_back() {
+    let state_dir = TempDir::new().unwrap();
+    let (down, failed) = spawn_http_stub(503).await;
+    let (relay, handle) = start_relay(&relay_config(state_dir.path(), &down, "")).await;
+
+    let transport = Transport::new(&downstream(&format!("http://{}/", relay), "")).unwrap();
+    for module in ["first", "second"] {
+        let batch = format!(r#"{{"Agent": {{"AgentId": "a-1"}}, "Module": "{}"}}"#, module);
+        transport.send(batch.into_bytes()).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!failed.lock().unwrap().is_empty());
+    assert_eq!(spooled(state_dir.path()), 2);
+
+    // A restarted relay picks up where the last one left off.
+    handle.abort();
+    let (up, requests) = spawn_http_stub(200).await;
+    let _relay = start_relay(&relay_config(state_dir.path(), &up, "")).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    let requests = requests.lock().unwrap();
+    let modules: Vec<Value> = requests.iter().map(|request| json(request)["Module"].clone()).collect();
+    assert_eq!(modules, ["first", "second"]);
+    assert_eq!(json(&requests[0])["Agent"]["AgentId"], "a-1");
+    assert_eq!(json(&requests[0])["Relays"].as_array().unwrap().len(), 1);
+    assert_eq!(spooled(state_dir.path()), 0);
+}
+
+#[tokio::test]
+async fn test_relay_requires_tls_and_token() {
+    let pki = pki();
+    let state_dir = TempDir::new().unwrap();
+    let token = pki.dir.path().join("token");
+    write_private(&token, b"relay-token\n");
+    let (upstream, requests) = spawn_http_stub(200).await;
+    let config = relay_config(state_dir.path(), &upstream, &format!(r#"
+    tls_cert_file: "{}"
+    tls_key_file: "{}"
+    token_file: "{}"
+    max_body_bytes: 1024
+"#, pki.server_cert.display(), pki.server_key.display(), token.display()));
+    let (relay, _handle) = start_relay(&config).await;
+    let url = format!("https://{}/", relay);
+    let ca = format!("  tls: {{ca_file: \"{}\"}}", pki.ca.display());
+
+    let agent = Transport::new(&downstream(&url, &format!("{}\n  auth: {{token_file: \"{}\"}}", ca, token.display()))).unwrap();
+    agent.send(br#"{"Module": "conns"}"#.to_vec()).await.unwrap();
+
+    let error = agent.send(b"not json".to_vec()).await.unwrap_err().to_string();
+    assert!(error.contains("400"), "{}", error);
+    assert!(agent.send(vec![b' '; 4096]).await.is_err());
+    let anonymous = Transport::new(&downstream(&url, &ca)).unwrap();
+    let error = anonymous.send(br#"{"Module": "conns"}"#.to_vec()).await.unwrap_err().to_string();
+    assert!(error.contains("401"), "{}", error);
+    let plain = Transport::new(&downstream(&format!("http://{}/", relay), "")).unwrap();
+    assert!(plain.send(br#"{"Module": "conns"}"#.to_vec()).await.is_err());
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_relay_times_out_idle_connections() {
+    let state_dir = TempDir::new().unwrap();
+    let (upstream, _requests) = spawn_http_stub(200).await;
+    let config = relay_config(state_dir.path(), &upstream, "    read_timeout: 1\n    max_connections: 1");
+    let (relay, _handle) = start_relay(&config).await;
+
+    // A peer that connects and sends nothing holds the only connection slot
+    // until it times out, and only then is the next agent accepted.
+    let mut idle = tokio::net::TcpStream::connect(&relay).await.unwrap();
+    let transport = Transport::new(&downstream(&format!("http://{}/", relay), "")).unwrap();
+    let started = Instant::now();
+    transport.send(br#"{"Module": "m"}"#.to_vec()).await.unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(500));
+
+    let mut response = String::new();
+    idle.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+}
@@ -0,0 +1,97 @@
+use agent::config::{Config, ConfigLoader, ConfigOverrides};
+use agent::reload::ConfigWatcher;
+use agent::CollectionEngine;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::mpsc;
+use crate::common::{create_module, create_temp_config, spawn_http_stub};
+
+fn config_yaml(url: &str, module_dir: &TempDir, modules: &str) -> String {
+    format!(r#"
+server:
+  url: "{}"
+  timeout: 5
+agent:
+  module_paths: ["{}"]
+  log_level: "info"
+modules:
+{}
+"#, url, module_dir.path().display(), modules)
+}
+
+fn config(url: &str, module_dir: &TempDir, modules: &str) -> Config {
+    serde_yaml::from_str(&config_yaml(url, module_dir, modules)).unwrap()
+}
+
+#[tokio::test]
+async fn test_invalid_reload_keeps_current_config() {
+    let module_dir = TempDir::new().unwrap();
+    let current = config("http://localhost:1/api", &module_dir, "  a:\n    interval: 60");
+    let mut engine = CollectionEngine::new(current.clone());
+
+    let broken = config("http://localhost:1/api", &module_dir,
+        "  a:\n    interval: 60\n  b:\n    schedule: \"not a cron line\"");
+    let error = engine.apply_config(broken).await.unwrap_err();
+
+    assert!(error.to_string().contains("module 'b'"));
+    assert_eq!(engine.config(), &current);
+}
+
+#[tokio::test]
+async fn test_reload_swaps_modules_without_dropping_in_flight_runs() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "retired", r#"
+sleep 1
+echo '{"dependencies": [], "changed": true, "failed": false}'
+"#);
+    create_module(module_dir.path(), "added", r#"
+echo '{"dependencies": [], "changed": true, "failed": false}'
+"#);
+    let (url, requests) = spawn_http_stub(200).await;
+    let mut engine = CollectionEngine::new(config(&url, &module_dir, "  retired:\n    interval: 60"));
+
+    let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+    let reloaded = config(&url, &module_dir, "  added:\n    interval: 60");
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        reload_tx.send(reloaded).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+        let _ = shutdown_tx.send(()).await;
+    });
+    engine.run_with_reload(shutdown_rx, reload_rx).await.unwrap();
+
+    let mut modules: Vec<String> = requests.lock().unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice::<Value>(&request.body).unwrap()["Module"].as_str().unwrap().to_string())
+        .collect();
+    modules.sort();
+    assert_eq!(modules, vec!["added".to_string(), "retired".to_string()]);
+    assert!(!engine.config().modules.contains_key("retired"));
+}
+
+#[tokio::test]
+async fn test_watcher_reloads_changed_file_and_skips_broken_ones() {
+    let module_dir = TempDir::new().unwrap();
+    let (_dir, config_file) = create_temp_config(&config_yaml("http://localhost:1/api", &module_dir, "  a:\n    interval: 60"));
+    let overrides = ConfigOverrides { config_path: Some(config_file.clone()), ..Default::default() };
+    let loader = ConfigLoader::new(overrides).with_env(HashMap::new());
+
+    let (reload_tx, mut reload_rx) = mpsc::channel(1);
+    tokio::spawn(
+        ConfigWatcher::new(loader, config_file.clone())
+            .with_poll_interval(Duration::from_millis(50))
+            .watch(reload_tx),
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    std::fs::write(&config_file, "modules: [this is not a config").unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(reload_rx.try_recv().is_err());
+
+    std::fs::write(&config_file, config_yaml("http://localhost:1/api", &module_dir, "  a:\n    interval: 30")).unwrap();
+    let config = tokio::time::timeout(Duration::from_secs(2), reload_rx.recv()).await.unwrap().unwrap();
+    assert_eq!(config.modules["a"].interval, Some(30));
+}
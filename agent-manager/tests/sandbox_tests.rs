@@ -0,0 +1,96 @@
+use agent::collect::collect_batch;
+use agent::config::Config;
+use agent::engine::BatchKind;
+use agent::validate::{validate, Severity};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use crate::common::create_module;
+
+/// A module that reports what `probe` prints as a dependency's description.
+fn reporting_module(dir: &Path, probe: &str) {
+    create_module(dir, "sandboxed", &format!(r#"
+report="$({})"
+printf '{{"dependencies": [{{"module": "m", "local_ip": "10.0.0.1", "local_os": "Linux", "remote_ip": "10.0.0.2", "local_port": 1, "remote_port": 2, "description": "%s"}}], "changed": true, "failed": false}}\n' "$report"
+"#, probe));
+}
+
+fn config(module_dir: &Path, sandbox: &str, module: &str) -> Config {
+    serde_yaml::from_str(&format!(r#"
+server: {{url: "http://127.0.0.1:9", timeout: 5}}
+agent:
+  module_paths: ["{}"]
+  log_level: info
+  sandbox: {{{}}}
+modules:
+  sandboxed: {{args: {{x: 1}}, {}}}
+"#, module_dir.display(), sandbox, module)).unwrap()
+}
+
+async fn report(config: &Config) -> String {
+    let batch = collect_batch(config, "sandboxed", None).await;
+    assert_eq!(batch.kind, BatchKind::Full, "{:?}", batch.health);
+    batch.dependencies[0].description.clone()
+}
+
+#[tokio::test]
+async fn test_env_working_dir_and_limits() {
+    let dir = TempDir::new().unwrap();
+    let work = TempDir::new().unwrap();
+    reporting_module(dir.path(), r#"/usr/bin/env | /usr/bin/sort | /usr/bin/tr '\n' ' '; echo "|$(pwd)|$(ulimit -n)|$(ulimit -t)""#);
+    let config = config(
+        dir.path(),
+        &format!("env: [HOME, GREETING=hi, NOT_SET_ANYWHERE], working_dir: \"{}\", max_open_files: 64", work.path().display()),
+        "sandbox: {max_cpu_seconds: 7}",
+    );
+
+    let report = report(&config).await;
+    let (env, rest) = report.split_once('|').unwrap();
+    let names: Vec<&str> = env.split_whitespace().filter_map(|entry| entry.split_once('=')).map(|(name, _)| name).collect();
+    for expected in ["ARGS_FILE", "GREETING", "HOME"] {
+        assert!(names.contains(&expected), "{}", env);
+    }
+    assert!(env.contains("GREETING=hi "), "{}", env);
+    // Besides what bash sets itself, nothing else gets through.
+    assert!(names.iter().all(|name| ["ARGS_FILE", "GREETING", "HOME", "PWD", "SHLVL", "_"].contains(name)), "{}", env);
+    assert_eq!(rest, format!("{}|64|7", fs::canonicalize(work.path()).unwrap().display()));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_user_namespaces_and_seccomp() {
+    use std::os::unix::fs::PermissionsExt;
+
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipping: dropping privileges needs root");
+        return;
+    }
+    let dir = TempDir::new().unwrap();
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    reporting_module(dir.path(), r#"
+echo "$(id -u):$(id -G)|$(test -r "$ARGS_FILE" && echo readable)|$(tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' \n')|$(grep -E '^(NoNewPrivs|Seccomp):' /proc/self/status | tr -s '\t\n' '  ')"
+"#);
+    let config = config(dir.path(), "user: nobody", "sandbox: {namespaces: [network, uts], seccomp: true}");
+
+    let report = report(&config).await;
+    assert_eq!(report, "65534:65534|readable|lo|NoNewPrivs: 1 Seccomp: 2 ");
+}
+
+#[test]
+fn test_check_config_reports_sandbox_problems() {
+    let dir = TempDir::new().unwrap();
+    reporting_module(dir.path(), "true");
+    let config = config(dir.path(), "user: no-such-user-here", "sandbox: {max_open_files: 0}");
+    let issues = validate(&config);
+    let issue = issues.iter().find(|issue| issue.key == "agent.sandbox").unwrap();
+    assert_eq!(issue.message, "unknown user 'no-such-user-here'");
+    // Module settings are only checked once the agent's are fine.
+    assert!(!issues.iter().any(|issue| issue.key == "modules.sandboxed.sandbox"));
+
+    let config = self::config(dir.path(), "working_dir: /no/such/dir", "sandbox: {max_open_files: 0}");
+    let issues = validate(&config);
+    let issue = issues.iter().find(|issue| issue.key == "modules.sandboxed.sandbox").unwrap();
+    assert_eq!((issue.severity, issue.message.as_str()), (Severity::Error, "resource limits must be greater than 0"));
+    let issue = issues.iter().find(|issue| issue.key == "agent.sandbox.working_dir").unwrap();
+    assert_eq!(issue.severity, Severity::Warning);
+}
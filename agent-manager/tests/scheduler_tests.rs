@@ -0,0 +1,90 @@
+use agent::config::{AgentConfig, ModuleConfig};
+use agent::scheduler::{ModuleSchedule, Trigger};
+use std::time::{Duration, Instant};
+
+fn agent_config() -> AgentConfig {
+    AgentConfig {
+        default_interval: 120,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_module_without_interval_uses_default_interval() {
+    let trigger = Trigger::for_module(&ModuleConfig::default(), &agent_config()).unwrap();
+    assert!(matches!(trigger, Trigger::Interval(interval) if interval == Duration::from_secs(120)));
+}
+
+#[test]
+fn test_interval_module_runs_at_startup_and_stays_on_grid() {
+    let now = Instant::now();
+    let module = ModuleConfig { interval: Some(10), ..Default::default() };
+    let mut schedule = ModuleSchedule::for_module(&module, &agent_config(), now).unwrap();
+    assert!(schedule.is_due(now));
+
+    // A run that starts late does not shift the following runs.
+    schedule.advance(now + Duration::from_secs(3));
+    assert_eq!(schedule.next_run, now + Duration::from_secs(10));
+}
+
+#[test]
+fn test_run_at_startup_can_be_disabled() {
+    let now = Instant::now();
+    let module = ModuleConfig { interval: Some(10), run_at_startup: Some(false), ..Default::default() };
+    let schedule = ModuleSchedule::for_module(&module, &agent_config(), now).unwrap();
+    assert_eq!(schedule.next_run, now + Duration::from_secs(10));
+}
+
+#[test]
+fn test_cron_module_waits_for_first_match() {
+    let now = Instant::now();
+    let module = ModuleConfig {
+        schedule: Some("0 0 0 1 1 * 2099".to_string()),
+        interval: Some(10),
+        ..Default::default()
+    };
+    let schedule = ModuleSchedule::for_module(&module, &agent_config(), now).unwrap();
+    assert!(!schedule.is_due(now + Duration::from_secs(60)));
+}
+
+#[test]
+fn test_cron_module_advances_to_next_match() {
+    let now = Instant::now();
+    let module = ModuleConfig { schedule: Some("* * * * * *".to_string()), ..Default::default() };
+    let mut schedule = ModuleSchedule::for_module(&module, &agent_config(), now).unwrap();
+
+    schedule.advance(now);
+    assert!(schedule.next_run > now);
+    assert!(schedule.next_run <= now + Duration::from_secs(1));
+}
+
+#[test]
+fn test_invalid_cron_expression_is_rejected() {
+    let module = ModuleConfig { schedule: Some("every tuesday".to_string()), ..Default::default() };
+    assert!(Trigger::for_module(&module, &agent_config()).is_err());
+}
+
+#[test]
+fn test_splay_delays_first_run_within_bound() {
+    let now = Instant::now();
+    let module = ModuleConfig { interval: Some(10), splay: Some(5), ..Default::default() };
+    for _ in 0..20 {
+        let schedule = ModuleSchedule::for_module(&module, &agent_config(), now).unwrap();
+        assert!(schedule.next_run >= now);
+        assert!(schedule.next_run < now + Duration::from_secs(5));
+    }
+}
+
+#[test]
+fn test_rescheduled_module_waits_for_new_interval_and_keeps_run_state() {
+    let now = Instant::now();
+    let module = ModuleConfig { interval: Some(10), ..Default::default() };
+    let mut previous = ModuleSchedule::for_module(&module, &agent_config(), now).unwrap();
+    previous.running = true;
+
+    let module = ModuleConfig { interval: Some(30), ..Default::default() };
+    let schedule = ModuleSchedule::rescheduled(&module, &agent_config(), &previous, now).unwrap();
+
+    assert!(schedule.running);
+    assert_eq!(schedule.next_run, now + Duration::from_secs(30));
+}
@@ -0,0 +1,192 @@
+use agent::collect::collect_batch;
+use agent::config::{Config, ConfigLoader, ConfigOverrides, ConfigSource};
+use agent::engine::BatchKind;
+use agent::health::HealthStatus;
+use agent::config::IntegrityConfig;
+use agent::spec::{ModuleSpec, SpecCache, SpecViolation};
+use agent::validate::{validate, validate_loaded};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+use crate::common::create_module;
+
+const SPEC: &str = r#"
+spec:
+  input_parameters:
+    - {name: directory, type: String, required: true}
+    - {name: depth, type: Integer}
+  return_values:
+    - {name: count, type: Integer, required: true}
+    - {name: files, type: List}
+"#;
+
+fn spec() -> ModuleSpec {
+    #[derive(Deserialize)]
+    struct SpecFile {
+        spec: ModuleSpec,
+    }
+    serde_yaml::from_str::<SpecFile>(SPEC).unwrap().spec
+}
+
+fn args(value: Value) -> HashMap<String, Value> {
+    serde_json::from_value(value).unwrap()
+}
+
+fn messages(violations: Vec<SpecViolation>) -> Vec<String> {
+    violations.into_iter().map(|violation| violation.to_string()).collect()
+}
+
+#[test]
+fn test_args_are_checked_against_spec() {
+    let spec = spec();
+    assert!(spec.check_args(Some(&args(json!({"directory": "/tmp", "depth": 2})))).is_empty());
+    assert_eq!(
+        messages(spec.check_args(Some(&args(json!({"directroy": "/tmp", "depth": "2", "verbose": true}))))),
+        [
+            "directory: is required but missing",
+            "depth: expected Integer, got a string",
+            "directroy: unknown argument, did you mean 'directory'?",
+            "verbose: unknown argument",
+        ]
+    );
+    assert_eq!(messages(spec.check_args(None)), ["directory: is required but missing"]);
+
+    // Without input_parameters, args are not checked at all.
+    let outputs_only = ModuleSpec { input_parameters: None, ..spec };
+    assert!(outputs_only.check_args(Some(&args(json!({"anything": 1})))).is_empty());
+}
+
+#[test]
+fn test_plugins_yaml_specs_accept_their_args() {
+    #[derive(Deserialize)]
+    struct Plugins {
+        plugins: Vec<Plugin>,
+    }
+    #[derive(Deserialize)]
+    struct Plugin {
+        name: String,
+        spec: ModuleSpec,
+        args: HashMap<String, Value>,
+    }
+
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/plugins.yaml");
+    let plugins: Plugins = serde_yaml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    assert_eq!(plugins.plugins.len(), 4);
+    for plugin in plugins.plugins {
+        assert!(plugin.spec.check_args(Some(&plugin.args)).is_empty(), "{}", plugin.name);
+    }
+}
+
+#[test]
+fn test_config_load_reports_args_that_do_not_fit_spec() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "files", "echo '{}'\n");
+    fs::write(module_dir.path().join("files.yaml"), SPEC).unwrap();
+    let content = format!(r#"
+server:
+  url: "http://localhost:8080/api"
+  timeout: 30
+agent:
+  module_paths: ["{}"]
+  log_level: info
+modules:
+  files:
+    args:
+      directroy: /var/log
+  inline:
+    spec:
+      input_parameters: []
+    args:
+      x: 1
+"#, module_dir.path().display());
+    let loaded = ConfigLoader::new(ConfigOverrides::default())
+        .load_str(&content, PathBuf::from("agent.yaml"), ConfigSource::Cli("config".to_string()))
+        .unwrap();
+
+    let issues: Vec<(String, String)> = validate_loaded(&loaded)
+        .into_iter()
+        .map(|issue| (issue.key, issue.message))
+        .collect();
+    for expected in [
+        ("modules.files.args.directory", "is required but missing"),
+        ("modules.files.args.directroy", "unknown argument, did you mean 'directory'?"),
+        ("modules.inline.args.x", "unknown argument"),
+    ] {
+        assert!(
+            issues.iter().any(|(key, message)| key == expected.0 && message == expected.1),
+            "missing {:?} in {:?}",
+            expected,
+            issues
+        );
+    }
+    assert!(loaded.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_output_must_match_return_values() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "good", r#"echo '{"dependencies": [], "changed": true, "failed": false, "count": 2, "files": ["a", "b"]}'"#);
+    create_module(module_dir.path(), "bad", r#"echo '{"dependencies": [], "changed": true, "failed": false, "count": "two"}'"#);
+    fs::write(module_dir.path().join("good.yaml"), SPEC).unwrap();
+    fs::write(module_dir.path().join("bad.yaml"), SPEC).unwrap();
+    let config: Config = serde_yaml::from_str(&format!(r#"
+server: {{url: "http://127.0.0.1:9", timeout: 5}}
+agent: {{module_paths: ["{}"], log_level: info}}
+modules:
+  good: {{args: {{directory: /tmp}}}}
+  bad: {{args: {{directory: /tmp}}}}
+"#, module_dir.path().display())).unwrap();
+
+    let batch = collect_batch(&config, "good", None).await;
+    assert_eq!(batch.kind, BatchKind::Full);
+
+    let batch = collect_batch(&config, "bad", None).await;
+    let health = batch.health.unwrap();
+    assert_eq!(health.status, HealthStatus::MalformedOutput);
+    assert!(health.message.unwrap().contains("count: expected Integer, got a string"));
+
+    // Args given on the command line are checked before the module runs.
+    let batch = collect_batch(&config, "good", Some(args(json!({"dir": "/tmp"})))).await;
+    let health = batch.health.unwrap();
+    assert_eq!(health.status, HealthStatus::ExecutionError);
+    assert!(health.message.unwrap().contains("directory: is required but missing"));
+}
+
+#[tokio::test]
+async fn test_spec_file_must_be_trusted_and_is_read_once() {
+    let module_dir = TempDir::new().unwrap();
+    let path = create_module(module_dir.path(), "good", r#"echo '{"dependencies": [], "changed": true, "failed": false, "count": 2}'"#);
+    let spec_file = module_dir.path().join("good.yaml");
+    fs::write(&spec_file, SPEC).unwrap();
+    let integrity = IntegrityConfig::default();
+    let module = Default::default();
+
+    let specs = SpecCache::default();
+    assert_eq!(specs.get("good", &path, &module, &integrity).await.unwrap(), Some(spec()));
+    fs::write(&spec_file, "spec: {}\n").unwrap();
+    assert_eq!(specs.get("good", &path, &module, &integrity).await.unwrap(), Some(spec()));
+    specs.clear();
+    assert_eq!(specs.get("good", &path, &module, &integrity).await.unwrap(), Some(ModuleSpec::default()));
+
+    // Anyone who can rewrite the spec decides which args and output pass.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(&spec_file, fs::Permissions::from_mode(0o666)).unwrap();
+        let config: Config = serde_yaml::from_str(&format!(r#"
+server: {{url: "http://127.0.0.1:9", timeout: 5}}
+agent: {{module_paths: ["{}"], log_level: info}}
+modules:
+  good: {{}}
+"#, module_dir.path().display())).unwrap();
+        let health = collect_batch(&config, "good", None).await.health.unwrap();
+        assert_eq!(health.status, HealthStatus::Untrusted);
+        assert!(health.message.unwrap().contains("good.yaml is writable by everyone"));
+        let issues = validate(&config);
+        assert!(issues.iter().any(|issue| issue.key == "modules.good" && issue.message.contains("good.yaml")), "{:?}", issues);
+    }
+}
@@ -0,0 +1,162 @@
+use agent::config::Config;
+use agent::health::{HealthStatus, ModuleHealth};
+use agent::status::{AgentStats, ListenAddr, StatusServer};
+use agent::CollectionEngine;
+use chrono::Utc;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::common::{create_module, run_engine_for, spawn_http_stub};
+
+fn health(status: HealthStatus, exit_code: Option<i32>, message: Option<&str>) -> ModuleHealth {
+    ModuleHealth {
+        status,
+        message: message.map(str::to_string),
+        exit_code,
+        stderr: None,
+        checked_at: Utc::now(),
+        failing_since: None,
+        consecutive_failures: 0,
+    }
+}
+
+/// Sends a GET and returns the status line and body.
+async fn get(addr: SocketAddr, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[test]
+fn test_listen_address_must_be_local() {
+    assert_eq!(
+        ListenAddr::parse("127.0.0.1:9464"),
+        Ok(ListenAddr::Tcp("127.0.0.1:9464".parse().unwrap()))
+    );
+    assert!(ListenAddr::parse("[::1]:9464").is_ok());
+    assert_eq!(
+        ListenAddr::parse("unix:/run/dep_map.sock"),
+        Ok(ListenAddr::Unix("/run/dep_map.sock".into()))
+    );
+    assert!(ListenAddr::parse("0.0.0.0:9464").unwrap_err().contains("loopback"));
+    assert!(ListenAddr::parse("localhost").is_err());
+    assert!(ListenAddr::parse("unix:").is_err());
+}
+
+#[test]
+fn test_metrics_count_runs_and_bucket_durations() {
+    let config: Config = serde_yaml::from_str("server: {url: \"http://localhost\", timeout: 5}\nagent: {module_paths: [], log_level: info}\nmodules: {}").unwrap();
+    let mut stats = AgentStats::new(config);
+    let now = Utc::now();
+    stats.record_run("connections", now, Duration::from_millis(300), &health(HealthStatus::Ok, Some(0), None), Some(4));
+    stats.record_run("connections", now, Duration::from_secs(7), &health(HealthStatus::Ok, Some(0), None), Some(6));
+    stats.record_run(
+        "connections",
+        now,
+        Duration::from_millis(50),
+        &health(HealthStatus::NonZeroExit, Some(2), Some("exited with code 2")),
+        None,
+    );
+    stats.record_upload(true);
+    stats.record_upload(false);
+
+    let metrics = stats.render_metrics();
+    for line in [
+        "dep_map_module_runs_total{module=\"connections\",status=\"ok\"} 2",
+        "dep_map_module_runs_total{module=\"connections\",status=\"non_zero_exit\"} 1",
+        "dep_map_module_duration_seconds_bucket{module=\"connections\",le=\"0.1\"} 1",
+        "dep_map_module_duration_seconds_bucket{module=\"connections\",le=\"0.5\"} 2",
+        "dep_map_module_duration_seconds_bucket{module=\"connections\",le=\"5\"} 2",
+        "dep_map_module_duration_seconds_bucket{module=\"connections\",le=\"10\"} 3",
+        "dep_map_module_duration_seconds_bucket{module=\"connections\",le=\"+Inf\"} 3",
+        "dep_map_module_duration_seconds_count{module=\"connections\"} 3",
+        "dep_map_module_dependencies{module=\"connections\"} 6",
+        "dep_map_uploads_total{result=\"ok\"} 1",
+        "dep_map_uploads_total{result=\"error\"} 1",
+    ] {
+        assert!(metrics.lines().any(|l| l == line), "missing {:?} in:\n{}", line, metrics);
+    }
+
+    let module = &stats.modules["connections"];
+    assert_eq!(module.exit_code, Some(2));
+    assert_eq!(module.last_error.as_deref(), Some("exited with code 2"));
+}
+
+#[tokio::test]
+async fn test_status_listener_serves_engine_stats() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "good", r#"
+echo '{"dependencies": [{"module": "Connections", "local_ip": "10.0.0.5", "local_os": "Linux", "remote_ip": "10.0.0.9", "local_port": 40000, "remote_port": 5432, "description": "TCP connection", "protocol": "TCP", "direction": "outbound", "service_port": 5432}], "changed": true, "failed": false}'
+"#);
+    create_module(module_dir.path(), "bad", "exit 3\n");
+    let (url, _requests) = spawn_http_stub(200).await;
+    let config: Config = serde_yaml::from_str(&format!(r#"
+server: {{url: "{}", timeout: 5}}
+agent: {{module_paths: ["{}"], log_level: info}}
+modules: {{good: {{interval: 60, args: {{password: hunter2}}}}, bad: {{interval: 60}}}}
+"#, url, module_dir.path().display())).unwrap();
+
+    let mut engine = CollectionEngine::new(config);
+    let server = StatusServer::bind(&ListenAddr::parse("127.0.0.1:0").unwrap()).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run(engine.stats()));
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    let (status, body) = get(addr, "/status").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let stats: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(stats["modules"]["good"]["last_status"], "ok");
+    assert_eq!(stats["modules"]["good"]["dependency_count"], 1);
+    assert!(stats["modules"]["good"]["last_duration_seconds"].is_number());
+    assert_eq!(stats["modules"]["bad"]["last_status"], "non_zero_exit");
+    assert_eq!(stats["modules"]["bad"]["exit_code"], 3);
+    assert_eq!(stats["uploads"]["ok"], 2);
+
+    let (_, body) = get(addr, "/config").await;
+    let config: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(config["server"]["url"], url.as_str());
+    assert_eq!(config["modules"]["good"]["args"], "<redacted>");
+    assert!(!body.contains("hunter2"));
+
+    let (_, metrics) = get(addr, "/metrics").await;
+    assert!(metrics.contains("dep_map_module_runs_total{module=\"good\",status=\"ok\"} 1"));
+    assert!(metrics.contains("dep_map_module_runs_total{module=\"bad\",status=\"non_zero_exit\"} 1"));
+
+    assert_eq!(get(addr, "/nope").await.0, "HTTP/1.1 404 Not Found");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_status_socket_is_private_and_times_out_idle_clients() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("status.sock");
+    let listen = ListenAddr::parse(&format!("unix:{}", path.display())).unwrap();
+    let server = StatusServer::bind(&listen).await.unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let config: Config = serde_yaml::from_str(r#"
+server: {url: "http://127.0.0.1:9", timeout: 5}
+agent: {module_paths: [], log_level: info}
+modules: {}
+"#).unwrap();
+    tokio::spawn(server.run(CollectionEngine::new(config).stats()));
+
+    // A client that never sends its request is answered and let go.
+    let mut idle = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(10), idle.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+}
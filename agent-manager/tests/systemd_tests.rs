@@ -0,0 +1,100 @@
+use agent::config::Config;
+use agent::logging::JournalLogger;
+use agent::systemd::{journal_entry, unit_file, Notifier};
+use agent::CollectionEngine;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use crate::common::{create_module, run_engine_for, spawn_http_stub};
+
+/// Binds a fake NOTIFY_SOCKET and returns it with its path.
+fn notify_socket(dir: &TempDir) -> (UnixDatagram, String) {
+    let path = dir.path().join("notify.sock");
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    (socket, path.display().to_string())
+}
+
+fn received(socket: &UnixDatagram) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = socket.recv(&mut buf) {
+        messages.push(String::from_utf8_lossy(&buf[..n]).to_string());
+    }
+    messages
+}
+
+#[test]
+fn test_watchdog_pings_at_half_interval() {
+    let dir = TempDir::new().unwrap();
+    let (socket, path) = notify_socket(&dir);
+    let mut notifier = Notifier::new(Some(path), Some(Duration::from_secs(10)));
+    let now = Instant::now();
+
+    notifier.keepalive(now);
+    notifier.keepalive(now + Duration::from_secs(4));
+    notifier.keepalive(now + Duration::from_secs(5));
+
+    assert_eq!(received(&socket), vec!["WATCHDOG=1", "WATCHDOG=1"]);
+}
+
+#[test]
+fn test_disabled_notifier_sends_nothing() {
+    let notifier = Notifier::new(None, None);
+    assert!(!notifier.is_enabled());
+    notifier.ready();
+}
+
+#[tokio::test]
+async fn test_engine_reports_readiness_and_module_status() {
+    let dir = TempDir::new().unwrap();
+    let (socket, path) = notify_socket(&dir);
+    create_module(dir.path(), "good", "echo '{\"dependencies\": [], \"changed\": true, \"failed\": false}'\n");
+    create_module(dir.path(), "bad", "exit 2\n");
+    let (url, _requests) = spawn_http_stub(200).await;
+    let config: Config = serde_yaml::from_str(&format!(r#"
+server: {{url: "{}", timeout: 5}}
+agent: {{module_paths: ["{}"], log_level: info}}
+modules: {{good: {{interval: 60}}, bad: {{interval: 60}}}}
+"#, url, dir.path().display())).unwrap();
+
+    let mut engine = CollectionEngine::new(config)
+        .with_notifier(Notifier::new(Some(path), Some(Duration::from_secs(2))));
+    run_engine_for(&mut engine, Duration::from_millis(1500)).await;
+
+    let messages = received(&socket);
+    assert!(messages.contains(&"READY=1".to_string()));
+    assert!(messages.contains(&"WATCHDOG=1".to_string()));
+    assert!(messages.contains(&"STATUS=2 modules: 1 ok, 1 failing (bad: non_zero_exit)".to_string()));
+    assert_eq!(messages.last().unwrap(), "STOPPING=1");
+}
+
+#[test]
+fn test_journal_entries_use_binary_form_for_multiline_values() {
+    let entry = journal_entry(&[("MESSAGE", "one\ntwo"), ("PRIORITY", "3")]);
+
+    let mut expected = b"MESSAGE\n".to_vec();
+    expected.extend_from_slice(&7u64.to_le_bytes());
+    expected.extend_from_slice(b"one\ntwo\nPRIORITY=3\n");
+    assert_eq!(entry, expected);
+
+    let record = log::Record::builder()
+        .args(format_args!("timed out"))
+        .level(log::Level::Warn)
+        .target("module::std.connections")
+        .build();
+    let entry = String::from_utf8(JournalLogger::entry(&record)).unwrap();
+    assert!(entry.contains("PRIORITY=4\n"));
+    assert!(entry.contains("DEP_MAP_MODULE=std.connections\n"));
+}
+
+#[test]
+fn test_unit_file_runs_agent_under_notify_and_watchdog() {
+    let unit = unit_file(Path::new("/usr/bin/dep_map"), Path::new("/etc/dep_map/config.yaml"), Duration::from_secs(120));
+
+    assert!(unit.contains("Type=notify\n"));
+    assert!(unit.contains("ExecStart=/usr/bin/dep_map --config /etc/dep_map/config.yaml\n"));
+    assert!(unit.contains("WatchdogSec=120\n"));
+    assert!(unit.contains("ExecReload=/bin/kill -HUP $MAINPID\n"));
+}
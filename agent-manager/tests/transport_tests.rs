@@ -0,0 +1,165 @@
+use agent::config::{Config, ServerConfig};
+use agent::transport::Transport;
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslAcceptor, SslMethod, SslVerifyMode};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+use crate::common::pki::{pki, write_private, Pki};
+use crate::common::{read_request, spawn_http_stub, StubRequest};
+
+/// A request received by [`spawn_https_stub`] and the common name of the
+/// client certificate it came with.
+type TlsRequest = (StubRequest, Option<String>);
+
+/// An HTTPS stand-in for the server that requires a client certificate
+/// issued by the test CA and answers every request with 200.
+async fn spawn_https_stub(pki: &Pki) -> (String, Arc<Mutex<Vec<TlsRequest>>>) {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+    acceptor.set_certificate_chain_file(&pki.server_cert).unwrap();
+    acceptor.set_private_key_file(&pki.server_key, openssl::ssl::SslFiletype::PEM).unwrap();
+    acceptor.set_ca_file(&pki.ca).unwrap();
+    acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    let acceptor = Arc::new(acceptor.build());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://{}/api/dependencies", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let log = requests.clone();
+
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            let ssl = Ssl::new(acceptor.context()).unwrap();
+            let log = log.clone();
+            tokio::spawn(async move {
+                let mut stream = SslStream::new(ssl, tcp).unwrap();
+                if Pin::new(&mut stream).accept().await.is_err() {
+                    return;
+                }
+                let peer = stream.ssl().peer_certificate().and_then(|cert| {
+                    cert.subject_name()
+                        .entries_by_nid(Nid::COMMONNAME)
+                        .next()
+                        .and_then(|entry| entry.data().to_string().ok())
+                });
+                if let Some(request) = read_request(&mut stream).await {
+                    log.lock().unwrap().push((request, peer));
+                }
+                let response = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    (url, requests)
+}
+
+fn server_config(url: &str, extra: &str) -> ServerConfig {
+    let config: Config = serde_yaml::from_str(&format!(r#"
+server:
+  url: "{}"
+  timeout: 5
+{}
+agent: {{module_paths: [], log_level: info}}
+modules: {{}}
+"#, url, extra)).unwrap();
+    config.server
+}
+
+fn header<'a>(request: &'a StubRequest, name: &str) -> Option<&'a str> {
+    request.headers.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+}
+
+#[tokio::test]
+async fn test_mutual_tls_with_bearer_token() {
+    let pki = pki();
+    let token = pki.dir.path().join("token");
+    write_private(&token, b"s3cret-token\n");
+    let (url, requests) = spawn_https_stub(&pki).await;
+    let server = server_config(&url, &format!(r#"
+  auth: {{token_file: "{}"}}
+  tls:
+    ca_file: "{}"
+    client_cert_file: "{}"
+    client_key_file: "{}"
+"#, token.display(), pki.ca.display(), pki.client_cert.display(), pki.client_key.display()));
+
+    Transport::new(&server).unwrap().send(b"{}".to_vec()).await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let (request, peer) = &requests[0];
+    assert_eq!(request.path, "/api/dependencies");
+    assert_eq!(header(request, "authorization"), Some("Bearer s3cret-token"));
+    assert_eq!(peer.as_deref(), Some("agent-1"));
+}
+
+#[tokio::test]
+async fn test_server_must_match_pinned_ca() {
+    let pki = pki();
+    let other = self::pki();
+    let (url, requests) = spawn_https_stub(&pki).await;
+    let client = format!(r#"
+    client_cert_file: "{}"
+    client_key_file: "{}"
+"#, pki.client_cert.display(), pki.client_key.display());
+
+    // Neither the system roots nor another CA vouch for the stand-in.
+    let system_roots = server_config(&url, &format!("  tls:{}", client));
+    assert!(Transport::new(&system_roots).unwrap().send(b"{}".to_vec()).await.is_err());
+    let other_ca = server_config(&url, &format!("  tls:\n    ca_file: \"{}\"{}", other.ca.display(), client));
+    assert!(Transport::new(&other_ca).unwrap().send(b"{}".to_vec()).await.is_err());
+
+    // The server refuses agents without a client certificate.
+    let no_client_cert = server_config(&url, &format!("  tls: {{ca_file: \"{}\"}}", pki.ca.display()));
+    assert!(Transport::new(&no_client_cert).unwrap().send(b"{}".to_vec()).await.is_err());
+
+    assert!(requests.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_secret_files_must_be_private() {
+    let dir = TempDir::new().unwrap();
+    let token = dir.path().join("token");
+    fs::write(&token, "s3cret").unwrap();
+    fs::set_permissions(&token, fs::Permissions::from_mode(0o644)).unwrap();
+    let server = server_config("https://127.0.0.1:1/", &format!("  auth: {{token_file: \"{}\"}}", token.display()));
+
+    let error = Transport::new(&server).unwrap_err().to_string();
+    assert!(error.contains("accessible by group or others (mode 644)"), "{}", error);
+
+    fs::set_permissions(&token, fs::Permissions::from_mode(0o600)).unwrap();
+    assert!(Transport::new(&server).is_ok());
+
+    let missing = server_config("https://127.0.0.1:1/", "  auth: {api_key_file: /nonexistent/key}");
+    assert!(Transport::new(&missing).unwrap_err().to_string().contains("/nonexistent/key"));
+}
+
+#[tokio::test]
+async fn test_api_key_through_proxy() {
+    let dir = TempDir::new().unwrap();
+    let api_key = dir.path().join("api_key");
+    write_private(&api_key, b"agent-key-1\n");
+    let password = dir.path().join("proxy_password");
+    write_private(&password, b"hunter2\n");
+    let (proxy_url, requests) = spawn_http_stub(200).await;
+    let server = server_config("http://dep-map.invalid/api", &format!(r#"
+  auth: {{api_key_file: "{}", api_key_header: X-Agent-Key}}
+  proxy: {{url: "{}", username: agent, password_file: "{}"}}
+"#, api_key.display(), proxy_url, password.display()));
+
+    Transport::new(&server).unwrap().send(b"{}".to_vec()).await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "http://dep-map.invalid/api");
+    assert_eq!(header(&requests[0], "x-agent-key"), Some("agent-key-1"));
+    // "agent:hunter2"
+    assert_eq!(header(&requests[0], "proxy-authorization"), Some("Basic YWdlbnQ6aHVudGVyMg=="));
+}
@@ -0,0 +1,180 @@
+use agent::config::{ConfigLoader, ConfigOverrides, ConfigSource};
+use agent::validate::{locate_key, validate_loaded, Severity};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tempfile::TempDir;
+use crate::common::create_module;
+
+fn load(content: &str, env: &[(&str, &str)]) -> agent::config::LoadedConfig {
+    let env = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+    ConfigLoader::new(ConfigOverrides::default())
+        .with_env(env)
+        .load_str(content, PathBuf::from("agent.yaml"), ConfigSource::Cli("config".to_string()))
+        .unwrap()
+}
+
+#[test]
+fn test_valid_config_has_no_issues() {
+    let module_dir = TempDir::new().unwrap();
+    create_module(module_dir.path(), "std.connections", "echo '{}'\n");
+    let loaded = load(&format!(r#"
+server:
+  url: "http://localhost:8080/api"
+  timeout: 30
+agent:
+  module_paths: ["{}"]
+  log_level: "debug"
+modules:
+  std.connections:
+    interval: 60
+"#, module_dir.path().display()), &[]);
+
+    assert!(validate_loaded(&loaded).is_empty());
+    assert!(loaded.validate().unwrap().is_empty());
+}
+
+#[test]
+fn test_all_issues_are_reported_with_positions() {
+    let loaded = load(r#"
+server:
+  url: "not a url"
+  timeout: 30
+agent:
+  module_paths: ["/nonexistent"]
+  log_level: "agent=loud"
+modules:
+  std.connections:
+    interval: 0
+  nightly:
+    schedule: "bogus"
+"#, &[]);
+
+    let issues = validate_loaded(&loaded);
+    let found: Vec<(&str, Option<usize>, Option<usize>)> = issues
+        .iter()
+        .map(|issue| (issue.key.as_str(), issue.line, issue.column))
+        .collect();
+
+    assert!(found.contains(&("server.url", Some(3), Some(3))));
+    assert!(found.contains(&("agent.log_level", Some(7), Some(3))));
+    assert!(found.contains(&("modules.std.connections.interval", Some(10), Some(5))));
+    assert!(found.contains(&("modules.nightly.schedule", Some(12), Some(5))));
+    let missing = issues.iter().find(|issue| issue.key == "modules.std.connections").unwrap();
+    assert_eq!(missing.severity, Severity::Warning);
+    assert_eq!(missing.line, Some(9));
+
+    let error = loaded.validate().unwrap_err().to_string();
+    assert!(error.contains("line 10 column 5: modules.std.connections.interval: must be greater than 0"));
+    assert!(!error.contains("module not found"));
+}
+
+#[test]
+fn test_issue_from_override_names_its_source() {
+    let loaded = load(r#"
+server:
+  url: "http://localhost:8080/api"
+  timeout: 30
+agent:
+  module_paths: []
+  log_level: "info"
+modules: {}
+"#, &[("DEP_MAP_SERVER_URL", "ftp://example.com")]);
+
+    let issues = validate_loaded(&loaded);
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].line, None);
+    assert_eq!(issues[0].source, Some(ConfigSource::Env("DEP_MAP_SERVER_URL".to_string())));
+}
+
+#[test]
+fn test_locate_key_handles_sequences_and_missing_keys() {
+    let content = "agent:\n  module_paths:\n  - /a\n  - /b\n  log_level: info\nmodules:\n  a:\n    interval: 5\n";
+
+    assert_eq!(locate_key(content, &["agent", "module_paths", "1"]), Some((4, 3)));
+    assert_eq!(locate_key(content, &["agent", "log_level"]), Some((5, 3)));
+    assert_eq!(locate_key(content, &["modules", "a", "timeout"]), Some((7, 3)));
+    assert_eq!(locate_key(content, &["server"]), None);
+}
+
+#[test]
+fn test_log_level_accepts_filter_directives() {
+    let content = |level: &str| format!(r#"
+server:
+  url: "http://localhost:8080/api"
+  timeout: 30
+agent:
+  module_paths: []
+  log_level: "{}"
+  log:
+    modules:
+      connections: chatty
+modules: {{}}
+"#, level);
+
+    let issues = validate_loaded(&load(&content("info,agent::outbox=debug"), &[]));
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].key, "agent.log.modules.connections");
+    assert_eq!(issues[0].line, Some(10));
+
+    // A bare word that is not a level is a target, as in env_logger.
+    for filter in ["hyper", "info,hyper=warn", "myapp,warn"] {
+        let issues = validate_loaded(&load(&content(filter), &[]));
+        assert!(issues.iter().all(|issue| issue.key != "agent.log_level"), "{}: {:?}", filter, issues);
+    }
+
+    for filter in ["info,agent::outbox=loud", "hyper=", "two words"] {
+        let issues = validate_loaded(&load(&content(filter), &[]));
+        assert!(issues.iter().any(|issue| issue.key == "agent.log_level"), "{}", filter);
+    }
+}
+
+#[test]
+fn test_transport_settings_must_fit_together() {
+    let loaded = load(r#"
+server:
+  url: "http://localhost:8080/api"
+  timeout: 30
+  auth:
+    token_file: /etc/dep_map/token
+    api_key_file: /etc/dep_map/api_key
+  tls:
+    client_cert_file: /etc/dep_map/agent.pem
+  proxy:
+    url: "not a url"
+  enroll_url: "ftp://localhost/enroll"
+agent:
+  module_paths: []
+  log_level: info
+modules: {}
+"#, &[]);
+
+    let issues = validate_loaded(&loaded);
+    let found: Vec<(&str, Severity)> = issues.iter().map(|issue| (issue.key.as_str(), issue.severity)).collect();
+    assert!(found.contains(&("server.auth.api_key_file", Severity::Error)));
+    assert!(found.contains(&("server.url", Severity::Warning)));
+    assert!(found.contains(&("server.tls.client_cert_file", Severity::Error)));
+    assert!(found.contains(&("server.proxy.url", Severity::Error)));
+    assert!(found.contains(&("server.enroll_url", Severity::Error)));
+}
+
+#[test]
+fn test_relay_needs_state_dir_and_tls_for_tokens() {
+    let loaded = load(r#"
+server:
+  url: "https://localhost:8080/api"
+  timeout: 30
+agent:
+  module_paths: []
+  log_level: info
+  relay:
+    listen: "0.0.0.0"
+    token_file: /etc/dep_map/relay_token
+modules: {}
+"#, &[]);
+
+    let issues = validate_loaded(&loaded);
+    let found: Vec<(&str, Severity)> = issues.iter().map(|issue| (issue.key.as_str(), issue.severity)).collect();
+    assert!(found.contains(&("agent.relay.listen", Severity::Error)));
+    assert!(found.contains(&("agent.relay", Severity::Error)));
+    assert!(found.contains(&("agent.relay.token_file", Severity::Warning)));
+}
@@ -3,11 +3,14 @@
 package controllers
 
 import (
+	"bytes"
 	"context"
 	"dependency-mapper/internal/models"
+	"encoding/json"
 	"fmt"
 	"github.com/gin-gonic/gin"
 	"github.com/neo4j/neo4j-go-driver/v5/neo4j"
+	"io"
 	"net/http"
 )
 
@@ -22,16 +25,43 @@ func InitDriver(uri, username, password string) error {
 	return driver.VerifyConnectivity(context.Background())
 }
 
+// decodeBatch accepts both an agent batch object and the bare array of
+// dependencies that older agents send.
+func decodeBatch(body []byte) (models.Batch, error) {
+	var batch models.Batch
+	trimmed := bytes.TrimSpace(body)
+	if len(trimmed) > 0 && trimmed[0] == '[' {
+		err := json.Unmarshal(trimmed, &batch.Dependencies)
+		batch.Kind = "full"
+		return batch, err
+	}
+	err := json.Unmarshal(trimmed, &batch)
+	return batch, err
+}
+
 func HandleDependencies(c *gin.Context) {
-	var dependencies []models.Dependency
-	if err := c.ShouldBindJSON(&dependencies); err != nil {
+	body, err := io.ReadAll(c.Request.Body)
+	if err != nil {
+		c.JSON(http.StatusBadRequest, gin.H{"error": "Invalid input data"})
+		return
+	}
+	batch, err := decodeBatch(body)
+	if err != nil {
 		c.JSON(http.StatusBadRequest, gin.H{"error": "Invalid input data"})
 		return
 	}
 
 	ctx := context.Background()
 
-	for _, dep := range dependencies {
+	// Dependencies a delta batch reports as gone
+	for _, dep := range batch.Removed {
+		if err := DeleteDependency(ctx, driver, dep); err != nil {
+			c.JSON(http.StatusInternalServerError, gin.H{"error": "Failed to remove dependency"})
+			return
+		}
+	}
+
+	for _, dep := range batch.Dependencies {
 		// Add or update the local node
 		localNode, err := models.AddNode(ctx, driver, models.Node{
 			ID:   dep.LocalIp,
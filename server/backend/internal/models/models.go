@@ -45,6 +45,20 @@ type Dependency struct {
     Description string `json:"description"`
 }
 
+// Batch is what agents upload: one module's report, optionally wrapped with
+// the sending agent's identity and the relays it passed through. Older agents
+// send a bare array of dependencies instead.
+type Batch struct {
+    Module       string                   `json:"module"`
+    Kind         string                   `json:"kind"`
+    Dependencies []Dependency             `json:"dependencies"`
+    Removed      []Dependency             `json:"removed"`
+    Services     []map[string]interface{} `json:"services"`
+    Health       map[string]interface{}   `json:"health"`
+    Agent        map[string]interface{}   `json:"agent"`
+    Relays       []map[string]interface{} `json:"relays"`
+}
+
 func AddNode(ctx context.Context, driver neo4j.DriverWithContext, node Node) (Node, error) {
     query := `
     MERGE (n:Node {id: $id})
@@ -1,7 +1,11 @@
 use procfs::net::{TcpNetEntry, TcpState, UdpNetEntry, UdpState};
+use procfs::process::FDTarget;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 use std::thread;
-use std_modules::response::{Response, Dependency};
+use std_modules::response::{Response, Dependency, Direction, ExposedService, Manifest, Parameter, ProcessInfo, Spec};
 use std_modules::implement_module;
 use thiserror::Error;
 
@@ -65,8 +69,26 @@ trait NetworkData {
     fn local_address(&self) -> String;
     fn remote_address(&self) -> String;
     fn inode(&self) -> u64;
+    fn local_port(&self) -> u16;
+    fn remote_port(&self) -> u16;
+    fn remote_is_unspecified(&self) -> bool;
     fn state(&self) -> ConnectionState;
     fn protocol(&self) -> String;
+
+    /// Only TCP sockets in `Listen` are TCP listeners. UDP has no listening
+    /// state, so a UDP socket counts as a service when it has no peer and is
+    /// bound to a fixed port; unconnected client sockets get an ephemeral one.
+    fn is_listener(&self, ephemeral: &RangeInclusive<i32>) -> bool {
+        match self.protocol().as_str() {
+            "UDP" => {
+                self.remote_is_unspecified()
+                    && self.remote_port() == 0
+                    && self.local_port() != 0
+                    && !ephemeral.contains(&i32::from(self.local_port()))
+            }
+            _ => self.state() == ConnectionState::Listen,
+        }
+    }
 }
 
 macro_rules! impl_network_data {
@@ -84,6 +106,18 @@ macro_rules! impl_network_data {
                 self.inode
             }
 
+            fn local_port(&self) -> u16 {
+                self.local_address.port()
+            }
+
+            fn remote_port(&self) -> u16 {
+                self.remote_address.port()
+            }
+
+            fn remote_is_unspecified(&self) -> bool {
+                self.remote_address.ip().is_unspecified()
+            }
+
             fn state(&self) -> ConnectionState {
                 ConnectionState::from(&self.state)
             }
@@ -108,7 +142,7 @@ fn parse_ipv6_address(address: &str) -> Option<(&str, &str)> {
 fn parse_ipv4_address(address: &str) -> Option<(&str, &str)> {
     let mut parts = address.split(':');
     let ip = parts.next()?;
-    let port = parts.last()?;
+    let port = parts.next_back()?;
     Some((ip, port))
 }
 
@@ -120,85 +154,258 @@ fn parse_address(address: &str) -> Option<(&str, &str)> {
     }
 }
 
+type SocketOwners = Arc<HashMap<u64, ProcessInfo>>;
+
+/// Reads `/etc/passwd` into a uid -> user name lookup.
+fn user_names() -> HashMap<u32, String> {
+    std::fs::read_to_string("/etc/passwd")
+        .map(|passwd| parse_passwd(&passwd))
+        .unwrap_or_default()
+}
+
+fn parse_passwd(passwd: &str) -> HashMap<u32, String> {
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse().ok()?;
+            Some((uid, name.to_string()))
+        })
+        .collect()
+}
+
+/// Maps every socket inode on the host to the process holding it open.
+///
+/// Processes that vanish or whose fds we are not allowed to read are skipped,
+/// so without root only the agent user's own sockets are attributed.
+fn socket_owners() -> SocketOwners {
+    let users = user_names();
+
+    let Ok(all_procs) = procfs::process::all_processes() else {
+        return Arc::new(HashMap::new());
+    };
+
+    let processes = all_procs.filter_map(Result::ok).filter_map(|process| {
+        let inodes: Vec<u64> = process
+            .fd()
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|fd| match fd.target {
+                FDTarget::Socket(inode) => Some(inode),
+                _ => None,
+            })
+            .collect();
+        if inodes.is_empty() {
+            return None;
+        }
+
+        let stat = process.stat().ok()?;
+        let info = ProcessInfo {
+            pid: process.pid,
+            name: stat.comm,
+            exe: process.exe().ok().map(|exe| exe.display().to_string()),
+            cmdline: process
+                .cmdline()
+                .ok()
+                .filter(|args| !args.is_empty())
+                .map(|args| args.join(" ")),
+            user: process.uid().ok().map(|uid| user_name(&users, uid)),
+        };
+        Some((info, inodes))
+    });
+
+    Arc::new(index_owners(processes))
+}
+
+fn user_name(users: &HashMap<u32, String>, uid: u32) -> String {
+    users.get(&uid).cloned().unwrap_or_else(|| uid.to_string())
+}
+
+/// Indexes processes by the socket inodes they hold. A socket shared after a
+/// fork goes to the first process listed, which is the parent since
+/// `/proc` lists processes by pid.
+fn index_owners(processes: impl IntoIterator<Item = (ProcessInfo, Vec<u64>)>) -> HashMap<u64, ProcessInfo> {
+    let mut owners = HashMap::new();
+    for (info, inodes) in processes {
+        for inode in inodes {
+            owners.entry(inode).or_insert_with(|| info.clone());
+        }
+    }
+    owners
+}
+
+fn is_local_connection(local_ip: &str, remote_ip: &str) -> bool {
+    local_ip.contains("127.0.0.")
+        || remote_ip.contains("127.0.0.")
+        || remote_ip.eq("::")
+        || remote_ip.eq("::1")
+        || remote_ip.eq("0.0.0.0")
+}
+
+fn is_loopback_bind(bind_ip: &str) -> bool {
+    bind_ip.starts_with("127.") || bind_ip.eq("::1")
+}
+
+#[derive(Debug, Default)]
+pub struct Connections {
+    pub dependencies: Vec<Dependency>,
+    pub services: Vec<ExposedService>,
+}
+
+impl Connections {
+    /// Merges `other` in, keeping one record per exposed service even when
+    /// several sockets share the same bind address and port.
+    fn extend(&mut self, other: Connections) {
+        self.dependencies.extend(other.dependencies);
+        for service in other.services {
+            if !self.services.contains(&service) {
+                self.services.push(service);
+            }
+        }
+    }
+}
+
 fn process_network_entries<F, T>(
     fetch_entries: F,
+    owners: SocketOwners,
+    ephemeral: RangeInclusive<i32>,
     omit_local_connections: bool,
-) -> Vec<Dependency>
+) -> Connections
 where
     F: Fn() -> Result<Vec<T>, procfs::ProcError> + Send + 'static,
     T: NetworkData + Send + 'static,
 {
     thread::spawn(move || {
-        let mut dependencies = Vec::new();
+        let mut connections = Connections::default();
 
         if let Ok(entries) = fetch_entries() {
             for entry in entries {
-                let state = entry.state();
-                if state.is_closed() {
+                let Some((local_ip, local_port)) = parse_address(&entry.local_address())
+                    .and_then(|(ip, port)| Some((ip.to_string(), port.parse::<i32>().ok()?)))
+                else {
+                    continue;
+                };
+
+                if entry.is_listener(&ephemeral) {
+                    if omit_local_connections && is_loopback_bind(&local_ip) {
+                        continue;
+                    }
+                    let service = ExposedService {
+                        module: "Connections".to_string(),
+                        bind_ip: local_ip,
+                        port: local_port,
+                        protocol: entry.protocol(),
+                        local_os: "Linux".to_string(),
+                        process: owners.get(&entry.inode()).cloned(),
+                    };
+                    connections.services.push(service);
+                    continue;
+                }
+
+                if entry.state().is_closed() {
                     continue;
                 }
 
-                if let (Some((local_ip, local_port)), Some((remote_ip, remote_port))) = 
-                    (parse_address(&entry.local_address()), parse_address(&entry.remote_address())) {
-                    if omit_local_connections {
-                        if local_ip.contains("127.0.0.") || remote_ip.contains("127.0.0.") || remote_ip.eq("::") || remote_ip.eq("::1") || remote_ip.eq("0.0.0.0") {
-                            continue;
-                        }
+                if let Some((remote_ip, remote_port)) = parse_address(&entry.remote_address()) {
+                    if omit_local_connections && is_local_connection(&local_ip, remote_ip) {
+                        continue;
                     }
 
-                    if let (Ok(local_port), Ok(remote_port)) = (local_port.parse::<i32>(), remote_port.parse::<i32>()) {
+                    if let Ok(remote_port) = remote_port.parse::<i32>() {
                         let dependency = Dependency {
                             module: "Connections".to_string(),
                             local_port,
-                            local_ip: local_ip.to_string(),
+                            local_ip: local_ip.clone(),
                             local_os: "Linux".to_string(),
                             remote_port,
                             remote_ip: remote_ip.to_string(),
                             description: format!("{} connection", entry.protocol()),
+                            protocol: Some(entry.protocol()),
+                            process: owners.get(&entry.inode()).cloned(),
+                            direction: Direction::Unknown,
+                            service_port: None,
                         };
-                        dependencies.push(dependency);
+                        connections.dependencies.push(dependency);
                     }
                 }
             }
         }
-        dependencies
+        connections
     }).join().unwrap()
 }
 
-pub fn conn_info(omit_local_connections: bool) -> Vec<Dependency> {
-
-    // TODO: Do we need the PID?
-    /*
-    let all_procs = procfs::process::all_processes().expect("Can't read /proc");
-    let mut map: HashMap<u64, Stat> = HashMap::new();
+/// Linux's default when `ip_local_port_range` cannot be read.
+const DEFAULT_EPHEMERAL_PORTS: RangeInclusive<i32> = 32768..=60999;
+
+fn ephemeral_port_range() -> RangeInclusive<i32> {
+    std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range")
+        .ok()
+        .and_then(|range| {
+            let mut bounds = range.split_whitespace().map(str::parse::<i32>);
+            Some(bounds.next()?.ok()?..=bounds.next()?.ok()?)
+        })
+        .unwrap_or(DEFAULT_EPHEMERAL_PORTS)
+}
 
-    for process in all_procs.filter_map(Result::ok) {
-        if let Ok(stat) = process.stat() {
-            if let Ok(fds) = process.fd() {
-                for fd in fds.filter_map(Result::ok) {
-                    if let FDTarget::Socket(inode) = fd.target {
-                        map.insert(inode, stat.clone());
-                    }
-                }
-            }
-        }
+/// Decides which end of each connection is the server.
+///
+/// A port that something on this host listens on marks that end as the
+/// service; for connections between two local sockets this is checked on
+/// both ends. Otherwise the ephemeral port range tells the client side
+/// apart, and when that is inconclusive too the lower port is assumed to be
+/// the service.
+fn infer_directions(connections: &mut Connections, ephemeral: &RangeInclusive<i32>) {
+    let listening: HashSet<(i32, String)> = connections
+        .services
+        .iter()
+        .map(|service| (service.port, service.protocol.clone()))
+        .collect();
+    let local_ips: HashSet<String> = connections
+        .dependencies
+        .iter()
+        .map(|dependency| dependency.local_ip.clone())
+        .collect();
+
+    for dependency in &mut connections.dependencies {
+        let protocol = dependency.protocol.clone().unwrap_or_default();
+        let local_listens = listening.contains(&(dependency.local_port, protocol.clone()));
+        let remote_listens = local_ips.contains(&dependency.remote_ip)
+            && listening.contains(&(dependency.remote_port, protocol));
+        let local_ephemeral = ephemeral.contains(&dependency.local_port);
+        let remote_ephemeral = ephemeral.contains(&dependency.remote_port);
+
+        let direction = if local_listens {
+            Direction::Inbound
+        } else if remote_listens || (local_ephemeral && !remote_ephemeral) {
+            Direction::Outbound
+        } else if remote_ephemeral && !local_ephemeral {
+            Direction::Inbound
+        } else {
+            Direction::Unknown
+        };
+
+        dependency.direction = direction;
+        dependency.service_port = Some(match direction {
+            Direction::Inbound => dependency.local_port,
+            Direction::Outbound => dependency.remote_port,
+            Direction::Unknown => dependency.local_port.min(dependency.remote_port),
+        });
     }
+}
 
-    let shared_map = Arc::new(map);
-    */
-
-    let tcp_dependencies = process_network_entries(procfs::net::tcp, omit_local_connections);
-    let udp_dependencies = process_network_entries(procfs::net::udp , omit_local_connections);
-    let tcp6_dependencies = process_network_entries(procfs::net::tcp6, omit_local_connections);
-    let udp6_dependencies = process_network_entries(procfs::net::udp6, omit_local_connections);
+pub fn conn_info(omit_local_connections: bool) -> Connections {
+    let owners = socket_owners();
+    let ephemeral = ephemeral_port_range();
 
-    let mut all_dependencies = Vec::new();
-    all_dependencies.extend(tcp_dependencies);
-    all_dependencies.extend(udp_dependencies);
-    all_dependencies.extend(tcp6_dependencies);
-    all_dependencies.extend(udp6_dependencies);
+    let mut all_connections = Connections::default();
+    all_connections.extend(process_network_entries(procfs::net::tcp, owners.clone(), ephemeral.clone(), omit_local_connections));
+    all_connections.extend(process_network_entries(procfs::net::udp, owners.clone(), ephemeral.clone(), omit_local_connections));
+    all_connections.extend(process_network_entries(procfs::net::tcp6, owners.clone(), ephemeral.clone(), omit_local_connections));
+    all_connections.extend(process_network_entries(procfs::net::udp6, owners, ephemeral.clone(), omit_local_connections));
 
-    all_dependencies
+    infer_directions(&mut all_connections, &ephemeral);
+    all_connections
 }
 
 #[derive(Debug, Error)]
@@ -208,20 +415,285 @@ pub enum ModuleError {
 }
 
 #[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct ConnectionArgs {
     omit_local_connections: bool,
 }
 
 fn run_connections(args: ConnectionArgs) -> Result<Response, ModuleError> {
 
-    let conn_info = conn_info(args.omit_local_connections);
-    let response = Response::new(conn_info, false, false);
+    let connections = conn_info(args.omit_local_connections);
+    // Each run is a fresh process with no memory of the previous snapshot,
+    // so the agent is always asked to diff.
+    let response = Response::new(connections.dependencies, true, false)
+        .with_services(connections.services);
     Ok(response)
 }
 
-implement_module!(ConnectionModule, ConnectionArgs, ModuleError, run_connections);
+fn connections_manifest() -> Manifest {
+    Manifest {
+        name: "std.connections".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        description: Some("TCP and UDP connections and listening sockets, with their owning processes".to_string()),
+        os: vec!["linux".to_string()],
+        outputs: vec!["dependencies".to_string(), "services".to_string()],
+        spec: Some(Spec {
+            input_parameters: Some(vec![Parameter {
+                name: "omit_local_connections".to_string(),
+                value_type: "Boolean".to_string(),
+                required: false,
+                description: Some("Skip connections and listeners that never leave this host".to_string()),
+            }]),
+            return_values: None,
+        }),
+    }
+}
+
+implement_module!(ConnectionModule, ConnectionArgs, ModuleError, run_connections, connections_manifest);
 
 
 fn main() {
     std_modules::response::run_module::<ConnectionModule>();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    const EPHEMERAL: RangeInclusive<i32> = 32768..=60999;
+
+    struct Socket {
+        protocol: &'static str,
+        state: ConnectionState,
+        local: SocketAddr,
+        remote: SocketAddr,
+    }
+
+    impl Socket {
+        fn new(protocol: &'static str, state: ConnectionState, local: &str, remote: &str) -> Self {
+            Socket {
+                protocol,
+                state,
+                local: local.parse().unwrap(),
+                remote: remote.parse().unwrap(),
+            }
+        }
+    }
+
+    impl NetworkData for Socket {
+        fn local_address(&self) -> String {
+            self.local.to_string()
+        }
+
+        fn remote_address(&self) -> String {
+            self.remote.to_string()
+        }
+
+        fn inode(&self) -> u64 {
+            0
+        }
+
+        fn local_port(&self) -> u16 {
+            self.local.port()
+        }
+
+        fn remote_port(&self) -> u16 {
+            self.remote.port()
+        }
+
+        fn remote_is_unspecified(&self) -> bool {
+            self.remote.ip().is_unspecified()
+        }
+
+        fn state(&self) -> ConnectionState {
+            self.state.clone()
+        }
+
+        fn protocol(&self) -> String {
+            self.protocol.into()
+        }
+    }
+
+    fn process(pid: i32, name: &str, user: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            name: name.to_string(),
+            exe: None,
+            cmdline: None,
+            user: Some(user.to_string()),
+        }
+    }
+
+    #[test]
+    fn parse_passwd_maps_uids_to_names() {
+        let users = parse_passwd(
+            "root:x:0:0:root:/root:/bin/bash\n\
+             # a comment\n\
+             postgres:x:114:120::/var/lib/postgresql:/bin/sh\n\
+             broken:x:notanumber:0::/:/bin/sh\n\
+             \n",
+        );
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[&0], "root");
+        assert_eq!(users[&114], "postgres");
+    }
+
+    #[test]
+    fn unknown_uids_fall_back_to_the_number() {
+        let users = parse_passwd("root:x:0:0:root:/root:/bin/bash");
+        assert_eq!(user_name(&users, 0), "root");
+        assert_eq!(user_name(&users, 1001), "1001");
+    }
+
+    #[test]
+    fn index_owners_maps_inodes_to_processes() {
+        let owners = index_owners([
+            (process(100, "nginx", "root"), vec![11, 12]),
+            (process(200, "postgres", "postgres"), vec![21]),
+        ]);
+        assert_eq!(owners.len(), 3);
+        assert_eq!(owners[&11].pid, 100);
+        assert_eq!(owners[&12].pid, 100);
+        assert_eq!(owners[&21].name, "postgres");
+        assert_eq!(owners[&21].user.as_deref(), Some("postgres"));
+        assert!(!owners.contains_key(&31));
+    }
+
+    #[test]
+    fn shared_socket_goes_to_the_first_process() {
+        let owners = index_owners([
+            (process(100, "nginx", "root"), vec![11]),
+            (process(101, "nginx", "www-data"), vec![11]),
+        ]);
+        assert_eq!(owners[&11].pid, 100);
+    }
+
+    fn dependency(local_ip: &str, local_port: i32, remote_ip: &str, remote_port: i32) -> Dependency {
+        Dependency {
+            module: "Connections".to_string(),
+            local_ip: local_ip.to_string(),
+            local_os: "Linux".to_string(),
+            remote_ip: remote_ip.to_string(),
+            local_port,
+            remote_port,
+            description: "TCP connection".to_string(),
+            protocol: Some("TCP".to_string()),
+            process: None,
+            direction: Direction::Unknown,
+            service_port: None,
+        }
+    }
+
+    fn service(port: i32) -> ExposedService {
+        ExposedService {
+            module: "Connections".to_string(),
+            bind_ip: "0.0.0.0".to_string(),
+            port,
+            protocol: "TCP".to_string(),
+            local_os: "Linux".to_string(),
+            process: None,
+        }
+    }
+
+    fn inferred(dependency: Dependency, services: Vec<ExposedService>) -> (Direction, Option<i32>) {
+        let mut connections = Connections {
+            dependencies: vec![dependency],
+            services,
+        };
+        infer_directions(&mut connections, &EPHEMERAL);
+        let dependency = &connections.dependencies[0];
+        (dependency.direction, dependency.service_port)
+    }
+
+    #[test]
+    fn connection_to_a_local_listener_is_inbound() {
+        let result = inferred(dependency("10.0.0.5", 5432, "10.0.0.9", 1500), vec![service(5432)]);
+        assert_eq!(result, (Direction::Inbound, Some(5432)));
+    }
+
+    #[test]
+    fn ephemeral_local_port_is_outbound() {
+        let result = inferred(dependency("10.0.0.5", 40000, "10.0.0.9", 443), vec![]);
+        assert_eq!(result, (Direction::Outbound, Some(443)));
+    }
+
+    #[test]
+    fn ephemeral_remote_port_is_inbound() {
+        let result = inferred(dependency("10.0.0.5", 8080, "10.0.0.9", 50000), vec![]);
+        assert_eq!(result, (Direction::Inbound, Some(8080)));
+    }
+
+    #[test]
+    fn connection_between_local_sockets_follows_the_listener() {
+        let mut connections = Connections {
+            dependencies: vec![
+                dependency("127.0.0.1", 45000, "127.0.0.1", 6379),
+                dependency("127.0.0.1", 6379, "127.0.0.1", 45000),
+            ],
+            services: vec![service(6379)],
+        };
+        infer_directions(&mut connections, &EPHEMERAL);
+        assert_eq!(connections.dependencies[0].direction, Direction::Outbound);
+        assert_eq!(connections.dependencies[1].direction, Direction::Inbound);
+        assert!(connections.dependencies.iter().all(|d| d.service_port == Some(6379)));
+    }
+
+    #[test]
+    fn ephemeral_to_ephemeral_is_unknown_with_the_lower_port() {
+        let result = inferred(dependency("10.0.0.5", 50000, "10.0.0.9", 40000), vec![]);
+        assert_eq!(result, (Direction::Unknown, Some(40000)));
+    }
+
+    #[test]
+    fn listener_on_another_protocol_does_not_count() {
+        let mut udp = service(5432);
+        udp.protocol = "UDP".to_string();
+        let result = inferred(dependency("10.0.0.5", 5432, "10.0.0.9", 2000), vec![udp]);
+        assert_eq!(result, (Direction::Unknown, Some(2000)));
+    }
+
+    #[test]
+    fn tcp_listen_is_a_listener() {
+        let socket = Socket::new("TCP", ConnectionState::Listen, "0.0.0.0:22", "0.0.0.0:0");
+        assert!(socket.is_listener(&EPHEMERAL));
+    }
+
+    #[test]
+    fn tcp_close_without_peer_is_not_a_listener() {
+        let socket = Socket::new("TCP", ConnectionState::Close, "10.0.0.1:22", "0.0.0.0:0");
+        assert!(!socket.is_listener(&EPHEMERAL));
+    }
+
+    #[test]
+    fn tcp_established_is_not_a_listener() {
+        let socket = Socket::new("TCP", ConnectionState::Established, "10.0.0.1:40000", "10.0.0.2:443");
+        assert!(!socket.is_listener(&EPHEMERAL));
+    }
+
+    #[test]
+    fn udp_bound_to_a_fixed_port_is_a_listener() {
+        let socket = Socket::new("UDP", ConnectionState::Close, "0.0.0.0:53", "0.0.0.0:0");
+        assert!(socket.is_listener(&EPHEMERAL));
+        let socket = Socket::new("UDP", ConnectionState::Close, "[::]:123", "[::]:0");
+        assert!(socket.is_listener(&EPHEMERAL));
+    }
+
+    #[test]
+    fn udp_client_on_an_ephemeral_port_is_not_a_listener() {
+        let socket = Socket::new("UDP", ConnectionState::Close, "10.0.0.1:45000", "0.0.0.0:0");
+        assert!(!socket.is_listener(&EPHEMERAL));
+    }
+
+    #[test]
+    fn unbound_udp_socket_is_not_a_listener() {
+        let socket = Socket::new("UDP", ConnectionState::Close, "0.0.0.0:0", "0.0.0.0:0");
+        assert!(!socket.is_listener(&EPHEMERAL));
+    }
+
+    #[test]
+    fn connected_udp_socket_is_not_a_listener() {
+        let socket = Socket::new("UDP", ConnectionState::Established, "10.0.0.1:53", "10.0.0.2:5353");
+        assert!(!socket.is_listener(&EPHEMERAL));
+    }
+}
@@ -8,6 +8,51 @@ pub trait Module {
     type Error: Error;
     type Args: DeserializeOwned + Default;
     fn run(args: Self::Args) -> Result<Response, Self::Error>;
+
+    /// What the module prints when run with `--describe`. Modules without a
+    /// manifest fail the probe.
+    fn manifest() -> Option<Manifest> {
+        None
+    }
+}
+
+/// What a module says about itself, in the agent's manifest format.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Names as in `std::env::consts::OS`. Empty means any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub os: Vec<String>,
+    /// `dependencies`, `services`, or both.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spec: Option<Spec>,
+}
+
+/// The args a module takes and the extra fields it returns.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Spec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_parameters: Option<Vec<Parameter>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_values: Option<Vec<Parameter>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Parameter {
+    pub name: String,
+    /// One of `String`, `Integer`, `Float`, `Boolean`, `List`, `Object` or
+    /// `Any`.
+    #[serde(rename = "type")]
+    pub value_type: String,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,11 +64,62 @@ pub struct Dependency {
     pub remote_port: i32,
     pub remote_ip: String,
     pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<ProcessInfo>,
+    #[serde(default)]
+    pub direction: Direction,
+    /// The port of the side that provides the service, whichever end of the
+    /// connection that is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_port: Option<i32>,
+}
+
+/// Which side of a connection the local host is on.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// A remote client connected to a service on this host.
+    Inbound,
+    /// This host connected to a remote service.
+    Outbound,
+    #[default]
+    Unknown,
+}
+
+/// The process that owns the socket behind a dependency.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    pub name: String,
+    pub exe: Option<String>,
+    pub cmdline: Option<String>,
+    pub user: Option<String>,
+}
+
+/// A service the host exposes, such as a listening TCP socket or a bound UDP
+/// socket. Listeners are reported here rather than as dependencies because
+/// they have no remote peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExposedService {
+    pub module: String,
+    pub bind_ip: String,
+    pub port: i32,
+    pub protocol: String,
+    pub local_os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<ProcessInfo>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
     pub dependencies: Vec<Dependency>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ExposedService>,
+    /// Whether the output differs from the module's previous run. The agent
+    /// only skips reporting on `false` for modules configured with
+    /// `trust_changed`.
     pub changed: bool,
     pub failed: bool,
     #[serde(flatten)]
@@ -34,12 +130,18 @@ impl Response {
     pub fn new(dependencies: Vec<Dependency>, changed: bool, failed: bool) -> Self {
         Response {
             dependencies,
+            services: vec![],
             changed,
             failed,
             extra: serde_json::Map::new(),
         }
     }
 
+    pub fn with_services(mut self, services: Vec<ExposedService>) -> Self {
+        self.services = services;
+        self
+    }
+
     pub fn add_extra<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), Box<dyn Error>> {
         let value = serde_json::to_value(value)?;
         self.extra.insert(key.to_string(), value);
@@ -49,6 +151,7 @@ impl Response {
     pub fn fail(error_msg: &str) -> Self {
         Response{
             dependencies: vec![],
+            services: vec![],
             changed: false,
             failed: true,
             extra: serde_json::Map::from_iter(vec![("error".to_string(), Value::String(error_msg.to_string()))]),
@@ -79,22 +182,22 @@ fn return_response(response_body: &Response) {
     println!("{}", response);
 }
 
+/// Runs a module with the args the agent passed in `ARGS_FILE`, or with
+/// default args when there is none. Args that cannot be read or do not fit
+/// `T::Args` fail the run rather than being replaced by defaults. Run with
+/// `--describe`, the module prints its manifest instead.
 pub fn run_module<T: Module>() {
+    if std::env::args().nth(1).as_deref() == Some("--describe") {
+        describe::<T>();
+    }
+
     let args = match std::env::var("ARGS_FILE") {
         Ok(args_file) => match fs::read_to_string(&args_file) {
             Ok(content) => match serde_json::from_str(&content) {
                 Ok(args) => args,
-                Err(rr) => {
-                    eprintln!("Warning: Could not parse args file as JSON. Using default args.");
-                    eprintln!("Args file content: {}", content);
-                    eprintln!("Error: {}", rr);
-                    T::Args::default()
-                }
+                Err(e) => fail_json(&format!("Invalid args: {}", e)),
             },
-            Err(_) => {
-                eprintln!("Warning: Could not read args file. Using default args.");
-                T::Args::default()
-            }
+            Err(e) => fail_json(&format!("Could not read args file {}: {}", args_file, e)),
         },
         Err(_) => {
             eprintln!("No args file provided. Using default args.");
@@ -108,6 +211,17 @@ pub fn run_module<T: Module>() {
     }
 }
 
+fn describe<T: Module>() -> ! {
+    let Some(manifest) = T::manifest() else {
+        fail_json("This module has no manifest");
+    };
+    match serde_json::to_string(&manifest) {
+        Ok(json) => println!("{}", json),
+        Err(e) => fail_json(&format!("Could not serialize manifest: {}", e)),
+    }
+    process::exit(0);
+}
+
 #[macro_export]
 macro_rules! implement_module {
     ($module:ident, $args:ty, $err:ty, $run_fn:expr) => {
@@ -122,6 +236,26 @@ macro_rules! implement_module {
             }
         }
 
+        pub fn run() {
+            $crate::response::run_module::<$module>();
+        }
+    };
+    ($module:ident, $args:ty, $err:ty, $run_fn:expr, $manifest_fn:expr) => {
+        pub struct $module;
+
+        impl $crate::response::Module for $module {
+            type Error = $err;
+            type Args = $args;
+
+            fn run(args: Self::Args) -> Result<$crate::response::Response, Self::Error> {
+                $run_fn(args)
+            }
+
+            fn manifest() -> Option<$crate::response::Manifest> {
+                Some($manifest_fn())
+            }
+        }
+
         pub fn run() {
             $crate::response::run_module::<$module>();
         }